@@ -0,0 +1,448 @@
+// Compiler Monitor - LD_PRELOAD exec interposer
+//
+// Loaded into every process of a build with
+//
+//   LD_PRELOAD=/path/to/libcompiler_monitor_preload.so \
+//   COMPILER_MONITOR_SOCKET=/path/to/preload.sock make
+//
+// it hooks `execve`, `execv`, `execvp`, `execvpe`, `posix_spawn` and `posix_spawnp`,
+// reports each call to the `record --backend preload` listener over a Unix socket
+// and then hands over to the real libc function. No privileges are needed.
+//
+// ## Wire Protocol
+//
+// One stream connection per exec. The message is a sequence of NUL-terminated fields:
+//
+//   "CMP1" pid ppid cwd path argc argv[0..argc] envc env[0..envc]
+//
+// Numbers are decimal. `path` is what the exec will run: execvp, execvpe and
+// posix_spawnp names are searched in PATH here the way libc does, and a name not
+// found there is sent as given. A spawn is reported before the child exists, with pid 0 and
+// the spawning process as ppid. The listener answers with a single byte once it has
+// processed the exec (response files included), and only then does the exec or spawn
+// proceed.
+//
+// The hooks may run in a vfork child sharing memory with its parent, so they avoid
+// heap allocation entirely: everything is streamed through a fixed stack buffer.
+// Any failure to reach the listener is silent and the exec continues unrecorded,
+// except for a socket path too long to connect to, which is reported once on stderr.
+
+#![cfg(target_os = "linux")]
+
+use libc::{c_char, c_int, c_void, pid_t, posix_spawn_file_actions_t, posix_spawnattr_t};
+use std::ffi::CStr;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Environment variable holding the listener socket path
+const SOCKET_ENV: &[u8] = b"COMPILER_MONITOR_SOCKET\0";
+
+/// Leading field identifying the protocol version
+const PROTOCOL_MAGIC: &[u8] = b"CMP1";
+
+/// How long an exec waits for the listener's acknowledgement
+const ACK_TIMEOUT_SECS: libc::time_t = 30;
+
+/// Printed once per process when the socket path cannot be used
+const PATH_TOO_LONG: &[u8] =
+    b"compiler_monitor_preload: COMPILER_MONITOR_SOCKET is longer than a Unix socket path may be (107 bytes), execs are not recorded\n";
+
+/// Environment variable listing the directories execvp searches
+const PATH_ENV: &[u8] = b"PATH\0";
+
+/// Search path of libc's execvp when PATH is unset
+const DEFAULT_PATH: &[u8] = b"/bin:/usr/bin";
+
+/// Whether `PATH_TOO_LONG` was printed already
+static WARNED_PATH_TOO_LONG: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    static environ: *const *const c_char;
+}
+
+type ExecveFn = unsafe extern "C" fn(*const c_char, *const *const c_char, *const *const c_char) -> c_int;
+type ExecvpeFn = unsafe extern "C" fn(*const c_char, *const *const c_char, *const *const c_char) -> c_int;
+type PosixSpawnFn = unsafe extern "C" fn(
+    *mut pid_t,
+    *const c_char,
+    *const posix_spawn_file_actions_t,
+    *const posix_spawnattr_t,
+    *const *mut c_char,
+    *const *mut c_char,
+) -> c_int;
+
+/// Look up the next definition of a libc symbol, skipping this library
+unsafe fn next_symbol(name: &[u8]) -> *mut c_void {
+    libc::dlsym(libc::RTLD_NEXT, name.as_ptr() as *const c_char)
+}
+
+/// Interposed `execve(2)`: reports the exec, then performs it
+///
+/// # Safety
+///
+/// Same contract as the libc function it replaces.
+#[no_mangle]
+pub unsafe extern "C" fn execve(
+    path: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    // Callers doing their own PATH search try execve on every candidate; only the
+    // one that can actually be executed is worth reporting
+    if !path.is_null() && libc::access(path, libc::X_OK) == 0 {
+        report(libc::getpid(), libc::getppid(), path, argv, envp);
+    }
+    let real = next_symbol(b"execve\0");
+    if real.is_null() {
+        return fail(libc::ENOSYS);
+    }
+    let real: ExecveFn = mem::transmute(real);
+    real(path, argv, envp)
+}
+
+/// Interposed `execv(3)`, forwarded to `execve` with the current environment
+///
+/// # Safety
+///
+/// Same contract as the libc function it replaces.
+#[no_mangle]
+pub unsafe extern "C" fn execv(path: *const c_char, argv: *const *const c_char) -> c_int {
+    execve(path, argv, environ)
+}
+
+/// Interposed `execvpe(3)`: reports the exec, then performs the PATH search and exec
+///
+/// # Safety
+///
+/// Same contract as the libc function it replaces.
+#[no_mangle]
+pub unsafe extern "C" fn execvpe(
+    file: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    let mut resolved = [0u8; libc::PATH_MAX as usize];
+    report(libc::getpid(), libc::getppid(), search_path(file, &mut resolved), argv, envp);
+    let real = next_symbol(b"execvpe\0");
+    if real.is_null() {
+        return fail(libc::ENOSYS);
+    }
+    let real: ExecvpeFn = mem::transmute(real);
+    real(file, argv, envp)
+}
+
+/// Interposed `execvp(3)`, forwarded to `execvpe` with the current environment
+///
+/// # Safety
+///
+/// Same contract as the libc function it replaces.
+#[no_mangle]
+pub unsafe extern "C" fn execvp(file: *const c_char, argv: *const *const c_char) -> c_int {
+    execvpe(file, argv, environ)
+}
+
+/// Interposed `posix_spawn(3)`: reports the child, then spawns it
+///
+/// # Safety
+///
+/// Same contract as the libc function it replaces.
+#[no_mangle]
+pub unsafe extern "C" fn posix_spawn(
+    pid: *mut pid_t,
+    path: *const c_char,
+    file_actions: *const posix_spawn_file_actions_t,
+    attrp: *const posix_spawnattr_t,
+    argv: *const *mut c_char,
+    envp: *const *mut c_char,
+) -> c_int {
+    spawn(b"posix_spawn\0", false, pid, path, file_actions, attrp, argv, envp)
+}
+
+/// Interposed `posix_spawnp(3)`: reports the child, then spawns it with PATH search
+///
+/// # Safety
+///
+/// Same contract as the libc function it replaces.
+#[no_mangle]
+pub unsafe extern "C" fn posix_spawnp(
+    pid: *mut pid_t,
+    file: *const c_char,
+    file_actions: *const posix_spawn_file_actions_t,
+    attrp: *const posix_spawnattr_t,
+    argv: *const *mut c_char,
+    envp: *const *mut c_char,
+) -> c_int {
+    spawn(b"posix_spawnp\0", true, pid, file, file_actions, attrp, argv, envp)
+}
+
+/// Report the child as not started yet, then spawn it through the real function
+///
+/// Like an exec, the spawn waits for the listener, so the compiler does not run
+/// before its response files were read.
+#[allow(clippy::too_many_arguments)]
+unsafe fn spawn(
+    symbol: &[u8],
+    search: bool,
+    pid: *mut pid_t,
+    path: *const c_char,
+    file_actions: *const posix_spawn_file_actions_t,
+    attrp: *const posix_spawnattr_t,
+    argv: *const *mut c_char,
+    envp: *const *mut c_char,
+) -> c_int {
+    let real = next_symbol(symbol);
+    if real.is_null() {
+        return libc::ENOSYS;
+    }
+    let real: PosixSpawnFn = mem::transmute(real);
+
+    let report_envp = if envp.is_null() { environ } else { envp as *const *const c_char };
+    let mut resolved = [0u8; libc::PATH_MAX as usize];
+    let report_path = if search { search_path(path, &mut resolved) } else { path };
+    report(0, libc::getpid(), report_path, argv as *const *const c_char, report_envp);
+
+    real(pid, path, file_actions, attrp, argv, envp)
+}
+
+/// Find `file` in the caller's PATH like execvp does, building the path in `buffer`
+///
+/// Names containing a slash are not searched; `file` itself is returned for those and
+/// for names found nowhere.
+unsafe fn search_path(file: *const c_char, buffer: &mut [u8; libc::PATH_MAX as usize]) -> *const c_char {
+    if file.is_null() {
+        return file;
+    }
+    let name = CStr::from_ptr(file).to_bytes();
+    if name.is_empty() || name.contains(&b'/') {
+        return file;
+    }
+
+    // Failed lookups must not change the caller's errno
+    let saved_errno = *libc::__errno_location();
+    let found = search_directories(name, buffer);
+    *libc::__errno_location() = saved_errno;
+    match found {
+        true => buffer.as_ptr() as *const c_char,
+        false => file,
+    }
+}
+
+/// Try each PATH directory for `name`; true with the path in `buffer` once found
+unsafe fn search_directories(name: &[u8], buffer: &mut [u8; libc::PATH_MAX as usize]) -> bool {
+    // libc searches the caller's PATH, even for execvpe with another environment
+    let search = libc::getenv(PATH_ENV.as_ptr() as *const c_char);
+    let search = if search.is_null() { DEFAULT_PATH } else { CStr::from_ptr(search).to_bytes() };
+
+    for directory in search.split(|&b| b == b':') {
+        // An empty element is the working directory
+        let directory: &[u8] = if directory.is_empty() { b"." } else { directory };
+        let len = directory.len() + 1 + name.len();
+        if len >= buffer.len() {
+            continue;
+        }
+        buffer[..directory.len()].copy_from_slice(directory);
+        buffer[directory.len()] = b'/';
+        buffer[directory.len() + 1..len].copy_from_slice(name);
+        buffer[len] = 0;
+
+        let candidate = buffer.as_ptr() as *const c_char;
+        let mut status: libc::stat = mem::zeroed();
+        if libc::stat(candidate, &mut status) == 0
+            && status.st_mode & libc::S_IFMT == libc::S_IFREG
+            && libc::access(candidate, libc::X_OK) == 0
+        {
+            return true;
+        }
+    }
+
+    false
+}
+
+unsafe fn fail(errno: c_int) -> c_int {
+    *libc::__errno_location() = errno;
+    -1
+}
+
+/// Send one exec to the listener and wait for its acknowledgement
+unsafe fn report(
+    pid: pid_t,
+    ppid: pid_t,
+    path: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) {
+    // Leave errno exactly as the caller had it
+    let saved_errno = *libc::__errno_location();
+
+    let socket_path = libc::getenv(SOCKET_ENV.as_ptr() as *const c_char);
+    if !socket_path.is_null() {
+        let fd = connect(CStr::from_ptr(socket_path).to_bytes());
+        if fd >= 0 {
+            let mut writer = Writer::new(fd);
+            writer.field(PROTOCOL_MAGIC);
+            writer.number(pid as u64);
+            writer.number(ppid as u64);
+
+            let mut cwd = [0u8; libc::PATH_MAX as usize];
+            if libc::getcwd(cwd.as_mut_ptr() as *mut c_char, cwd.len()).is_null() {
+                writer.field(b"");
+            } else {
+                writer.field(CStr::from_ptr(cwd.as_ptr() as *const c_char).to_bytes());
+            }
+
+            writer.field(if path.is_null() { b"" } else { CStr::from_ptr(path).to_bytes() });
+            writer.list(argv);
+            writer.list(envp);
+
+            if writer.finish() {
+                libc::shutdown(fd, libc::SHUT_WR);
+                let mut ack = 0u8;
+                libc::read(fd, &mut ack as *mut u8 as *mut c_void, 1);
+            }
+            libc::close(fd);
+        }
+    }
+
+    *libc::__errno_location() = saved_errno;
+}
+
+unsafe fn connect(socket_path: &[u8]) -> c_int {
+    let mut addr: libc::sockaddr_un = mem::zeroed();
+    if socket_path.is_empty() {
+        return -1;
+    }
+    if socket_path.len() >= addr.sun_path.len() {
+        if !WARNED_PATH_TOO_LONG.swap(true, Ordering::Relaxed) {
+            libc::write(libc::STDERR_FILENO, PATH_TOO_LONG.as_ptr() as *const c_void, PATH_TOO_LONG.len());
+        }
+        return -1;
+    }
+
+    let fd = libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0);
+    if fd < 0 {
+        return -1;
+    }
+
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, &src) in addr.sun_path.iter_mut().zip(socket_path) {
+        *dst = src as c_char;
+    }
+
+    let timeout = libc::timeval {
+        tv_sec: ACK_TIMEOUT_SECS,
+        tv_usec: 0,
+    };
+    libc::setsockopt(
+        fd,
+        libc::SOL_SOCKET,
+        libc::SO_RCVTIMEO,
+        &timeout as *const _ as *const c_void,
+        mem::size_of::<libc::timeval>() as libc::socklen_t,
+    );
+
+    if libc::connect(
+        fd,
+        &addr as *const _ as *const libc::sockaddr,
+        mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+    ) < 0
+    {
+        libc::close(fd);
+        return -1;
+    }
+
+    fd
+}
+
+/// Streams NUL-terminated fields to a socket through a fixed stack buffer
+struct Writer {
+    fd: c_int,
+    buffer: [u8; 8192],
+    len: usize,
+    ok: bool,
+}
+
+impl Writer {
+    fn new(fd: c_int) -> Self {
+        Self {
+            fd,
+            buffer: [0; 8192],
+            len: 0,
+            ok: true,
+        }
+    }
+
+    fn field(&mut self, bytes: &[u8]) {
+        self.bytes(bytes);
+        self.bytes(&[0]);
+    }
+
+    fn number(&mut self, mut value: u64) {
+        let mut digits = [0u8; 20];
+        let mut start = digits.len();
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (value % 10) as u8;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        self.field(&digits[start..]);
+    }
+
+    /// A NULL-terminated `char*` array, preceded by its length
+    unsafe fn list(&mut self, mut items: *const *const c_char) {
+        let mut count = 0u64;
+        if !items.is_null() {
+            while !(*items.add(count as usize)).is_null() {
+                count += 1;
+            }
+        }
+
+        self.number(count);
+        for _ in 0..count {
+            self.field(CStr::from_ptr(*items).to_bytes());
+            items = items.add(1);
+        }
+    }
+
+    fn bytes(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() && self.ok {
+            if self.len == self.buffer.len() {
+                self.flush();
+            }
+            let n = bytes.len().min(self.buffer.len() - self.len);
+            self.buffer[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+            self.len += n;
+            bytes = &bytes[n..];
+        }
+    }
+
+    fn flush(&mut self) {
+        let mut written = 0;
+        while written < self.len && self.ok {
+            let rc = unsafe {
+                libc::send(
+                    self.fd,
+                    self.buffer[written..self.len].as_ptr() as *const c_void,
+                    self.len - written,
+                    libc::MSG_NOSIGNAL,
+                )
+            };
+            if rc < 0 {
+                if unsafe { *libc::__errno_location() } != libc::EINTR {
+                    self.ok = false;
+                }
+            } else {
+                written += rc as usize;
+            }
+        }
+        self.len = 0;
+    }
+
+    /// Flush what is left; false if the listener went away
+    fn finish(&mut self) -> bool {
+        self.flush();
+        self.ok
+    }
+}
@@ -0,0 +1,327 @@
+// Kernel type information for the eBPF backend
+//
+// The eBPF programs read kernel structures (task_struct, dentry, mount, ...) whose
+// layout changes between kernel versions and configurations. Rather than being built
+// against one kernel's headers, the backend looks up the member offsets it needs in
+// the running kernel's BTF (`/sys/kernel/btf/vmlinux`) and patches them into the
+// programs at load time, as libbpf's CO-RE relocations would.
+//
+// Only what that lookup needs is parsed: struct and union members, and the
+// typedef/const/volatile chains leading to anonymous members. See
+// Documentation/bpf/btf.rst in the kernel for the format.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+/// BTF of the running kernel
+const VMLINUX_BTF: &str = "/sys/kernel/btf/vmlinux";
+
+const BTF_MAGIC: u16 = 0xeb9f;
+
+/// Size of `struct btf_header` up to and including `str_len`
+const HEADER_LEN: usize = 24;
+
+// Type kinds, from linux/btf.h
+const KIND_INT: u32 = 1;
+const KIND_PTR: u32 = 2;
+const KIND_ARRAY: u32 = 3;
+const KIND_STRUCT: u32 = 4;
+const KIND_UNION: u32 = 5;
+const KIND_ENUM: u32 = 6;
+const KIND_FWD: u32 = 7;
+const KIND_TYPEDEF: u32 = 8;
+const KIND_VOLATILE: u32 = 9;
+const KIND_CONST: u32 = 10;
+const KIND_RESTRICT: u32 = 11;
+const KIND_FUNC: u32 = 12;
+const KIND_FUNC_PROTO: u32 = 13;
+const KIND_VAR: u32 = 14;
+const KIND_DATASEC: u32 = 15;
+const KIND_FLOAT: u32 = 16;
+const KIND_DECL_TAG: u32 = 17;
+const KIND_TYPE_TAG: u32 = 18;
+const KIND_ENUM64: u32 = 19;
+
+/// A member of a struct or union
+struct Member {
+    name: String,
+    type_id: u32,
+    /// Offset from the start of the enclosing type, in bits
+    bit_offset: u32,
+}
+
+/// The parts of a BTF type the offset lookup looks at
+enum Type {
+    /// A struct or union and its members
+    Composite(Vec<Member>),
+    /// typedef, const, volatile, restrict or type tag of another type
+    Alias(u32),
+    Other,
+}
+
+/// Parsed BTF type section
+pub struct Btf {
+    /// Indexed by type id; id 0 is void
+    types: Vec<Type>,
+    /// Struct type ids by name
+    structs: HashMap<String, Vec<u32>>,
+}
+
+impl Btf {
+    /// Read the running kernel's BTF
+    pub fn load() -> Result<Self> {
+        let data = std::fs::read(VMLINUX_BTF)
+            .with_context(|| format!("Failed to read {} (kernel built without BTF?)", VMLINUX_BTF))?;
+        Self::parse(&data).with_context(|| format!("Failed to parse {}", VMLINUX_BTF))
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let u16_at = |offset: usize| data.get(offset..offset + 2).map(|b| u16::from_ne_bytes([b[0], b[1]]));
+        let u32_at = |offset: usize| {
+            data.get(offset..offset + 4)
+                .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+        };
+
+        if u16_at(0) != Some(BTF_MAGIC) {
+            anyhow::bail!("Not BTF data of this machine's byte order");
+        }
+        let (Some(header_len), Some(type_off), Some(type_len), Some(str_off), Some(str_len)) =
+            (u32_at(4), u32_at(8), u32_at(12), u32_at(16), u32_at(20))
+        else {
+            anyhow::bail!("Truncated BTF header");
+        };
+        let header_len = (header_len as usize).max(HEADER_LEN);
+
+        let type_start = header_len + type_off as usize;
+        let type_end = type_start + type_len as usize;
+        let str_start = header_len + str_off as usize;
+        let strings = data
+            .get(str_start..str_start + str_len as usize)
+            .context("Truncated BTF string section")?;
+        let name = |offset: u32| -> String {
+            let tail = strings.get(offset as usize..).unwrap_or_default();
+            let end = tail.iter().position(|&b| b == 0).unwrap_or(tail.len());
+            String::from_utf8_lossy(&tail[..end]).to_string()
+        };
+
+        let mut types = vec![Type::Other];
+        let mut structs: HashMap<String, Vec<u32>> = HashMap::new();
+        let mut offset = type_start;
+
+        while offset < type_end {
+            let (Some(name_off), Some(info), Some(size_or_type)) =
+                (u32_at(offset), u32_at(offset + 4), u32_at(offset + 8))
+            else {
+                anyhow::bail!("Truncated BTF type at offset {}", offset);
+            };
+            offset += 12;
+
+            let kind = (info >> 24) & 0x1f;
+            let vlen = (info & 0xffff) as usize;
+            let kind_flag = info >> 31 == 1;
+
+            let ty = match kind {
+                KIND_STRUCT | KIND_UNION => {
+                    let mut members = Vec::with_capacity(vlen);
+                    for _ in 0..vlen {
+                        let (Some(member_name), Some(type_id), Some(member_offset)) =
+                            (u32_at(offset), u32_at(offset + 4), u32_at(offset + 8))
+                        else {
+                            anyhow::bail!("Truncated BTF member at offset {}", offset);
+                        };
+                        offset += 12;
+                        members.push(Member {
+                            name: name(member_name),
+                            type_id,
+                            // With kind_flag the top byte is a bitfield size
+                            bit_offset: if kind_flag { member_offset & 0x00ff_ffff } else { member_offset },
+                        });
+                    }
+                    let struct_name = name(name_off);
+                    if kind == KIND_STRUCT && !struct_name.is_empty() {
+                        structs.entry(struct_name).or_default().push(types.len() as u32);
+                    }
+                    Type::Composite(members)
+                }
+                KIND_TYPEDEF | KIND_VOLATILE | KIND_CONST | KIND_RESTRICT | KIND_TYPE_TAG => {
+                    Type::Alias(size_or_type)
+                }
+                KIND_INT | KIND_VAR | KIND_DECL_TAG => {
+                    offset += 4;
+                    Type::Other
+                }
+                KIND_ARRAY => {
+                    offset += 12;
+                    Type::Other
+                }
+                KIND_ENUM | KIND_FUNC_PROTO => {
+                    offset += 8 * vlen;
+                    Type::Other
+                }
+                KIND_DATASEC | KIND_ENUM64 => {
+                    offset += 12 * vlen;
+                    Type::Other
+                }
+                KIND_PTR | KIND_FWD | KIND_FUNC | KIND_FLOAT => Type::Other,
+                other => anyhow::bail!("Unknown BTF type kind {}", other),
+            };
+            types.push(ty);
+        }
+
+        Ok(Self { types, structs })
+    }
+
+    /// Byte offset of `member` in `struct structure`, also found inside anonymous
+    /// struct and union members
+    pub fn member_offset(&self, structure: &str, member: &str) -> Result<u32> {
+        self.structs
+            .get(structure)
+            .into_iter()
+            .flatten()
+            .find_map(|&id| self.find_member(id, member))
+            .map(|bits| bits / 8)
+            .with_context(|| format!("Kernel has no {}.{}", structure, member))
+    }
+
+    fn find_member(&self, type_id: u32, member: &str) -> Option<u32> {
+        let Type::Composite(members) = self.resolve(type_id)? else {
+            return None;
+        };
+
+        members.iter().find_map(|candidate| {
+            if candidate.name == member {
+                Some(candidate.bit_offset)
+            } else if candidate.name.is_empty() {
+                self.find_member(candidate.type_id, member)
+                    .map(|inner| candidate.bit_offset + inner)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The type behind any typedef and qualifier chain
+    fn resolve(&self, mut type_id: u32) -> Option<&Type> {
+        // Chains are short; the bound only guards against malformed cycles
+        for _ in 0..32 {
+            match self.types.get(type_id as usize)? {
+                Type::Alias(target) => type_id = *target,
+                ty => return Some(ty),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assembles BTF the way the kernel lays it out
+    struct Builder {
+        types: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Builder {
+        fn new() -> Self {
+            Self {
+                types: Vec::new(),
+                strings: vec![0],
+            }
+        }
+
+        fn string(&mut self, s: &str) -> u32 {
+            if s.is_empty() {
+                return 0;
+            }
+            let offset = self.strings.len() as u32;
+            self.strings.extend_from_slice(s.as_bytes());
+            self.strings.push(0);
+            offset
+        }
+
+        fn push(&mut self, words: &[u32]) {
+            for word in words {
+                self.types.extend_from_slice(&word.to_ne_bytes());
+            }
+        }
+
+        fn int(&mut self, name: &str) {
+            let name = self.string(name);
+            self.push(&[name, KIND_INT << 24, 4, 32]);
+        }
+
+        fn composite(&mut self, kind: u32, name: &str, size: u32, members: &[(&str, u32, u32)]) {
+            let name = self.string(name);
+            self.push(&[name, (kind << 24) | members.len() as u32, size]);
+            for &(member, type_id, bit_offset) in members {
+                let member = self.string(member);
+                self.push(&[member, type_id, bit_offset]);
+            }
+        }
+
+        fn typedef(&mut self, name: &str, target: u32) {
+            let name = self.string(name);
+            self.push(&[name, KIND_TYPEDEF << 24, target]);
+        }
+
+        fn build(self) -> Vec<u8> {
+            let mut data = Vec::new();
+            data.extend_from_slice(&BTF_MAGIC.to_ne_bytes());
+            data.extend_from_slice(&[1, 0]); // version, flags
+            let types_len = self.types.len() as u32;
+            for word in [HEADER_LEN as u32, 0, types_len, types_len, self.strings.len() as u32] {
+                data.extend_from_slice(&word.to_ne_bytes());
+            }
+            data.extend_from_slice(&self.types);
+            data.extend_from_slice(&self.strings);
+            data
+        }
+    }
+
+    /// `struct mm_struct { int mm_count; struct { int a; long arg_start; }; }`, the
+    /// anonymous struct behind a typedef; type ids: 1 int, 2 anonymous, 3 typedef, 4 mm_struct
+    fn sample() -> Btf {
+        let mut builder = Builder::new();
+        builder.int("int");
+        builder.composite(KIND_STRUCT, "", 16, &[("a", 1, 0), ("arg_start", 1, 64)]);
+        builder.typedef("anon_t", 2);
+        builder.composite(KIND_STRUCT, "mm_struct", 24, &[("mm_count", 1, 0), ("", 3, 64)]);
+        Btf::parse(&builder.build()).unwrap()
+    }
+
+    #[test]
+    fn finds_direct_members() {
+        assert_eq!(sample().member_offset("mm_struct", "mm_count").unwrap(), 0);
+    }
+
+    #[test]
+    fn finds_members_of_anonymous_structs_behind_typedefs() {
+        assert_eq!(sample().member_offset("mm_struct", "arg_start").unwrap(), 16);
+    }
+
+    #[test]
+    fn reports_missing_members_and_structs() {
+        let btf = sample();
+        assert!(btf.member_offset("mm_struct", "arg_end").is_err());
+        assert!(btf.member_offset("task_struct", "pid").is_err());
+    }
+
+    #[test]
+    fn rejects_other_data() {
+        assert!(Btf::parse(b"not btf at all, not even close").is_err());
+        assert!(Btf::parse(&[]).is_err());
+    }
+
+    #[test]
+    fn reads_bitfield_offsets_without_their_size() {
+        let mut builder = Builder::new();
+        builder.int("int");
+        let name = builder.string("flags");
+        let member = builder.string("exit_state");
+        builder.push(&[name, (1 << 31) | (KIND_STRUCT << 24) | 1, 8, member, 1, (3 << 24) | 32]);
+        let btf = Btf::parse(&builder.build()).unwrap();
+        assert_eq!(btf.member_offset("flags", "exit_state").unwrap(), 4);
+    }
+}
@@ -0,0 +1,1079 @@
+// Linux capture backend (eBPF exec tracing)
+//
+// Attaches two small eBPF programs to raw tracepoints. On `sched_process_exec`
+// the kernel runs the first in the context of the freshly exec'd program; it
+// reads everything a compile entry needs straight from kernel memory while the
+// process is guaranteed to exist: pid, parent pid, start time, the exec'd path,
+// argv from the new mm (up to `ARGV_SIZE` bytes) and the working directory,
+// walked dentry by dentry up to the root. The second, on `sched_process_exit`,
+// reports the wait status of exiting processes. Both write fixed-layout records
+// into a BPF ring buffer, which this backend drains and decodes.
+//
+// A compile that has exited by the time its record is read is therefore still
+// recorded, unlike with the netlink backend. Only what the kernel record cannot
+// carry is read from /proc, and only while the same process is still running:
+// the full argv when it was longer than the record, the cwd when it was deeper
+// than `CWD_DEPTH` components, and the environment.
+//
+// No external toolchain is involved: the programs are hand-assembled bytecode
+// loaded with the raw `bpf(2)` syscall. The kernel structure offsets they read
+// through are taken from the running kernel's BTF (see btf.rs).
+//
+// Requirements:
+// - Linux 5.8+ (BPF ring buffer) with BTF (`CONFIG_DEBUG_INFO_BTF`)
+// - CAP_BPF + CAP_PERFMON, or CAP_SYS_ADMIN (root)
+
+use super::btf::Btf;
+use super::procfs::{
+    read_process_argv, read_process_environment, read_process_executable,
+    read_process_start_time, read_process_working_directory, ChildLookup,
+};
+use super::worker::{exit_code, spawn_worker, CaptureEvent, PendingExits};
+use super::CaptureBackend;
+use crate::monitor::{join_arguments, CompilerMonitor, ProcessInfo};
+use crate::tree::parent_chain;
+use anyhow::{Context, Result};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
+// From linux/bpf.h
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_RAW_TRACEPOINT_OPEN: libc::c_long = 17;
+const BPF_MAP_TYPE_PERCPU_ARRAY: u32 = 6;
+const BPF_MAP_TYPE_RINGBUF: u32 = 27;
+const BPF_PROG_TYPE_RAW_TRACEPOINT: u32 = 17;
+const BPF_FUNC_MAP_LOOKUP_ELEM: i32 = 1;
+const BPF_FUNC_GET_CURRENT_PID_TGID: i32 = 14;
+const BPF_FUNC_PROBE_READ_USER: i32 = 112;
+const BPF_FUNC_PROBE_READ_KERNEL: i32 = 113;
+const BPF_FUNC_PROBE_READ_KERNEL_STR: i32 = 115;
+const BPF_FUNC_RINGBUF_OUTPUT: i32 = 130;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_RINGBUF_BUSY_BIT: u32 = 1 << 31;
+const BPF_RINGBUF_DISCARD_BIT: u32 = 1 << 30;
+const BPF_RINGBUF_HDR_SZ: usize = 8;
+
+/// Ring buffer size, a power of two; an exec record takes its header plus argv
+const RING_SIZE: usize = 16 * 1024 * 1024;
+
+/// How long a wait for samples lasts before the loop checks whether to stop
+const POLL_TIMEOUT_MS: libc::c_int = 200;
+
+/// Size of the `bpf_attr` buffer passed to the kernel (unused tail must be zero)
+const BPF_ATTR_SIZE: usize = 128;
+
+/// Verifier log buffer, only used to explain a rejected program
+const VERIFIER_LOG_SIZE: usize = 1024 * 1024;
+
+// Record layout shared by the programs and `parse_event`, native-endian. Exit
+// records are the 24 byte header; exec records follow it with the exec'd path,
+// the cwd components (innermost first, an empty one where a mount was crossed)
+// and argv, and end after the argv bytes actually read.
+const EVENT_EXEC: u32 = 1;
+const EVENT_EXIT: u32 = 2;
+const KIND_OFFSET: i16 = 0;
+const PID_OFFSET: i16 = 4;
+const PARENT_PID_OFFSET: i16 = 8;
+const STATUS_OFFSET: i16 = 12;
+const START_TIME_OFFSET: i16 = 16;
+const EXIT_EVENT_SIZE: i32 = 24;
+const FLAGS_OFFSET: i16 = 24;
+const CWD_DEPTH_OFFSET: i16 = 28;
+const FILENAME_OFFSET: i16 = 32;
+const FILENAME_SIZE: i32 = 256;
+const CWD_OFFSET: i16 = FILENAME_OFFSET + FILENAME_SIZE as i16;
+const CWD_DEPTH: usize = 32;
+const CWD_COMPONENT_SIZE: i32 = 48;
+const ARGV_OFFSET: i16 = CWD_OFFSET + (CWD_DEPTH as i32 * CWD_COMPONENT_SIZE) as i16;
+/// Largest value a per-CPU array element may have
+const EXEC_EVENT_SIZE: i32 = 32 * 1024;
+const ARGV_SIZE: i32 = EXEC_EVENT_SIZE - ARGV_OFFSET as i32;
+
+/// `FLAGS_OFFSET` bits
+const ARGV_TRUNCATED: u32 = 1;
+const CWD_TRUNCATED: u32 = 2;
+
+/// Exec and exit tracing through eBPF programs on the scheduler tracepoints
+pub struct EbpfBackend {
+    ring: RingBuffer,
+    // Keep the programs attached for as long as the backend lives
+    _scratch: OwnedFd,
+    _programs: Vec<OwnedFd>,
+    _links: Vec<OwnedFd>,
+}
+
+impl EbpfBackend {
+    /// Load and attach the programs
+    ///
+    /// Fails with a clear message when capabilities are missing, so callers can
+    /// fall back to another backend.
+    pub fn new() -> Result<Self> {
+        let offsets = Offsets::from_btf(&Btf::load()?)?;
+
+        let ring = create_map(BPF_MAP_TYPE_RINGBUF, 0, 0, RING_SIZE as u32)
+            .map_err(explain_permission)
+            .context("Failed to create BPF ring buffer")?;
+        // Exec records are too large for the program stack, so they are built here
+        let scratch = create_map(BPF_MAP_TYPE_PERCPU_ARRAY, 4, EXEC_EVENT_SIZE as u32, 1)
+            .map_err(explain_permission)
+            .context("Failed to create BPF scratch map")?;
+
+        let mut programs = Vec::new();
+        let mut links = Vec::new();
+        for (tracepoint, program) in [
+            ("sched_process_exec", exec_program(&offsets, &ring, &scratch)),
+            ("sched_process_exit", exit_program(&offsets, &ring)),
+        ] {
+            let program = load_program(&program)
+                .map_err(explain_permission)
+                .with_context(|| format!("Failed to load eBPF program for {}", tracepoint))?;
+            let link = attach_raw_tracepoint(&program, tracepoint)
+                .map_err(explain_permission)
+                .with_context(|| format!("Failed to attach to {}", tracepoint))?;
+            programs.push(program);
+            links.push(link);
+        }
+
+        let ring = RingBuffer::map(ring).context("Failed to map BPF ring buffer")?;
+
+        Ok(Self {
+            ring,
+            _scratch: scratch,
+            _programs: programs,
+            _links: links,
+        })
+    }
+}
+
+impl CaptureBackend for EbpfBackend {
+    fn name(&self) -> &'static str {
+        "ebpf"
+    }
+
+    fn reports_exits(&self) -> bool {
+        true
+    }
+
+    fn run(&mut self, monitor: Arc<CompilerMonitor>) -> Result<()> {
+        monitor_with_ebpf(&mut self.ring, monitor)
+    }
+}
+
+fn monitor_with_ebpf(ring: &mut RingBuffer, monitor: Arc<CompilerMonitor>) -> Result<()> {
+    println!("Starting eBPF exec tracer...");
+    println!("Press Ctrl+C to stop monitoring\n");
+
+    let (sender, worker) = spawn_worker(Arc::clone(&monitor));
+
+    println!("✓ Attached to sched_process_exec and sched_process_exit");
+    println!("✓ Monitoring process creation...\n");
+
+    let nanoseconds_per_tick = 1_000_000_000 / unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+
+    let mut pending = PendingExits::new();
+    let mut events = Vec::new();
+
+    while !monitor.should_stop() {
+        for process in pending.expire() {
+            if sender.send(CaptureEvent::ExitLost(process)).is_err() {
+                anyhow::bail!("Capture worker thread stopped unexpectedly");
+            }
+        }
+
+        ring.wait()?;
+
+        ring.drain(|sample| match parse_event(sample) {
+            Some(event) => events.push(event),
+            None => monitor.warn(None, "Ignoring malformed eBPF record"),
+        });
+
+        for event in events.drain(..) {
+            let event = match event {
+                KernelEvent::Exec(exec) => {
+                    let Some(exec) = exec_process(exec, nanoseconds_per_tick, &monitor) else {
+                        continue;
+                    };
+                    pending.insert(exec.id());
+                    CaptureEvent::Exec(exec)
+                }
+                KernelEvent::Exit { pid, start_time, status } => match pending.exited(pid) {
+                    Some(process) if process.start_time == start_time / nanoseconds_per_tick => CaptureEvent::Exit {
+                        process,
+                        exit_code: exit_code(status),
+                    },
+                    // The pid was reused, so the pending process is gone too
+                    Some(process) => CaptureEvent::ExitLost(process),
+                    None => continue,
+                },
+            };
+
+            if sender.send(event).is_err() {
+                anyhow::bail!("Capture worker thread stopped unexpectedly");
+            }
+        }
+    }
+
+    // Let the worker record everything still queued
+    drop(sender);
+    let _ = worker.join();
+
+    Ok(())
+}
+
+/// A decoded ring buffer record
+#[derive(Debug, PartialEq)]
+enum KernelEvent {
+    Exec(ExecEvent),
+    /// Exit of a whole process, with its wait status
+    Exit { pid: u32, start_time: u64, status: i32 },
+}
+
+/// An exec as read by the kernel program
+#[derive(Debug, PartialEq)]
+struct ExecEvent {
+    pid: u32,
+    parent_pid: u32,
+    /// Nanoseconds since boot
+    start_time: u64,
+    /// Path as passed to exec, possibly relative to `working_dir`; `None` if longer
+    /// than `FILENAME_SIZE`
+    filename: Option<String>,
+    /// Empty if it was cut off at `ARGV_SIZE` bytes or unreadable
+    argv: Vec<String>,
+    argv_truncated: bool,
+    /// `None` if deeper than `CWD_DEPTH` components or a component was cut off
+    working_dir: Option<String>,
+}
+
+/// Decode a record written by one of the programs
+fn parse_event(sample: &[u8]) -> Option<KernelEvent> {
+    let u32_at = |offset: i16| {
+        let offset = offset as usize;
+        sample
+            .get(offset..offset + 4)
+            .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    let start_time = sample
+        .get(START_TIME_OFFSET as usize..START_TIME_OFFSET as usize + 8)
+        .map(|b| u64::from_ne_bytes(b.try_into().unwrap()))?;
+    let pid = u32_at(PID_OFFSET)?;
+
+    match u32_at(KIND_OFFSET)? {
+        EVENT_EXIT => Some(KernelEvent::Exit {
+            pid,
+            start_time,
+            status: u32_at(STATUS_OFFSET)? as i32,
+        }),
+        EVENT_EXEC => {
+            let flags = u32_at(FLAGS_OFFSET)?;
+            let cwd_depth = u32_at(CWD_DEPTH_OFFSET)? as usize;
+            let filename = sample.get(FILENAME_OFFSET as usize..CWD_OFFSET as usize)?;
+            let argv = sample.get(ARGV_OFFSET as usize..)?;
+
+            let components = sample
+                .get(CWD_OFFSET as usize..ARGV_OFFSET as usize)?
+                .chunks(CWD_COMPONENT_SIZE as usize)
+                .take(cwd_depth.min(CWD_DEPTH));
+            let working_dir = if flags & CWD_TRUNCATED != 0 {
+                None
+            } else {
+                components
+                    .rev()
+                    .try_fold(String::new(), |mut path, component| {
+                        let component = untruncated_string(component)?;
+                        if !component.is_empty() {
+                            path.push('/');
+                            path.push_str(&component);
+                        }
+                        Some(path)
+                    })
+                    .map(|path| if path.is_empty() { "/".to_string() } else { path })
+            };
+
+            let mut argv: Vec<String> = argv
+                .split(|&b| b == 0)
+                .map(|arg| String::from_utf8_lossy(arg).to_string())
+                .collect();
+            // The argv area ends with a NUL, which leaves one empty trailing element
+            if argv.last().is_some_and(|arg| arg.is_empty()) {
+                argv.pop();
+            }
+
+            Some(KernelEvent::Exec(ExecEvent {
+                pid,
+                parent_pid: u32_at(PARENT_PID_OFFSET)?,
+                start_time,
+                filename: untruncated_string(filename),
+                argv,
+                argv_truncated: flags & ARGV_TRUNCATED != 0,
+                working_dir,
+            }))
+        }
+        _ => None,
+    }
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+/// A string read with `bpf_probe_read_kernel_str`, `None` if it may have been cut
+/// off: the helper always terminates, so a string filling the field ends in its
+/// last byte
+fn untruncated_string(field: &[u8]) -> Option<String> {
+    match field.iter().position(|&b| b == 0) {
+        Some(end) if end + 1 < field.len() => Some(String::from_utf8_lossy(&field[..end]).to_string()),
+        _ => None,
+    }
+}
+
+/// Turn an exec record into the monitor's process info, if it matches the
+/// pattern and is in scope
+///
+/// /proc only fills in what the record lacks, and only while it still shows the
+/// same process (same start time).
+fn exec_process(exec: ExecEvent, nanoseconds_per_tick: u64, monitor: &CompilerMonitor) -> Option<ProcessInfo> {
+    let pid = exec.pid;
+    let start_time = exec.start_time / nanoseconds_per_tick;
+    let running = read_process_start_time(pid) == Some(start_time);
+
+    // exec paths may be relative to the caller's working directory
+    let filename = exec
+        .filename
+        .or_else(|| running.then(|| read_process_executable(pid)).flatten())?;
+    let name = Path::new(&filename)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())?;
+
+    if !monitor.matches_process_name(&name) {
+        return None;
+    }
+
+    let argv = match exec.argv_truncated && running {
+        true => read_process_argv(pid).unwrap_or(exec.argv),
+        false => exec.argv,
+    };
+    if argv.is_empty() {
+        return None;
+    }
+
+    // Left empty for the monitor to recover if unknown
+    let working_dir = exec
+        .working_dir
+        .or_else(|| running.then(|| read_process_working_directory(pid)).flatten())
+        .unwrap_or_default();
+
+    let lookup = ChildLookup {
+        child: pid,
+        parent: exec.parent_pid,
+    };
+    if !monitor.scope().allows(pid, &working_dir, &lookup) {
+        return None;
+    }
+
+    let executable = Path::new(&working_dir).join(&filename);
+
+    Some(ProcessInfo {
+        pid,
+        start_time,
+        parent_pid: Some(exec.parent_pid),
+        name,
+        executable: executable.to_string_lossy().to_string(),
+        command_line: join_arguments(&argv),
+        working_dir,
+        environment: running
+            .then(|| read_process_environment(pid))
+            .flatten()
+            .unwrap_or_default(),
+        parents: parent_chain(pid, &lookup),
+    })
+}
+
+/// Offsets of the kernel structure members the programs read
+struct Offsets {
+    task_real_parent: i32,
+    task_tgid: i32,
+    task_start_time: i32,
+    task_exit_code: i32,
+    task_mm: i32,
+    task_fs: i32,
+    bprm_filename: i32,
+    mm_arg_start: i32,
+    mm_arg_end: i32,
+    fs_pwd: i32,
+    path_mnt: i32,
+    path_dentry: i32,
+    dentry_parent: i32,
+    dentry_name: i32,
+    qstr_name: i32,
+    mount_parent: i32,
+    mount_mountpoint: i32,
+    mount_mnt: i32,
+    vfsmount_root: i32,
+}
+
+impl Offsets {
+    fn from_btf(btf: &Btf) -> Result<Self> {
+        let offset = |structure: &str, member: &str| btf.member_offset(structure, member).map(|o| o as i32);
+
+        Ok(Self {
+            task_real_parent: offset("task_struct", "real_parent")?,
+            task_tgid: offset("task_struct", "tgid")?,
+            // Renamed in Linux 5.5
+            task_start_time: offset("task_struct", "start_boottime")
+                .or_else(|_| offset("task_struct", "real_start_time"))?,
+            task_exit_code: offset("task_struct", "exit_code")?,
+            task_mm: offset("task_struct", "mm")?,
+            task_fs: offset("task_struct", "fs")?,
+            bprm_filename: offset("linux_binprm", "filename")?,
+            mm_arg_start: offset("mm_struct", "arg_start")?,
+            mm_arg_end: offset("mm_struct", "arg_end")?,
+            fs_pwd: offset("fs_struct", "pwd")?,
+            path_mnt: offset("path", "mnt")?,
+            path_dentry: offset("path", "dentry")?,
+            dentry_parent: offset("dentry", "d_parent")?,
+            dentry_name: offset("dentry", "d_name")?,
+            qstr_name: offset("qstr", "name")?,
+            mount_parent: offset("mount", "mnt_parent")?,
+            mount_mountpoint: offset("mount", "mnt_mountpoint")?,
+            mount_mnt: offset("mount", "mnt")?,
+            vfsmount_root: offset("vfsmount", "mnt_root")?,
+        })
+    }
+}
+
+// Registers; r1-r5 are helper arguments and clobbered by calls, r6-r9 survive them
+const R0: u8 = 0;
+const R1: u8 = 1;
+const R2: u8 = 2;
+const R3: u8 = 3;
+const R4: u8 = 4;
+const R6: u8 = 6;
+const R7: u8 = 7;
+const R8: u8 = 8;
+const R9: u8 = 9;
+const R10: u8 = 10;
+
+/// Program for `sched_process_exec(task, old_pid, bprm)`
+///
+/// r6 holds the record in the scratch map, r9 the argv length; the cwd walk keeps
+/// the dentry in r7 and the `struct mount` it belongs to in r8.
+fn exec_program(offsets: &Offsets, ring: &OwnedFd, scratch: &OwnedFd) -> Vec<u8> {
+    let mut asm = Assembler::default();
+    let out = asm.label();
+
+    asm.ldx_dw(R7, R1, 0); // task
+    asm.ldx_dw(R8, R1, 16); // bprm
+
+    // r6 = scratch[0]
+    asm.st_w(R10, -4, 0);
+    asm.ld_map(R1, scratch);
+    asm.mov(R2, R10);
+    asm.add_imm(R2, -4);
+    asm.call(BPF_FUNC_MAP_LOOKUP_ELEM);
+    asm.jeq_imm(R0, 0, out);
+    asm.mov(R6, R0);
+
+    asm.st_w(R6, KIND_OFFSET, EVENT_EXEC as i32);
+    asm.call(BPF_FUNC_GET_CURRENT_PID_TGID);
+    asm.rsh_imm(R0, 32);
+    asm.stx_w(R6, PID_OFFSET, R0);
+    asm.st_w(R6, STATUS_OFFSET, 0);
+    asm.st_w(R6, FLAGS_OFFSET, 0);
+    asm.st_w(R6, CWD_DEPTH_OFFSET, 0);
+
+    // parent pid = task->real_parent->tgid
+    asm.read_kernel_to_stack(-8, R7, offsets.task_real_parent);
+    asm.ldx_dw(R3, R10, -8);
+    asm.add_imm(R3, offsets.task_tgid);
+    asm.read_kernel(BPF_FUNC_PROBE_READ_KERNEL, R6, PARENT_PID_OFFSET, 4);
+
+    asm.mov(R3, R7);
+    asm.add_imm(R3, offsets.task_start_time);
+    asm.read_kernel(BPF_FUNC_PROBE_READ_KERNEL, R6, START_TIME_OFFSET, 8);
+
+    asm.read_kernel_to_stack(-8, R8, offsets.bprm_filename);
+    asm.ldx_dw(R3, R10, -8);
+    asm.read_kernel(BPF_FUNC_PROBE_READ_KERNEL_STR, R6, FILENAME_OFFSET, FILENAME_SIZE);
+
+    // argv: the NUL-separated strings between mm->arg_start and mm->arg_end
+    let argv_fits = asm.label();
+    let argv_read = asm.label();
+    asm.read_kernel_to_stack(-8, R7, offsets.task_mm);
+    asm.ldx_dw(R9, R10, -8);
+    asm.read_kernel_to_stack(-16, R9, offsets.mm_arg_start);
+    asm.read_kernel_to_stack(-24, R9, offsets.mm_arg_end);
+    asm.ldx_dw(R9, R10, -24);
+    asm.ldx_dw(R3, R10, -16);
+    asm.sub(R9, R3);
+    asm.jle_imm(R9, ARGV_SIZE, argv_fits);
+    asm.mov_imm(R9, ARGV_SIZE);
+    asm.st_w(R6, FLAGS_OFFSET, ARGV_TRUNCATED as i32);
+    asm.bind(argv_fits);
+    asm.mov(R1, R6);
+    asm.add_imm(R1, ARGV_OFFSET as i32);
+    asm.mov(R2, R9);
+    asm.call(BPF_FUNC_PROBE_READ_USER);
+    asm.jeq_imm(R0, 0, argv_read);
+    asm.mov_imm(R9, 0);
+    asm.st_w(R6, FLAGS_OFFSET, ARGV_TRUNCATED as i32);
+    asm.bind(argv_read);
+
+    // cwd: task->fs->pwd, walked up like d_path() does, crossing into the parent
+    // mount at each mount root until the root mount (its own parent) is reached
+    asm.read_kernel_to_stack(-8, R7, offsets.task_fs);
+    asm.ldx_dw(R3, R10, -8);
+    asm.add_imm(R3, offsets.fs_pwd + offsets.path_dentry);
+    asm.read_kernel(BPF_FUNC_PROBE_READ_KERNEL, R10, -16, 8);
+    asm.ldx_dw(R3, R10, -8);
+    asm.add_imm(R3, offsets.fs_pwd + offsets.path_mnt);
+    asm.read_kernel(BPF_FUNC_PROBE_READ_KERNEL, R10, -24, 8);
+    asm.ldx_dw(R7, R10, -16);
+    asm.ldx_dw(R8, R10, -24);
+    // container_of(vfsmount, struct mount, mnt)
+    asm.add_imm(R8, -offsets.mount_mnt);
+
+    let cwd_done = asm.label();
+    for depth in 0..CWD_DEPTH {
+        let slot = CWD_OFFSET + (depth as i32 * CWD_COMPONENT_SIZE) as i16;
+        let mount_root = asm.label();
+        let next = asm.label();
+
+        asm.read_kernel_to_stack(-8, R8, offsets.mount_mnt + offsets.vfsmount_root);
+        asm.ldx_dw(R1, R10, -8);
+        asm.jeq(R7, R1, mount_root);
+        asm.read_kernel_to_stack(-16, R7, offsets.dentry_parent);
+        asm.ldx_dw(R1, R10, -16);
+        asm.jeq(R7, R1, mount_root);
+
+        asm.read_kernel_to_stack(-24, R7, offsets.dentry_name + offsets.qstr_name);
+        asm.ldx_dw(R3, R10, -24);
+        asm.read_kernel(BPF_FUNC_PROBE_READ_KERNEL_STR, R6, slot, CWD_COMPONENT_SIZE);
+        asm.ldx_dw(R7, R10, -16);
+        asm.ja(next);
+
+        asm.bind(mount_root);
+        asm.read_kernel_to_stack(-8, R8, offsets.mount_parent);
+        asm.ldx_dw(R1, R10, -8);
+        let not_root = asm.label();
+        asm.jne(R8, R1, not_root);
+        asm.st_w(R6, CWD_DEPTH_OFFSET, depth as i32);
+        asm.ja(cwd_done);
+        asm.bind(not_root);
+        asm.read_kernel_to_stack(-16, R8, offsets.mount_mountpoint);
+        asm.ldx_dw(R7, R10, -16);
+        asm.ldx_dw(R8, R10, -8);
+        asm.st_b(R6, slot, 0);
+
+        asm.bind(next);
+    }
+    asm.ldx_w(R1, R6, FLAGS_OFFSET);
+    asm.or_imm(R1, CWD_TRUNCATED as i32);
+    asm.stx_w(R6, FLAGS_OFFSET, R1);
+    asm.st_w(R6, CWD_DEPTH_OFFSET, CWD_DEPTH as i32);
+    asm.bind(cwd_done);
+
+    // Send the record up to the end of argv
+    asm.ld_map(R1, ring);
+    asm.mov(R2, R6);
+    asm.mov(R3, R9);
+    asm.add_imm(R3, ARGV_OFFSET as i32);
+    asm.mov_imm(R4, 0);
+    asm.call(BPF_FUNC_RINGBUF_OUTPUT);
+
+    asm.bind(out);
+    asm.mov_imm(R0, 0);
+    asm.exit();
+    asm.finish()
+}
+
+/// Program for `sched_process_exit(task, ...)`, reporting the wait status when a
+/// thread group leader exits
+fn exit_program(offsets: &Offsets, ring: &OwnedFd) -> Vec<u8> {
+    let mut asm = Assembler::default();
+    let out = asm.label();
+    let record = -EXIT_EVENT_SIZE as i16;
+
+    asm.ldx_dw(R6, R1, 0); // task
+
+    // Only the process as a whole: pid (upper half) == thread id (lower half)
+    asm.call(BPF_FUNC_GET_CURRENT_PID_TGID);
+    asm.mov(R7, R0);
+    asm.rsh_imm(R7, 32);
+    asm.lsh_imm(R0, 32);
+    asm.rsh_imm(R0, 32);
+    asm.jne(R0, R7, out);
+
+    asm.st_w(R10, record + KIND_OFFSET, EVENT_EXIT as i32);
+    asm.stx_w(R10, record + PID_OFFSET, R7);
+    asm.st_w(R10, record + PARENT_PID_OFFSET, 0);
+    asm.mov(R3, R6);
+    asm.add_imm(R3, offsets.task_exit_code);
+    asm.read_kernel(BPF_FUNC_PROBE_READ_KERNEL, R10, record + STATUS_OFFSET, 4);
+    asm.mov(R3, R6);
+    asm.add_imm(R3, offsets.task_start_time);
+    asm.read_kernel(BPF_FUNC_PROBE_READ_KERNEL, R10, record + START_TIME_OFFSET, 8);
+
+    asm.ld_map(R1, ring);
+    asm.mov(R2, R10);
+    asm.add_imm(R2, record as i32);
+    asm.mov_imm(R3, EXIT_EVENT_SIZE);
+    asm.mov_imm(R4, 0);
+    asm.call(BPF_FUNC_RINGBUF_OUTPUT);
+
+    asm.bind(out);
+    asm.mov_imm(R0, 0);
+    asm.exit();
+    asm.finish()
+}
+
+/// A jump target in an `Assembler` program
+#[derive(Clone, Copy)]
+struct Label(usize);
+
+/// Just enough of an eBPF assembler for the programs above, with forward jumps
+#[derive(Default)]
+struct Assembler {
+    instructions: Vec<[u8; 8]>,
+    /// Instruction index of each label, once bound
+    labels: Vec<Option<usize>>,
+    /// Jump instructions and the labels they target
+    jumps: Vec<(usize, Label)>,
+}
+
+impl Assembler {
+    fn emit(&mut self, code: u8, dst: u8, src: u8, off: i16, imm: i32) {
+        let mut raw = [0u8; 8];
+        raw[0] = code;
+        raw[1] = (src << 4) | (dst & 0x0f);
+        raw[2..4].copy_from_slice(&off.to_ne_bytes());
+        raw[4..8].copy_from_slice(&imm.to_ne_bytes());
+        self.instructions.push(raw);
+    }
+
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.instructions.len());
+    }
+
+    fn jump(&mut self, code: u8, dst: u8, src: u8, imm: i32, target: Label) {
+        self.jumps.push((self.instructions.len(), target));
+        self.emit(code, dst, src, 0, imm);
+    }
+
+    fn mov_imm(&mut self, dst: u8, imm: i32) {
+        self.emit(0xb7, dst, 0, 0, imm);
+    }
+
+    fn mov(&mut self, dst: u8, src: u8) {
+        self.emit(0xbf, dst, src, 0, 0);
+    }
+
+    fn add_imm(&mut self, dst: u8, imm: i32) {
+        self.emit(0x07, dst, 0, 0, imm);
+    }
+
+    fn sub(&mut self, dst: u8, src: u8) {
+        self.emit(0x1f, dst, src, 0, 0);
+    }
+
+    fn or_imm(&mut self, dst: u8, imm: i32) {
+        self.emit(0x47, dst, 0, 0, imm);
+    }
+
+    fn lsh_imm(&mut self, dst: u8, imm: i32) {
+        self.emit(0x67, dst, 0, 0, imm);
+    }
+
+    fn rsh_imm(&mut self, dst: u8, imm: i32) {
+        self.emit(0x77, dst, 0, 0, imm);
+    }
+
+    fn ldx_dw(&mut self, dst: u8, src: u8, off: i16) {
+        self.emit(0x79, dst, src, off, 0);
+    }
+
+    fn ldx_w(&mut self, dst: u8, src: u8, off: i16) {
+        self.emit(0x61, dst, src, off, 0);
+    }
+
+    fn stx_w(&mut self, dst: u8, off: i16, src: u8) {
+        self.emit(0x63, dst, src, off, 0);
+    }
+
+    fn st_w(&mut self, dst: u8, off: i16, imm: i32) {
+        self.emit(0x62, dst, 0, off, imm);
+    }
+
+    fn st_b(&mut self, dst: u8, off: i16, imm: i32) {
+        self.emit(0x72, dst, 0, off, imm);
+    }
+
+    /// Load a map reference (two-slot `ld_imm64`)
+    fn ld_map(&mut self, dst: u8, map: &OwnedFd) {
+        self.emit(0x18, dst, BPF_PSEUDO_MAP_FD, 0, map.as_raw_fd());
+        self.emit(0x00, 0, 0, 0, 0);
+    }
+
+    fn call(&mut self, helper: i32) {
+        self.emit(0x85, 0, 0, 0, helper);
+    }
+
+    fn exit(&mut self) {
+        self.emit(0x95, 0, 0, 0, 0);
+    }
+
+    fn ja(&mut self, target: Label) {
+        self.jump(0x05, 0, 0, 0, target);
+    }
+
+    fn jeq_imm(&mut self, dst: u8, imm: i32, target: Label) {
+        self.jump(0x15, dst, 0, imm, target);
+    }
+
+    fn jle_imm(&mut self, dst: u8, imm: i32, target: Label) {
+        self.jump(0xb5, dst, 0, imm, target);
+    }
+
+    fn jeq(&mut self, dst: u8, src: u8, target: Label) {
+        self.jump(0x1d, dst, src, 0, target);
+    }
+
+    fn jne(&mut self, dst: u8, src: u8, target: Label) {
+        self.jump(0x5d, dst, src, 0, target);
+    }
+
+    /// `helper(base + off, size, r3)`, a probe read from the address in r3
+    fn read_kernel(&mut self, helper: i32, base: u8, off: i16, size: i32) {
+        self.mov(R1, base);
+        self.add_imm(R1, off as i32);
+        self.mov_imm(R2, size);
+        self.call(helper);
+    }
+
+    /// Read the pointer at `src + member` into the stack slot at `r10 + slot`
+    fn read_kernel_to_stack(&mut self, slot: i16, src: u8, member: i32) {
+        self.mov(R3, src);
+        self.add_imm(R3, member);
+        self.read_kernel(BPF_FUNC_PROBE_READ_KERNEL, R10, slot, 8);
+    }
+
+    /// The program's bytecode, with jump offsets resolved
+    fn finish(mut self) -> Vec<u8> {
+        for (index, target) in std::mem::take(&mut self.jumps) {
+            let target = self.labels[target.0].expect("jump to unbound label");
+            let off = (target as isize - index as isize - 1) as i16;
+            self.instructions[index][2..4].copy_from_slice(&off.to_ne_bytes());
+        }
+        self.instructions.concat()
+    }
+}
+
+fn explain_permission(err: io::Error) -> anyhow::Error {
+    match err.raw_os_error() {
+        Some(libc::EPERM) | Some(libc::EACCES) => anyhow::Error::new(err)
+            .context("eBPF capture needs CAP_BPF and CAP_PERFMON (or root)"),
+        _ => anyhow::Error::new(err),
+    }
+}
+
+fn bpf(cmd: libc::c_long, attr: &mut [u8; BPF_ATTR_SIZE]) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr.as_mut_ptr(), BPF_ATTR_SIZE as u32) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) })
+}
+
+/// Write native-endian fields into a `bpf_attr` buffer
+fn put(attr: &mut [u8; BPF_ATTR_SIZE], offset: usize, bytes: &[u8]) {
+    attr[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+fn create_map(map_type: u32, key_size: u32, value_size: u32, max_entries: u32) -> io::Result<OwnedFd> {
+    let mut attr = [0u8; BPF_ATTR_SIZE];
+    put(&mut attr, 0, &map_type.to_ne_bytes()); // map_type
+    put(&mut attr, 4, &key_size.to_ne_bytes()); // key_size
+    put(&mut attr, 8, &value_size.to_ne_bytes()); // value_size
+    put(&mut attr, 12, &max_entries.to_ne_bytes()); // max_entries
+    bpf(BPF_MAP_CREATE, &mut attr)
+}
+
+/// Load a raw tracepoint program; when the verifier rejects it, load it again
+/// with logging to say why
+fn load_program(instructions: &[u8]) -> io::Result<OwnedFd> {
+    let license = b"GPL\0";
+    let load = |log: Option<&mut [u8]>| {
+        let mut attr = [0u8; BPF_ATTR_SIZE];
+        put(&mut attr, 0, &BPF_PROG_TYPE_RAW_TRACEPOINT.to_ne_bytes()); // prog_type
+        put(&mut attr, 4, &((instructions.len() / 8) as u32).to_ne_bytes()); // insn_cnt
+        put(&mut attr, 8, &(instructions.as_ptr() as u64).to_ne_bytes()); // insns
+        put(&mut attr, 16, &(license.as_ptr() as u64).to_ne_bytes()); // license
+        if let Some(log) = log {
+            put(&mut attr, 24, &1u32.to_ne_bytes()); // log_level
+            put(&mut attr, 28, &(log.len() as u32).to_ne_bytes()); // log_size
+            put(&mut attr, 32, &(log.as_mut_ptr() as u64).to_ne_bytes()); // log_buf
+        }
+        put(&mut attr, 48, b"compiler_mon\0"); // prog_name
+        bpf(BPF_PROG_LOAD, &mut attr)
+    };
+
+    let err = match load(None) {
+        Ok(program) => return Ok(program),
+        Err(err) if err.raw_os_error() == Some(libc::EPERM) => return Err(err),
+        Err(err) => err,
+    };
+
+    let mut log = vec![0u8; VERIFIER_LOG_SIZE];
+    let _ = load(Some(&mut log));
+    let verifier_log = c_string(&log);
+    // The reason is at the end of the log
+    let reason: Vec<&str> = verifier_log.trim().lines().rev().take(3).collect();
+    if reason.is_empty() {
+        Err(err)
+    } else {
+        let reason: Vec<&str> = reason.into_iter().rev().collect();
+        Err(io::Error::new(err.kind(), format!("{} (verifier: {})", err, reason.join("; "))))
+    }
+}
+
+fn attach_raw_tracepoint(program: &OwnedFd, tracepoint: &str) -> io::Result<OwnedFd> {
+    let name = format!("{}\0", tracepoint);
+
+    let mut attr = [0u8; BPF_ATTR_SIZE];
+    put(&mut attr, 0, &(name.as_ptr() as u64).to_ne_bytes()); // name
+    put(&mut attr, 8, &(program.as_raw_fd() as u32).to_ne_bytes()); // prog_fd
+    bpf(BPF_RAW_TRACEPOINT_OPEN, &mut attr)
+}
+
+/// Consumer side of a BPF ring buffer map
+struct RingBuffer {
+    map: OwnedFd,
+    consumer: *mut u8,
+    producer: *mut u8,
+    page_size: usize,
+}
+
+impl RingBuffer {
+    fn map(map: OwnedFd) -> io::Result<Self> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+
+        // Consumer position page (read-write), then producer position page followed
+        // by the data area mapped twice back to back (read-only)
+        let consumer = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                page_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                map.as_raw_fd(),
+                0,
+            )
+        };
+        if consumer == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let producer = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                page_size + 2 * RING_SIZE,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                map.as_raw_fd(),
+                page_size as libc::off_t,
+            )
+        };
+        if producer == libc::MAP_FAILED {
+            let err = io::Error::last_os_error();
+            unsafe { libc::munmap(consumer, page_size) };
+            return Err(err);
+        }
+
+        Ok(Self {
+            map,
+            consumer: consumer as *mut u8,
+            producer: producer as *mut u8,
+            page_size,
+        })
+    }
+
+    /// Block until the kernel signals new data, a signal arrives or the poll times out
+    fn wait(&self) -> Result<()> {
+        let mut poll_fd = libc::pollfd {
+            fd: self.map.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        if unsafe { libc::poll(&mut poll_fd, 1, POLL_TIMEOUT_MS) } >= 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINTR) {
+            return Err(err).context("Failed to poll BPF ring buffer");
+        }
+        Ok(())
+    }
+
+    /// Pass every committed sample to `consume` and release it
+    fn drain(&mut self, mut consume: impl FnMut(&[u8])) {
+        let consumer_pos = unsafe { &*(self.consumer as *const AtomicU64) };
+        let producer_pos = unsafe { &*(self.producer as *const AtomicU64) };
+        let data = unsafe { self.producer.add(self.page_size) };
+
+        let mut position = consumer_pos.load(Ordering::Acquire);
+        let end = producer_pos.load(Ordering::Acquire);
+
+        while position < end {
+            let header = unsafe { data.add(position as usize & (RING_SIZE - 1)) };
+            let length = unsafe { &*(header as *const AtomicU32) }.load(Ordering::Acquire);
+
+            // Reserved but not yet committed by the producer
+            if length & BPF_RINGBUF_BUSY_BIT != 0 {
+                break;
+            }
+
+            let sample_len = (length & !(BPF_RINGBUF_BUSY_BIT | BPF_RINGBUF_DISCARD_BIT)) as usize;
+            if length & BPF_RINGBUF_DISCARD_BIT == 0 {
+                let sample = unsafe {
+                    std::slice::from_raw_parts(header.add(BPF_RINGBUF_HDR_SZ), sample_len)
+                };
+                consume(sample);
+            }
+
+            position += ((sample_len + BPF_RINGBUF_HDR_SZ + 7) & !7) as u64;
+            consumer_pos.store(position, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for RingBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.consumer as *mut libc::c_void, self.page_size);
+            libc::munmap(self.producer as *mut libc::c_void, self.page_size + 2 * RING_SIZE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(kind: u32, pid: u32, parent_pid: u32, status: i32, start_time: u64) -> Vec<u8> {
+        let mut record = Vec::new();
+        record.extend_from_slice(&kind.to_ne_bytes());
+        record.extend_from_slice(&pid.to_ne_bytes());
+        record.extend_from_slice(&parent_pid.to_ne_bytes());
+        record.extend_from_slice(&status.to_ne_bytes());
+        record.extend_from_slice(&start_time.to_ne_bytes());
+        record
+    }
+
+    /// An exec record as the program writes it; cwd components innermost first
+    fn exec_record(flags: u32, filename: &str, cwd: &[&[u8]], argv: &[u8]) -> Vec<u8> {
+        let mut record = header(EVENT_EXEC, 42, 7, 0, 5_000_000_000);
+        record.extend_from_slice(&flags.to_ne_bytes());
+        record.extend_from_slice(&(cwd.len() as u32).to_ne_bytes());
+        let mut filename = filename.as_bytes().to_vec();
+        filename.resize(FILENAME_SIZE as usize, 0);
+        record.extend_from_slice(&filename);
+        for slot in 0..CWD_DEPTH {
+            let mut component = cwd.get(slot).copied().unwrap_or_default().to_vec();
+            component.resize(CWD_COMPONENT_SIZE as usize, 0);
+            record.extend_from_slice(&component);
+        }
+        assert_eq!(record.len(), ARGV_OFFSET as usize);
+        record.extend_from_slice(argv);
+        record
+    }
+
+    fn parse_exec(record: &[u8]) -> ExecEvent {
+        match parse_event(record) {
+            Some(KernelEvent::Exec(exec)) => exec,
+            other => panic!("expected an exec, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decodes_exec_records() {
+        let record = exec_record(0, "/usr/bin/gcc", &[b"src", b"", b"project", b"home"], b"gcc\0-c\0a.c\0");
+        assert_eq!(
+            parse_exec(&record),
+            ExecEvent {
+                pid: 42,
+                parent_pid: 7,
+                start_time: 5_000_000_000,
+                filename: Some("/usr/bin/gcc".to_string()),
+                argv: vec!["gcc".to_string(), "-c".to_string(), "a.c".to_string()],
+                argv_truncated: false,
+                working_dir: Some("/home/project/src".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_the_root_directory() {
+        let record = exec_record(0, "cc", &[], b"cc\0");
+        assert_eq!(parse_exec(&record).working_dir.as_deref(), Some("/"));
+    }
+
+    #[test]
+    fn drops_cut_off_names() {
+        let mut long = [b'x'; CWD_COMPONENT_SIZE as usize];
+        long[CWD_COMPONENT_SIZE as usize - 1] = 0;
+        assert_eq!(parse_exec(&exec_record(0, "cc", &[&long], b"cc\0")).working_dir, None);
+        assert_eq!(parse_exec(&exec_record(CWD_TRUNCATED, "cc", &[b"a"], b"cc\0")).working_dir, None);
+        assert_eq!(parse_exec(&exec_record(0, &"x".repeat(255), &[], b"cc\0")).filename, None);
+    }
+
+    #[test]
+    fn keeps_truncated_argv_marked() {
+        let exec = parse_exec(&exec_record(ARGV_TRUNCATED, "cc", &[b"a"], b"cc\0-DLONG"));
+        assert!(exec.argv_truncated);
+        assert_eq!(exec.argv, vec!["cc".to_string(), "-DLONG".to_string()]);
+    }
+
+    #[test]
+    fn decodes_exit_records() {
+        let record = header(EVENT_EXIT, 42, 0, 256, 5_000_000_000);
+        assert_eq!(
+            parse_event(&record),
+            Some(KernelEvent::Exit {
+                pid: 42,
+                start_time: 5_000_000_000,
+                status: 256,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_short_and_unknown_records() {
+        assert_eq!(parse_event(&header(EVENT_EXEC, 42, 7, 0, 0)), None);
+        assert_eq!(parse_event(&header(9, 42, 7, 0, 0)), None);
+        assert_eq!(parse_event(&[1, 0, 0]), None);
+    }
+
+    #[test]
+    fn resolves_jumps_to_labels() {
+        let mut asm = Assembler::default();
+        let end = asm.label();
+        asm.jeq_imm(R0, 0, end);
+        asm.mov_imm(R0, 1);
+        asm.bind(end);
+        asm.exit();
+        let code = asm.finish();
+        assert_eq!(i16::from_ne_bytes([code[2], code[3]]), 1);
+    }
+}
@@ -0,0 +1,134 @@
+// Capture backends
+//
+// A backend observes process creation on the host and hands every candidate
+// compiler process to `CompilerMonitor::process_creation_callback`. The monitor
+// itself is platform-neutral; which backend runs is decided per OS here.
+
+use crate::monitor::CompilerMonitor;
+use anyhow::Result;
+use clap::ValueEnum;
+use std::sync::Arc;
+
+#[cfg(all(target_os = "linux", feature = "ebpf"))]
+mod btf;
+#[cfg(all(target_os = "linux", feature = "ebpf"))]
+mod ebpf;
+#[cfg(target_os = "linux")]
+mod netlink;
+#[cfg(target_os = "linux")]
+mod preload;
+#[cfg(target_os = "linux")]
+mod procfs;
+#[cfg(target_os = "linux")]
+mod ptrace;
+#[cfg(target_os = "linux")]
+mod worker;
+#[cfg(windows)]
+mod wmi;
+
+#[cfg(target_os = "linux")]
+pub use procfs::{read_process_file, read_process_start_time, ProcfsLookup};
+#[cfg(target_os = "linux")]
+pub use ptrace::PtraceBackend;
+#[cfg(windows)]
+pub use wmi::get_process_start_time as read_process_start_time;
+
+/// A source of process creation events feeding a `CompilerMonitor`
+pub trait CaptureBackend {
+    /// Short name shown in the record banner
+    fn name(&self) -> &'static str;
+
+    /// Whether `run` reports process exits, so recorded compiles get an exit status
+    fn reports_exits(&self) -> bool {
+        false
+    }
+
+    /// Capture processes until interrupted
+    fn run(&mut self, monitor: Arc<CompilerMonitor>) -> Result<()>;
+}
+
+/// Capture backends selectable with `record --backend`
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Best backend available on this platform and privilege level
+    Auto,
+    /// Toolhelp snapshots + WMI queries (polling)
+    #[cfg(windows)]
+    Wmi,
+    /// /proc scanning (polling, no privileges needed)
+    #[cfg(target_os = "linux")]
+    Procfs,
+    /// Kernel process connector exec events (needs CAP_NET_ADMIN)
+    #[cfg(target_os = "linux")]
+    Netlink,
+    /// Exec reports from builds run with the LD_PRELOAD interposer library
+    #[cfg(target_os = "linux")]
+    Preload,
+    /// eBPF programs on the exec and exit tracepoints (needs CAP_BPF + CAP_PERFMON)
+    #[cfg(all(target_os = "linux", feature = "ebpf"))]
+    Ebpf,
+}
+
+/// Create the requested capture backend
+pub fn create_backend(kind: BackendKind) -> Result<Box<dyn CaptureBackend>> {
+    match kind {
+        BackendKind::Auto => default_backend(),
+        #[cfg(windows)]
+        BackendKind::Wmi => Ok(Box::new(wmi::WmiBackend)),
+        #[cfg(target_os = "linux")]
+        BackendKind::Procfs => Ok(Box::new(procfs::ProcfsBackend)),
+        #[cfg(target_os = "linux")]
+        BackendKind::Netlink => Ok(Box::new(netlink::NetlinkBackend::new()?)),
+        #[cfg(target_os = "linux")]
+        BackendKind::Preload => Ok(Box::new(preload::PreloadBackend)),
+        #[cfg(all(target_os = "linux", feature = "ebpf"))]
+        BackendKind::Ebpf => match ebpf::EbpfBackend::new() {
+            Ok(backend) => Ok(Box::new(backend)),
+            Err(e) => {
+                println!("Note: eBPF capture unavailable ({:#}), trying netlink process events", e);
+                linux_event_backend()
+            }
+        },
+    }
+}
+
+/// Pick the capture backend for the current platform
+pub fn default_backend() -> Result<Box<dyn CaptureBackend>> {
+    #[cfg(windows)]
+    {
+        Ok(Box::new(wmi::WmiBackend))
+    }
+
+    #[cfg(all(target_os = "linux", feature = "ebpf"))]
+    {
+        match ebpf::EbpfBackend::new() {
+            Ok(backend) => Ok(Box::new(backend)),
+            Err(e) => {
+                println!("Note: eBPF capture unavailable ({:#}), trying netlink process events", e);
+                linux_event_backend()
+            }
+        }
+    }
+
+    #[cfg(all(target_os = "linux", not(feature = "ebpf")))]
+    {
+        linux_event_backend()
+    }
+
+    #[cfg(not(any(windows, target_os = "linux")))]
+    {
+        anyhow::bail!("No capture backend is available for this platform yet")
+    }
+}
+
+/// Event-driven capture when privileged, polling otherwise
+#[cfg(target_os = "linux")]
+fn linux_event_backend() -> Result<Box<dyn CaptureBackend>> {
+    match netlink::NetlinkBackend::new() {
+        Ok(backend) => Ok(Box::new(backend)),
+        Err(e) => {
+            println!("Note: Netlink process events unavailable ({:#}), falling back to /proc polling", e);
+            Ok(Box::new(procfs::ProcfsBackend))
+        }
+    }
+}
@@ -0,0 +1,343 @@
+// Linux capture backend (netlink process connector)
+//
+// Subscribes to the kernel's process events connector (`NETLINK_CONNECTOR`,
+// `CN_IDX_PROC`) and reacts to `PROC_EVENT_EXEC`. Unlike the polling backends
+// nothing is sampled: the kernel queues one event per exec, and the receiving
+// thread reads argv, name and cwd from /proc the moment the event arrives. The
+// event itself carries only pids, so a process that has exited by then (a very
+// short compile) is still lost, along with response files deleted meanwhile.
+// `PROC_EVENT_EXIT` of a queued process gives the compile's exit status; exits
+// lost in an overrun are noticed later (see worker.rs).
+//
+// To keep up with a `make -j64` the receiving thread does nothing but parse
+// events and read /proc; everything else happens on the capture worker thread
+// (see worker.rs). The socket receive buffer is enlarged so bursts of execs are
+// queued rather than dropped, and overruns (ENOBUFS) are reported instead of
+// being silently ignored.
+//
+// Joining the connector's multicast group requires CAP_NET_ADMIN.
+
+use super::worker::{exit_code, read_exec, spawn_worker, CaptureEvent, PendingExits};
+use super::CaptureBackend;
+use crate::monitor::CompilerMonitor;
+use anyhow::{Context, Result};
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+
+// From linux/connector.h and linux/cn_proc.h
+const CN_IDX_PROC: u32 = 1;
+const CN_VAL_PROC: u32 = 1;
+const PROC_CN_MCAST_LISTEN: u32 = 1;
+const PROC_EVENT_EXEC: u32 = 0x0000_0002;
+const PROC_EVENT_EXIT: u32 = 0x8000_0000;
+
+const NLMSG_HDR_LEN: usize = 16;
+const CN_MSG_LEN: usize = 20;
+
+/// Requested socket receive buffer, enough to absorb exec bursts of large parallel builds
+const RECV_BUFFER_SIZE: libc::c_int = 8 * 1024 * 1024;
+
+/// How long a receive waits before the loop checks whether to stop
+const RECV_TIMEOUT_US: libc::suseconds_t = 200_000;
+
+/// Receives exec events from the kernel process connector
+pub struct NetlinkBackend {
+    socket: OwnedFd,
+}
+
+impl NetlinkBackend {
+    /// Open and subscribe the connector socket
+    ///
+    /// Fails without CAP_NET_ADMIN, so callers can fall back to polling.
+    pub fn new() -> Result<Self> {
+        let socket = open_proc_connector().context("Failed to subscribe to netlink process events")?;
+        Ok(Self { socket })
+    }
+}
+
+impl CaptureBackend for NetlinkBackend {
+    fn name(&self) -> &'static str {
+        "netlink"
+    }
+
+    fn reports_exits(&self) -> bool {
+        true
+    }
+
+    fn run(&mut self, monitor: Arc<CompilerMonitor>) -> Result<()> {
+        monitor_with_netlink(&self.socket, monitor)
+    }
+}
+
+fn monitor_with_netlink(socket: &OwnedFd, monitor: Arc<CompilerMonitor>) -> Result<()> {
+    println!("Starting netlink process connector monitor...");
+    println!("Press Ctrl+C to stop monitoring\n");
+
+    let (sender, worker) = spawn_worker(Arc::clone(&monitor));
+
+    println!("✓ Subscribed to process events");
+    println!("✓ Monitoring process creation...\n");
+
+    let mut buffer = vec![0u8; 64 * 1024];
+
+    let mut pending = PendingExits::new();
+
+    while !monitor.should_stop() {
+        for process in pending.expire() {
+            if sender.send(CaptureEvent::ExitLost(process)).is_err() {
+                anyhow::bail!("Capture worker thread stopped unexpectedly");
+            }
+        }
+
+        let received = unsafe {
+            libc::recv(
+                socket.as_raw_fd(),
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                0,
+            )
+        };
+
+        if received < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) | Some(libc::EAGAIN) => continue,
+                Some(libc::ENOBUFS) => {
+                    monitor.warn(None, "Netlink receive buffer overflowed, some process events were lost");
+                    continue;
+                }
+                _ => return Err(err).context("Failed to receive netlink message"),
+            }
+        }
+
+        for event in process_events(&buffer[..received as usize]) {
+            let event = match event {
+                ProcEvent::Exec(pid) => {
+                    let Some(exec) = read_exec(pid, &monitor) else {
+                        continue;
+                    };
+                    pending.insert(exec.id());
+                    CaptureEvent::Exec(exec)
+                }
+                ProcEvent::Exit { pid, status } => {
+                    let Some(process) = pending.exited(pid) else {
+                        continue;
+                    };
+                    CaptureEvent::Exit {
+                        process,
+                        exit_code: exit_code(status),
+                    }
+                }
+            };
+
+            if sender.send(event).is_err() {
+                anyhow::bail!("Capture worker thread stopped unexpectedly");
+            }
+        }
+    }
+
+    // Let the worker record everything still queued
+    drop(sender);
+    let _ = worker.join();
+
+    Ok(())
+}
+
+fn open_proc_connector() -> io::Result<OwnedFd> {
+    unsafe {
+        let fd = libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            libc::NETLINK_CONNECTOR,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = OwnedFd::from_raw_fd(fd);
+
+        // SO_RCVBUFFORCE ignores rmem_max but needs CAP_NET_ADMIN, which binding requires anyway
+        let size = RECV_BUFFER_SIZE;
+        for option in [libc::SO_RCVBUFFORCE, libc::SO_RCVBUF] {
+            let rc = libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &size as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            );
+            if rc == 0 {
+                break;
+            }
+        }
+
+        // Wake up regularly to notice Ctrl+C and the idle timeout
+        let timeout = libc::timeval {
+            tv_sec: 0,
+            tv_usec: RECV_TIMEOUT_US,
+        };
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeout as *const _ as *const libc::c_void,
+            mem::size_of::<libc::timeval>() as libc::socklen_t,
+        );
+
+        let mut addr: libc::sockaddr_nl = mem::zeroed();
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_pid = 0;
+        addr.nl_groups = CN_IDX_PROC;
+
+        if libc::bind(
+            fd,
+            &addr as *const _ as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        ) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        // nlmsghdr + cn_msg + PROC_CN_MCAST_LISTEN
+        let total_len = NLMSG_HDR_LEN + CN_MSG_LEN + mem::size_of::<u32>();
+        let mut message = Vec::with_capacity(total_len);
+        message.extend_from_slice(&(total_len as u32).to_ne_bytes());
+        message.extend_from_slice(&(libc::NLMSG_DONE as u16).to_ne_bytes());
+        message.extend_from_slice(&0u16.to_ne_bytes()); // flags
+        message.extend_from_slice(&0u32.to_ne_bytes()); // seq
+        message.extend_from_slice(&0u32.to_ne_bytes()); // port id, 0 lets the kernel assign
+        message.extend_from_slice(&CN_IDX_PROC.to_ne_bytes());
+        message.extend_from_slice(&CN_VAL_PROC.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes()); // seq
+        message.extend_from_slice(&0u32.to_ne_bytes()); // ack
+        message.extend_from_slice(&(mem::size_of::<u32>() as u16).to_ne_bytes());
+        message.extend_from_slice(&0u16.to_ne_bytes()); // flags
+        message.extend_from_slice(&PROC_CN_MCAST_LISTEN.to_ne_bytes());
+
+        if libc::send(fd, message.as_ptr() as *const libc::c_void, message.len(), 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(socket)
+    }
+}
+
+/// Process connector events the backend reacts to
+#[derive(Debug, PartialEq)]
+enum ProcEvent {
+    Exec(u32),
+    /// Exit of a whole process, with its wait status
+    Exit { pid: u32, status: i32 },
+}
+
+/// Extract all exec and process exit events in a received datagram
+fn process_events(datagram: &[u8]) -> Vec<ProcEvent> {
+    let read_u32 = |bytes: &[u8], offset: usize| -> Option<u32> {
+        bytes
+            .get(offset..offset + 4)
+            .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+    };
+
+    let mut events = Vec::new();
+    let mut offset = 0;
+
+    while let Some(msg_len) = read_u32(datagram, offset) {
+        let msg_len = msg_len as usize;
+        if msg_len < NLMSG_HDR_LEN || offset + msg_len > datagram.len() {
+            break;
+        }
+        let message = &datagram[offset..offset + msg_len];
+
+        // proc_event: what, cpu, timestamp_ns, then event_data
+        let event = &message[(NLMSG_HDR_LEN + CN_MSG_LEN).min(message.len())..];
+        match read_u32(event, 0) {
+            Some(PROC_EVENT_EXEC) => {
+                // exec: process_pid, process_tgid
+                if let Some(tgid) = read_u32(event, 20) {
+                    events.push(ProcEvent::Exec(tgid));
+                }
+            }
+            Some(PROC_EVENT_EXIT) => {
+                // exit: process_pid, process_tgid, exit_code, exit_signal; threads exit too
+                if let (Some(pid), Some(tgid), Some(status)) =
+                    (read_u32(event, 16), read_u32(event, 20), read_u32(event, 24))
+                {
+                    if pid == tgid {
+                        events.push(ProcEvent::Exit {
+                            pid,
+                            status: status as i32,
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        // NLMSG_ALIGN
+        offset += (msg_len + 3) & !3;
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One netlink message carrying a proc_event with `data` as its event_data
+    fn message(what: u32, data: &[u32]) -> Vec<u8> {
+        let len = NLMSG_HDR_LEN + CN_MSG_LEN + 16 + 4 * data.len();
+        let mut message = Vec::new();
+        message.extend_from_slice(&(len as u32).to_ne_bytes());
+        message.extend_from_slice(&(libc::NLMSG_DONE as u16).to_ne_bytes());
+        message.extend_from_slice(&[0; 10]); // flags, seq, port id
+        message.extend_from_slice(&CN_IDX_PROC.to_ne_bytes());
+        message.extend_from_slice(&CN_VAL_PROC.to_ne_bytes());
+        message.extend_from_slice(&[0; 8]); // seq, ack
+        message.extend_from_slice(&((16 + 4 * data.len()) as u16).to_ne_bytes());
+        message.extend_from_slice(&[0; 2]); // flags
+        message.extend_from_slice(&what.to_ne_bytes());
+        message.extend_from_slice(&[0; 12]); // cpu, timestamp_ns
+        for word in data {
+            message.extend_from_slice(&word.to_ne_bytes());
+        }
+        message
+    }
+
+    #[test]
+    fn reads_exec_events_by_thread_group() {
+        // A thread of process 100 executing; exec makes it the leader
+        let datagram = message(PROC_EVENT_EXEC, &[101, 100]);
+        assert_eq!(process_events(&datagram), [ProcEvent::Exec(100)]);
+    }
+
+    #[test]
+    fn reads_exits_of_whole_processes_only() {
+        let mut datagram = message(PROC_EVENT_EXIT, &[101, 100, 0, 17]);
+        datagram.extend(message(PROC_EVENT_EXIT, &[100, 100, 3 << 8, 17]));
+        assert_eq!(process_events(&datagram), [ProcEvent::Exit { pid: 100, status: 3 << 8 }]);
+    }
+
+    #[test]
+    fn reads_every_message_of_a_datagram_and_skips_other_events() {
+        const PROC_EVENT_FORK: u32 = 1;
+        let mut datagram = message(PROC_EVENT_FORK, &[1, 1, 200, 200]);
+        datagram.extend(message(PROC_EVENT_EXEC, &[200, 200]));
+        datagram.extend(message(PROC_EVENT_EXEC, &[300, 300]));
+        assert_eq!(process_events(&datagram), [ProcEvent::Exec(200), ProcEvent::Exec(300)]);
+    }
+
+    #[test]
+    fn stops_at_truncated_messages() {
+        let mut datagram = message(PROC_EVENT_EXEC, &[200, 200]);
+        let second = message(PROC_EVENT_EXEC, &[300, 300]);
+        datagram.extend_from_slice(&second[..second.len() - 4]);
+        assert_eq!(process_events(&datagram), [ProcEvent::Exec(200)]);
+
+        // An exec event too short for its tgid
+        assert_eq!(process_events(&message(PROC_EVENT_EXEC, &[200])), []);
+        assert_eq!(process_events(&[]), []);
+        assert_eq!(process_events(&[1, 2, 3]), []);
+    }
+}
@@ -0,0 +1,283 @@
+// Linux capture backend (LD_PRELOAD listener)
+//
+// Counterpart of the `compiler_monitor_preload` interposer library. The build is run
+// with that library preloaded; every exec or spawn in it connects to the Unix socket
+// served here and sends its argv, cwd and environment (see preload/src/lib.rs for the
+// wire format). The exec waits for our acknowledgement, so response files are read
+// before the compiler even starts. No ptrace or other privileges are required.
+//
+// A spawn is reported before the child exists, as pid 0; its ancestry, user and
+// cgroups are those of the spawning process (see `ChildLookup`). Each spawn gets a
+// sequence number in place of its start time, so two spawns are never taken for
+// the same process.
+
+use super::procfs::{read_process_start_time, ChildLookup};
+use super::CaptureBackend;
+use crate::monitor::{join_arguments, CompilerMonitor, ProcessInfo};
+use crate::tree::parent_chain;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Name of the interposer library built from the `preload` crate
+const PRELOAD_LIBRARY: &str = "libcompiler_monitor_preload.so";
+
+/// Leading field of every message, see the preload crate
+const PROTOCOL_MAGIC: &str = "CMP1";
+
+/// Socket file created inside the cache directory
+const SOCKET_NAME: &str = "preload.sock";
+
+/// Most execs handled at once; further ones wait in the socket's backlog
+const MAX_HANDLERS: usize = 64;
+
+/// Serves exec reports from preloaded build processes
+pub struct PreloadBackend;
+
+impl CaptureBackend for PreloadBackend {
+    fn name(&self) -> &'static str {
+        "preload"
+    }
+
+    fn run(&mut self, monitor: Arc<CompilerMonitor>) -> Result<()> {
+        monitor_with_preload(monitor)
+    }
+}
+
+/// One exec as reported by the interposer
+struct ExecMessage {
+    /// 0 for a spawn, whose child does not exist yet
+    pid: u32,
+    parent_pid: u32,
+    cwd: String,
+    path: String,
+    argv: Vec<String>,
+    environment: BTreeMap<String, String>,
+}
+
+fn monitor_with_preload(monitor: Arc<CompilerMonitor>) -> Result<()> {
+    let socket_path = std::path::absolute(monitor.cache_dir().join(SOCKET_NAME))
+        .context("Failed to resolve preload socket path")?;
+
+    // A socket left behind by an earlier session would make bind fail
+    if socket_path.exists() {
+        fs::remove_file(&socket_path)
+            .with_context(|| format!("Failed to remove stale socket {}", socket_path.display()))?;
+    }
+
+    let listener = UnixListener::bind(&socket_path)
+        .with_context(|| format!("Failed to listen on {}", socket_path.display()))?;
+    // Accept without blocking, to notice Ctrl+C and the idle timeout between connections
+    listener
+        .set_nonblocking(true)
+        .context("Failed to configure preload socket")?;
+
+    println!("Starting LD_PRELOAD exec listener...");
+    println!("Run your build with:\n");
+    println!("  LD_PRELOAD={} \\", preload_library_path().display());
+    println!("  COMPILER_MONITOR_SOCKET={} <build command>\n", socket_path.display());
+    println!("Press Ctrl+C to stop monitoring\n");
+
+    let mut handlers = Vec::new();
+    let spawns = Arc::new(AtomicU64::new(0));
+
+    while !monitor.should_stop() {
+        handlers.retain(|handler: &std::thread::JoinHandle<()>| !handler.is_finished());
+        if handlers.len() >= MAX_HANDLERS {
+            std::thread::sleep(Duration::from_millis(5));
+            continue;
+        }
+
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(20));
+                continue;
+            }
+            Err(e) => {
+                monitor.warn(None, &format!("Failed to accept preload connection: {}", e));
+                continue;
+            }
+        };
+        if let Err(e) = stream.set_nonblocking(false) {
+            monitor.warn(None, &format!("Failed to configure preload connection: {}", e));
+            continue;
+        }
+
+        // Parallel builds report many execs at once, each waiting for its answer
+        let monitor = Arc::clone(&monitor);
+        let spawns = Arc::clone(&spawns);
+        handlers.push(std::thread::spawn(move || handle_connection(stream, &monitor, &spawns)));
+    }
+
+    // Finish the execs being recorded, and release them
+    for handler in handlers {
+        let _ = handler.join();
+    }
+    let _ = fs::remove_file(&socket_path);
+
+    Ok(())
+}
+
+fn handle_connection(mut stream: UnixStream, monitor: &CompilerMonitor, spawns: &AtomicU64) {
+    let mut data = Vec::new();
+    if stream.read_to_end(&mut data).is_err() {
+        return;
+    }
+
+    match parse_message(&data) {
+        Some(exec) => {
+            let process_name = Path::new(&exec.path)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+
+            // The hooks wait for our ack, so the process (or the spawner) is still
+            // there to be looked up
+            let lookup = ChildLookup {
+                child: exec.pid,
+                parent: exec.parent_pid,
+            };
+            if monitor.matches_process_name(&process_name) && monitor.scope().allows(exec.pid, &exec.cwd, &lookup) {
+                // execve paths may be relative to the caller's working directory; a bare
+                // name is one the hooks found nowhere in PATH, and is kept as is
+                let executable = match exec.path.contains('/') {
+                    true => Path::new(&exec.cwd).join(&exec.path),
+                    false => PathBuf::from(&exec.path),
+                };
+                // exec keeps the start time, so the caller's is the compiler's
+                let start_time = match exec.pid {
+                    0 => spawns.fetch_add(1, Ordering::Relaxed) + 1,
+                    pid => read_process_start_time(pid).unwrap_or_default(),
+                };
+                monitor.capture_process(&ProcessInfo {
+                    pid: exec.pid,
+                    start_time,
+                    parent_pid: Some(exec.parent_pid),
+                    name: process_name,
+                    executable: executable.to_string_lossy().to_string(),
+                    command_line: join_arguments(&exec.argv),
+                    working_dir: exec.cwd,
+                    environment: exec.environment,
+                    parents: parent_chain(exec.pid, &lookup),
+                });
+            }
+        }
+        None => monitor.warn(None, "Ignoring malformed message from preload library"),
+    }
+
+    // Release the waiting exec
+    let _ = stream.write_all(&[1]);
+}
+
+fn parse_message(data: &[u8]) -> Option<ExecMessage> {
+    // Every field is NUL-terminated, so a complete message ends with one
+    let mut fields = data
+        .strip_suffix(&[0])?
+        .split(|&b| b == 0)
+        .map(|field| String::from_utf8_lossy(field).to_string());
+
+    if fields.next()? != PROTOCOL_MAGIC {
+        return None;
+    }
+
+    let pid: u32 = fields.next()?.parse().ok()?;
+    let parent_pid: u32 = fields.next()?.parse().ok()?;
+    let cwd = fields.next()?;
+    let path = fields.next()?;
+
+    let argc: usize = fields.next()?.parse().ok()?;
+    let argv: Vec<String> = fields.by_ref().take(argc).collect();
+    if argv.len() != argc {
+        return None;
+    }
+
+    let envc: usize = fields.next()?.parse().ok()?;
+    let environment: Vec<String> = fields.by_ref().take(envc).collect();
+    if environment.len() != envc {
+        return None;
+    }
+    let environment = environment
+        .into_iter()
+        .filter_map(|entry| {
+            let (name, value) = entry.split_once('=')?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect();
+
+    Some(ExecMessage { pid, parent_pid, cwd, path, argv, environment })
+}
+
+/// Where the interposer library is expected: next to our own executable
+fn preload_library_path() -> PathBuf {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join(PRELOAD_LIBRARY)))
+        .unwrap_or_else(|| PathBuf::from(PRELOAD_LIBRARY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A message as the interposer writes it: NUL-terminated fields
+    fn message(fields: &[&str]) -> Vec<u8> {
+        fields.iter().flat_map(|field| field.bytes().chain([0])).collect()
+    }
+
+    #[test]
+    fn parses_an_exec() {
+        let data = message(&[
+            "CMP1", "1234", "1200", "/src", "/usr/bin/gcc", "3", "gcc", "-c", "a c.c", "2", "CPATH=/opt/inc", "EMPTY=",
+        ]);
+        let exec = parse_message(&data).unwrap();
+        assert_eq!((exec.pid, exec.parent_pid), (1234, 1200));
+        assert_eq!(exec.cwd, "/src");
+        assert_eq!(exec.path, "/usr/bin/gcc");
+        assert_eq!(exec.argv, ["gcc", "-c", "a c.c"]);
+        assert_eq!(
+            exec.environment,
+            BTreeMap::from([
+                ("CPATH".to_string(), "/opt/inc".to_string()),
+                ("EMPTY".to_string(), String::new()),
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_spawns_and_empty_lists() {
+        // posix_spawn reports before the child exists, getcwd may fail
+        let exec = parse_message(&message(&["CMP1", "0", "1200", "", "/usr/bin/cc", "0", "0"])).unwrap();
+        assert_eq!(exec.pid, 0);
+        assert_eq!(exec.cwd, "");
+        assert!(exec.argv.is_empty());
+        assert!(exec.environment.is_empty());
+    }
+
+    #[test]
+    fn keeps_values_containing_equals_and_drops_entries_without() {
+        let exec =
+            parse_message(&message(&["CMP1", "1", "0", "/", "/bin/cl", "1", "cl", "2", "CL=/DA=1", "BROKEN"])).unwrap();
+        assert_eq!(exec.environment, BTreeMap::from([("CL".to_string(), "/DA=1".to_string())]));
+    }
+
+    #[test]
+    fn rejects_other_and_truncated_messages() {
+        assert!(parse_message(&message(&["CMP2", "1", "0", "/", "/bin/cc", "0", "0"])).is_none());
+        assert!(parse_message(&message(&["CMP1", "x", "0", "/", "/bin/cc", "0", "0"])).is_none());
+        // Fewer arguments or variables than announced
+        assert!(parse_message(&message(&["CMP1", "1", "0", "/", "/bin/cc", "3", "cc", "-c"])).is_none());
+        assert!(parse_message(&message(&["CMP1", "1", "0", "/", "/bin/cc", "1", "cc", "2", "A=1"])).is_none());
+        assert!(parse_message(&message(&["CMP1", "1", "0", "/"])).is_none());
+        let mut data = message(&["CMP1", "1", "0", "/", "/bin/cc", "0", "0"]);
+        data.pop();
+        assert!(parse_message(&data).is_none());
+        assert!(parse_message(&[]).is_none());
+    }
+}
@@ -0,0 +1,388 @@
+// Linux capture backend (/proc polling)
+//
+// The Linux counterpart of the WMI backend: instead of Toolhelp snapshots it walks
+// the numeric entries of /proc every 50ms and, for each new process whose name
+// matches the pattern, reads:
+//
+// - `/proc/<pid>/comm` and `/proc/<pid>/exe` for the process name and executable path
+// - `/proc/<pid>/cmdline` for the real NUL-separated argv
+// - `/proc/<pid>/cwd` for the exact working directory
+//
+// Like any polling approach it can miss compiles that start and exit between two
+// scans.
+
+use super::CaptureBackend;
+use crate::identity::{ProcessId, SeenProcesses};
+use crate::monitor::{join_arguments, CompilerMonitor, ProcessInfo};
+use crate::scope::ProcessLookup;
+use crate::tree::parent_chain;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Length limit of `/proc/<pid>/comm` (TASK_COMM_LEN - 1)
+const COMM_MAX_LEN: usize = 15;
+
+/// Polls /proc for matching processes
+pub struct ProcfsBackend;
+
+impl CaptureBackend for ProcfsBackend {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn run(&mut self, monitor: Arc<CompilerMonitor>) -> Result<()> {
+        monitor_with_procfs(monitor)
+    }
+}
+
+fn monitor_with_procfs(monitor: Arc<CompilerMonitor>) -> Result<()> {
+    println!("Starting /proc-based process monitor...");
+    println!("Press Ctrl+C to stop monitoring\n");
+
+    // Fail early if /proc is not mounted rather than spinning forever
+    fs::read_dir("/proc").context("Failed to read /proc")?;
+
+    println!("✓ Monitoring process creation...\n");
+
+    let mut known_processes = SeenProcesses::default();
+
+    while !monitor.should_stop() {
+        for entry in fs::read_dir("/proc").context("Failed to read /proc")?.flatten() {
+            let pid = match entry.file_name().to_str().and_then(|s| s.parse::<u32>().ok()) {
+                Some(pid) => pid,
+                None => continue,
+            };
+
+            let Some(process_name) = read_process_name(pid) else {
+                continue;
+            };
+
+            // Check if this matches our pattern
+            if !monitor.matches_process_name(&process_name) {
+                continue;
+            }
+
+            let Some(start_time) = read_process_start_time(pid) else {
+                continue;
+            };
+            if !known_processes.observe(ProcessId { pid, start_time }) {
+                continue;
+            }
+            let Some(argv) = read_process_argv(pid) else {
+                continue;
+            };
+
+            let executable = read_process_executable(pid).unwrap_or_else(|| argv[0].clone());
+
+            // Left empty for the monitor to recover if unreadable
+            let work_dir = read_process_working_directory(pid).unwrap_or_default();
+            if !monitor.scope().allows(pid, &work_dir, &ProcfsLookup) {
+                continue;
+            }
+
+            monitor.capture_process(&ProcessInfo {
+                pid,
+                start_time,
+                parent_pid: ProcfsLookup.parent(pid),
+                name: process_name,
+                executable,
+                command_line: join_arguments(&argv),
+                working_dir: work_dir,
+                environment: read_process_environment(pid).unwrap_or_default(),
+                parents: parent_chain(pid, &ProcfsLookup),
+            });
+        }
+
+        known_processes.expire();
+
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    Ok(())
+}
+
+/// Name of the executable, untruncated where possible
+///
+/// `comm` is cut to 15 bytes, so a name of exactly that length is completed from
+/// the basename of argv[0] or of the `exe` link when one of them extends it.
+pub fn read_process_name(pid: u32) -> Option<String> {
+    let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    let comm = comm.trim_end_matches('\n').to_string();
+
+    if comm.len() < COMM_MAX_LEN {
+        return Some(comm);
+    }
+
+    let argv0 = read_process_argv(pid).and_then(|argv| argv.into_iter().next());
+    let exe = read_process_executable(pid);
+
+    let full_name = [argv0, exe]
+        .into_iter()
+        .flatten()
+        .filter_map(|path| {
+            Path::new(&path)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+        })
+        .find(|name| name.starts_with(&comm));
+
+    Some(full_name.unwrap_or(comm))
+}
+
+/// The process argv as stored in `/proc/<pid>/cmdline`
+///
+/// Returns `None` for kernel threads and zombies, whose cmdline is empty.
+pub fn read_process_argv(pid: u32) -> Option<Vec<String>> {
+    let bytes = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+
+    let argv: Vec<String> = bytes
+        .split(|&b| b == 0)
+        .map(|arg| String::from_utf8_lossy(arg).to_string())
+        .collect();
+
+    // The buffer ends with a NUL, which leaves one empty trailing element
+    let argv = match argv.split_last() {
+        Some((last, rest)) if last.is_empty() => rest.to_vec(),
+        _ => argv,
+    };
+
+    if argv.is_empty() {
+        None
+    } else {
+        Some(argv)
+    }
+}
+
+/// Full path of the executable from the `/proc/<pid>/exe` link
+pub fn read_process_executable(pid: u32) -> Option<String> {
+    fs::read_link(format!("/proc/{}/exe", pid))
+        .ok()
+        .map(|p| p.to_string_lossy().to_string())
+}
+
+/// The working directory of a process from the `/proc/<pid>/cwd` link
+pub fn read_process_working_directory(pid: u32) -> Option<String> {
+    fs::read_link(format!("/proc/{}/cwd", pid))
+        .ok()
+        .map(|p| p.to_string_lossy().to_string())
+}
+
+/// Start time of a process in clock ticks since boot, field 22 of `/proc/<pid>/stat`
+pub fn read_process_start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    stat_field(&stat, 22)?.parse().ok()
+}
+
+/// Field `number` (counted from 1, as in proc(5)) of a `/proc/<pid>/stat` line
+fn stat_field(stat: &str, number: usize) -> Option<&str> {
+    // comm (field 2) is in parentheses and may contain spaces and parentheses itself
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(number.checked_sub(3)?)
+}
+
+/// The environment a process was started with, from `/proc/<pid>/environ`
+pub fn read_process_environment(pid: u32) -> Option<BTreeMap<String, String>> {
+    let environ = fs::read(format!("/proc/{}/environ", pid)).ok()?;
+
+    let environment = environ
+        .split(|&b| b == 0)
+        .filter_map(|entry| {
+            let entry = String::from_utf8_lossy(entry);
+            let (name, value) = entry.split_once('=')?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect();
+    Some(environment)
+}
+
+/// Read a file as a process sees it, when it cannot be read by path from here
+///
+/// Tries the path under `/proc/<pid>/root`, which covers processes in other mount
+/// namespaces (containers, chroots), then the process's open file descriptors, which
+/// still reach a file that has been deleted since it was opened.
+pub fn read_process_file(pid: u32, path: &Path) -> Option<Vec<u8>> {
+    let relative = path.strip_prefix("/").unwrap_or(path);
+    if let Ok(bytes) = fs::read(Path::new(&format!("/proc/{}/root", pid)).join(relative)) {
+        return Some(bytes);
+    }
+
+    // A deleted file's link reads "<path> (deleted)"
+    let deleted = format!("{} (deleted)", path.display());
+    fs::read_dir(format!("/proc/{}/fd", pid))
+        .ok()?
+        .flatten()
+        .find(|fd| {
+            fs::read_link(fd.path())
+                .is_ok_and(|target| target == path || target.as_os_str() == deleted.as_str())
+        })
+        .and_then(|fd| fs::read(fd.path()).ok())
+}
+
+/// Process facts for scope filters, read from /proc
+pub struct ProcfsLookup;
+
+impl ProcessLookup for ProcfsLookup {
+    fn parent(&self, pid: u32) -> Option<u32> {
+        read_status_field(pid, "PPid:")?.parse().ok()
+    }
+
+    fn name(&self, pid: u32) -> Option<String> {
+        read_process_name(pid)
+    }
+
+    fn command_line(&self, pid: u32) -> Option<String> {
+        read_process_argv(pid).map(|argv| join_arguments(&argv))
+    }
+
+    fn working_dir(&self, pid: u32) -> Option<String> {
+        read_process_working_directory(pid)
+    }
+
+    fn user(&self, pid: u32) -> Option<String> {
+        // Real, effective, saved and filesystem uid; the real uid owns the process
+        let uids = read_status_field(pid, "Uid:")?;
+        uids.split_whitespace().next().map(|uid| uid.to_string())
+    }
+
+    fn cgroups(&self, pid: u32) -> Vec<String> {
+        // One `hierarchy-id:controllers:path` line per hierarchy, `0::path` for cgroup v2
+        fs::read_to_string(format!("/proc/{}/cgroup", pid))
+            .map(|contents| {
+                contents
+                    .lines()
+                    .filter_map(|line| line.splitn(3, ':').nth(2))
+                    .map(|path| path.to_string())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn is_session_leader(&self, pid: u32) -> bool {
+        fs::read_to_string(format!("/proc/{}/stat", pid))
+            .is_ok_and(|stat| stat_field(&stat, 6) == Some(pid.to_string().as_str()))
+    }
+}
+
+/// `ProcfsLookup` for a process /proc may not show, a child reported before it was
+/// spawned or after it exited, whose parent the backend was told
+///
+/// The child's user and cgroups fall back to the parent's, which it inherits.
+pub struct ChildLookup {
+    pub child: u32,
+    pub parent: u32,
+}
+
+impl ProcessLookup for ChildLookup {
+    fn parent(&self, pid: u32) -> Option<u32> {
+        if pid == self.child {
+            Some(self.parent)
+        } else {
+            ProcfsLookup.parent(pid)
+        }
+    }
+
+    fn name(&self, pid: u32) -> Option<String> {
+        ProcfsLookup.name(pid)
+    }
+
+    fn command_line(&self, pid: u32) -> Option<String> {
+        ProcfsLookup.command_line(pid)
+    }
+
+    fn working_dir(&self, pid: u32) -> Option<String> {
+        ProcfsLookup.working_dir(pid)
+    }
+
+    fn user(&self, pid: u32) -> Option<String> {
+        ProcfsLookup.user(pid).or_else(|| {
+            (pid == self.child)
+                .then(|| ProcfsLookup.user(self.parent))
+                .flatten()
+        })
+    }
+
+    fn cgroups(&self, pid: u32) -> Vec<String> {
+        let cgroups = ProcfsLookup.cgroups(pid);
+        if cgroups.is_empty() && pid == self.child {
+            ProcfsLookup.cgroups(self.parent)
+        } else {
+            cgroups
+        }
+    }
+
+    fn is_session_leader(&self, pid: u32) -> bool {
+        ProcfsLookup.is_session_leader(pid)
+    }
+}
+
+/// Value of one `Name:` line of `/proc/<pid>/status`
+fn read_status_field(pid: u32, field: &str) -> Option<String> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(field))
+        .map(|value| value.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempTree;
+
+    /// A stat line as in proc(5), up to vsize (field 23), for a process named `comm`
+    fn stat(comm: &str) -> String {
+        format!("1234 ({}) S 1 1234 1234 0 -1 4194560 100 0 0 0 1 2 0 0 20 0 1 0 987654 1000\n", comm)
+    }
+
+    #[test]
+    fn stat_fields_are_counted_after_the_name() {
+        for comm in ["gcc", "my compiler", "a) b (c", ")", "x) S 9 9 9"] {
+            let stat = stat(comm);
+            assert_eq!(stat_field(&stat, 3), Some("S"), "{:?}", comm);
+            assert_eq!(stat_field(&stat, 6), Some("1234"), "{:?}", comm);
+            assert_eq!(stat_field(&stat, 22), Some("987654"), "{:?}", comm);
+        }
+    }
+
+    #[test]
+    fn missing_stat_fields() {
+        assert_eq!(stat_field(&stat("gcc"), 24), None);
+        assert_eq!(stat_field(&stat("gcc"), 2), None);
+        assert_eq!(stat_field("1234 gcc S 1", 3), None);
+    }
+
+    #[test]
+    fn start_times_identify_running_processes() {
+        let own = read_process_start_time(std::process::id()).unwrap();
+        assert!(own > 0);
+        assert_eq!(read_process_start_time(std::process::id()), Some(own));
+        assert_eq!(read_process_start_time(u32::MAX), None);
+    }
+
+    #[test]
+    fn files_are_read_through_the_process_root() {
+        let tree = TempTree::new("procfs_root", &[]);
+        let path = tree.path().join("a.rsp");
+        fs::write(&path, "-DA=1").unwrap();
+        assert_eq!(read_process_file(std::process::id(), &path), Some(b"-DA=1".to_vec()));
+    }
+
+    #[test]
+    fn deleted_files_are_read_through_open_descriptors() {
+        let tree = TempTree::new("procfs_deleted", &[]);
+        let path = tree.path().join("a b.rsp");
+        fs::write(&path, "-c a.c").unwrap();
+
+        // Deleted while the process still has it open, as a build tool's temp file
+        let open = fs::File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read_process_file(std::process::id(), &path), Some(b"-c a.c".to_vec()));
+
+        drop(open);
+        assert_eq!(read_process_file(std::process::id(), &path), None);
+    }
+}
@@ -0,0 +1,249 @@
+// Linux capture backend for `run` (ptrace exec tracing)
+//
+// Launches the build command as a traced child and follows every fork, vfork and
+// clone in its process tree, like `strace -f` or bear. Each `execve` stops the
+// tracee (PTRACE_EVENT_EXEC) while its argv and cwd are read from /proc, so
+// nothing is lost to races and response files are still on disk when they are
+// inlined. Only descendants of the build are ever seen.
+//
+// The build is traced with PTRACE_SEIZE rather than PTRACE_TRACEME, as only then
+// can job control stops (Ctrl+Z, SIGSTOP) be told apart and left in place with
+// PTRACE_LISTEN; continuing them would undo the stop. The child still starts
+// with PTRACE_TRACEME, as it cannot seize itself, and is handed over right after
+// its first exec: detached into a SIGSTOP, seized while stopped, and continued.
+//
+// Capture ends when the root process exits. Descendants that outlive it (e.g. a
+// compiler cache server that daemonized) are detached by the kernel when the
+// tracer stops and keep running untraced.
+
+use super::worker::{exit_code, read_exec};
+use super::CaptureBackend;
+use crate::identity::ProcessId;
+use crate::monitor::{join_arguments, CompilerMonitor};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::ptr;
+use std::sync::Arc;
+
+/// Runs a build command and traces every exec in its process tree
+pub struct PtraceBackend {
+    command: Vec<String>,
+    exit_code: Option<i32>,
+}
+
+impl PtraceBackend {
+    pub fn new(command: Vec<String>) -> Self {
+        Self {
+            command,
+            exit_code: None,
+        }
+    }
+
+    /// Exit code of the build once `run` has returned (128 + signal if it was killed)
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+}
+
+impl CaptureBackend for PtraceBackend {
+    fn name(&self) -> &'static str {
+        "ptrace"
+    }
+
+    fn reports_exits(&self) -> bool {
+        true
+    }
+
+    fn run(&mut self, monitor: Arc<CompilerMonitor>) -> Result<()> {
+        self.exit_code = Some(trace_build(&self.command, &monitor)?);
+        Ok(())
+    }
+}
+
+fn trace_build(command: &[String], monitor: &CompilerMonitor) -> Result<i32> {
+    let (program, args) = command
+        .split_first()
+        .context("No build command given")?;
+
+    println!("Starting ptrace exec tracer...");
+    println!("Build:   {}\n", join_arguments(command));
+
+    // Ctrl+C reaches the whole foreground process group; let the build handle it and
+    // keep tracing until the root exits so its compiles are still recorded
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_IGN);
+    }
+
+    let mut child_command = Command::new(program);
+    child_command.args(args);
+    unsafe {
+        child_command.pre_exec(|| {
+            // Restore default Ctrl+C handling for the build itself
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+            if libc::ptrace(libc::PTRACE_TRACEME, 0, ptr::null_mut::<libc::c_void>(), ptr::null_mut::<libc::c_void>()) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    let child = child_command
+        .spawn()
+        .with_context(|| format!("Failed to start {}", program))?;
+    let root = child.id() as libc::pid_t;
+
+    // The traced child stops with SIGTRAP right after its initial exec
+    let mut status = 0;
+    if unsafe { libc::waitpid(root, &mut status, libc::__WALL) } < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to wait for build process");
+    }
+    if !libc::WIFSTOPPED(status) {
+        anyhow::bail!("Build process exited before tracing could start");
+    }
+
+    // Captured processes by pid, for reporting their exit
+    let mut captured: HashMap<libc::pid_t, ProcessId> = HashMap::new();
+
+    // The build command itself may be a compiler
+    if let Some(process) = on_exec(root as u32, monitor) {
+        captured.insert(root, process);
+    }
+
+    seize(root).context("Failed to trace build process")?;
+
+    println!("✓ Tracing build process (pid {})\n", root);
+
+    loop {
+        let pid = unsafe { libc::waitpid(-1, &mut status, libc::__WALL) };
+        if pid < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // No tracees left
+                Some(libc::ECHILD) => return Ok(0),
+                _ => return Err(err).context("Failed to wait for traced processes"),
+            }
+        }
+
+        if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+            let code = exit_code(status);
+            if let Some(process) = captured.remove(&pid) {
+                if let Err(e) = monitor.process_exit_callback(process, code) {
+                    monitor.warn(Some(pid as u32), &format!("Failed to record exit of pid {}: {:#}", pid, e));
+                }
+            }
+            if pid == root {
+                println!("✓ Build finished with exit code {}", code);
+                return Ok(code);
+            }
+            continue;
+        }
+
+        if !libc::WIFSTOPPED(status) {
+            continue;
+        }
+
+        let signal = libc::WSTOPSIG(status);
+        let event = status >> 16;
+
+        match event {
+            libc::PTRACE_EVENT_EXEC => {
+                match on_exec(pid as u32, monitor) {
+                    Some(process) => captured.insert(pid, process),
+                    None => captured.remove(&pid),
+                };
+                resume(pid, 0);
+            }
+            // A group-stop stays in place until SIGCONT
+            libc::PTRACE_EVENT_STOP if is_stop_signal(signal) => listen(pid),
+            // Deliver the signal the tracee actually received
+            0 => resume(pid, signal),
+            // fork/vfork/clone (the new tracee is attached automatically), the first
+            // stop of a new tracee, or the end of a group-stop
+            _ => resume(pid, 0),
+        }
+    }
+}
+
+/// Trace a process stopped under PTRACE_TRACEME with PTRACE_SEIZE instead
+fn seize(pid: libc::pid_t) -> io::Result<()> {
+    let options = libc::PTRACE_O_TRACEFORK
+        | libc::PTRACE_O_TRACEVFORK
+        | libc::PTRACE_O_TRACECLONE
+        | libc::PTRACE_O_TRACEEXEC;
+
+    unsafe {
+        if libc::ptrace(libc::PTRACE_DETACH, pid, ptr::null_mut::<libc::c_void>(), libc::SIGSTOP as usize as *mut libc::c_void) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut status = 0;
+        loop {
+            if libc::waitpid(pid, &mut status, libc::WUNTRACED) < 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() == Some(libc::EINTR) {
+                    continue;
+                }
+                return Err(err);
+            }
+            if libc::WIFSTOPPED(status) {
+                break;
+            }
+            if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+                return Err(io::Error::other("the build process exited"));
+            }
+        }
+
+        if libc::ptrace(libc::PTRACE_SEIZE, pid, ptr::null_mut::<libc::c_void>(), options as usize as *mut libc::c_void) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::kill(pid, libc::SIGCONT) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Read argv and cwd of a process stopped right after exec and hand it to the monitor
+///
+/// Returns the process if it was handed over.
+fn on_exec(pid: u32, monitor: &CompilerMonitor) -> Option<ProcessId> {
+    // Processed synchronously: the tracee stays stopped, so its response files stay put
+    let exec = read_exec(pid, monitor)?;
+
+    monitor.capture_process(&exec);
+    Some(exec.id())
+}
+
+fn resume(pid: libc::pid_t, signal: libc::c_int) {
+    unsafe {
+        libc::ptrace(
+            libc::PTRACE_CONT,
+            pid,
+            ptr::null_mut::<libc::c_void>(),
+            signal as usize as *mut libc::c_void,
+        );
+    }
+}
+
+/// Leave a tracee in its group-stop, but keep reporting it; PTRACE_CONT would resume it
+fn listen(pid: libc::pid_t) {
+    unsafe {
+        libc::ptrace(
+            libc::PTRACE_LISTEN,
+            pid,
+            ptr::null_mut::<libc::c_void>(),
+            ptr::null_mut::<libc::c_void>(),
+        );
+    }
+}
+
+/// Signals whose default action is stopping the process; with PTRACE_SEIZE a
+/// group-stop is reported as PTRACE_EVENT_STOP with one of these
+fn is_stop_signal(signal: libc::c_int) -> bool {
+    matches!(signal, libc::SIGSTOP | libc::SIGTSTP | libc::SIGTTIN | libc::SIGTTOU)
+}
@@ -0,0 +1,202 @@
+// Exec hand-off shared by the event-driven Linux backends
+//
+// Event receivers must stay fast enough to never fall behind the kernel, so they
+// only read what has to be read while the process is alive (name, argv, cwd from
+// /proc) and queue it. Response file inlining and cache writes happen here, on a
+// worker thread. Exits travel through the same queue, so a compile's exit status
+// is always applied after its exec was recorded. When capture stops, the receiver
+// drops its sender and joins the worker, so everything queued is still recorded.
+//
+// Receivers remember the compiles whose exit is still to come in `PendingExits`.
+// An exit the kernel dropped, in a receive buffer overrun or a lost ring buffer
+// record, would keep its process there for good, so pending processes are checked
+// for still existing once a minute; one that is gone on two checks in a row is
+// forgotten, and the monitor no longer counts it as running.
+
+use super::procfs::{
+    read_process_argv, read_process_environment, read_process_executable, read_process_name,
+    read_process_start_time, read_process_working_directory, ProcfsLookup,
+};
+use crate::identity::ProcessId;
+use crate::monitor::{join_arguments, CompilerMonitor, ProcessInfo};
+use crate::scope::ProcessLookup;
+use crate::tree::parent_chain;
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often pending processes are checked for having exited unnoticed
+const PENDING_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Read a freshly exec'd process, if its name matches the monitor's pattern and it is in scope
+pub fn read_exec(pid: u32, monitor: &CompilerMonitor) -> Option<ProcessInfo> {
+    let process_name = read_process_name(pid)?;
+
+    if !monitor.matches_process_name(&process_name) {
+        return None;
+    }
+
+    let argv = read_process_argv(pid)?;
+    let executable = read_process_executable(pid).unwrap_or_else(|| argv[0].clone());
+
+    // Left empty for the monitor to recover if unreadable
+    let working_dir = read_process_working_directory(pid).unwrap_or_default();
+
+    if !monitor.scope().allows(pid, &working_dir, &ProcfsLookup) {
+        return None;
+    }
+
+    Some(ProcessInfo {
+        pid,
+        start_time: read_process_start_time(pid).unwrap_or_default(),
+        parent_pid: ProcfsLookup.parent(pid),
+        name: process_name,
+        executable,
+        command_line: join_arguments(&argv),
+        working_dir,
+        environment: read_process_environment(pid).unwrap_or_default(),
+        parents: parent_chain(pid, &ProcfsLookup),
+    })
+}
+
+/// A process event queued for the worker thread
+pub enum CaptureEvent {
+    Exec(ProcessInfo),
+    Exit { process: ProcessId, exit_code: i32 },
+    /// A captured process that is gone without its exit having been received
+    ExitLost(ProcessId),
+}
+
+/// Captured processes whose exit is still to come
+pub struct PendingExits {
+    /// Start time, and whether the process was gone at the last check
+    processes: HashMap<u32, (u64, bool)>,
+    last_check: Instant,
+}
+
+impl PendingExits {
+    pub fn new() -> Self {
+        Self {
+            processes: HashMap::new(),
+            last_check: Instant::now(),
+        }
+    }
+
+    /// Await the exit of a process handed to the worker
+    pub fn insert(&mut self, process: ProcessId) {
+        self.processes.insert(process.pid, (process.start_time, false));
+    }
+
+    /// The pending process with this pid, which is no longer pending once it exited
+    pub fn exited(&mut self, pid: u32) -> Option<ProcessId> {
+        let (start_time, _) = self.processes.remove(&pid)?;
+        Some(ProcessId { pid, start_time })
+    }
+
+    /// Pending processes whose exit was lost, checked at most once a minute
+    pub fn expire(&mut self) -> Vec<ProcessId> {
+        if self.last_check.elapsed() < PENDING_CHECK_INTERVAL {
+            return Vec::new();
+        }
+        self.last_check = Instant::now();
+        self.expire_with(|process| read_process_start_time(process.pid) == Some(process.start_time))
+    }
+
+    /// A process may be gone while its exit still waits to be received, so it is
+    /// only given up once it is missing on a second check
+    fn expire_with(&mut self, exists: impl Fn(ProcessId) -> bool) -> Vec<ProcessId> {
+        let mut lost = Vec::new();
+        self.processes.retain(|&pid, (start_time, missing)| {
+            let process = ProcessId { pid, start_time: *start_time };
+            if exists(process) {
+                *missing = false;
+            } else if *missing {
+                lost.push(process);
+                return false;
+            } else {
+                *missing = true;
+            }
+            true
+        });
+        lost
+    }
+}
+
+/// Exit code of a wait status, 128 + signal number if the process was killed
+pub fn exit_code(status: i32) -> i32 {
+    if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        libc::WEXITSTATUS(status)
+    }
+}
+
+/// Start the thread that feeds queued events to the monitor's callbacks; it ends
+/// once the sender is dropped and the queue is drained
+pub fn spawn_worker(monitor: Arc<CompilerMonitor>) -> (Sender<CaptureEvent>, JoinHandle<()>) {
+    let (sender, receiver) = mpsc::channel::<CaptureEvent>();
+
+    let worker = std::thread::spawn(move || {
+        for event in receiver {
+            match event {
+                CaptureEvent::Exec(exec) => {
+                    monitor.capture_process(&exec);
+                }
+                CaptureEvent::Exit { process, exit_code } => {
+                    if let Err(e) = monitor.process_exit_callback(process, exit_code) {
+                        monitor.warn(Some(process.pid), &format!("Failed to record exit of pid {}: {:#}", process.pid, e));
+                    }
+                }
+                CaptureEvent::ExitLost(process) => monitor.process_exit_lost(process),
+            }
+        }
+    });
+
+    (sender, worker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GCC: ProcessId = ProcessId { pid: 100, start_time: 5 };
+
+    #[test]
+    fn exits_end_pending_processes() {
+        let mut pending = PendingExits::new();
+        pending.insert(GCC);
+        assert_eq!(pending.exited(100), Some(GCC));
+        assert_eq!(pending.exited(100), None);
+        assert_eq!(pending.exited(101), None);
+    }
+
+    #[test]
+    fn processes_gone_on_two_checks_are_lost() {
+        let mut pending = PendingExits::new();
+        let clang = ProcessId { pid: 200, start_time: 9 };
+        pending.insert(GCC);
+        pending.insert(clang);
+
+        // gcc's exit may still be on its way
+        assert_eq!(pending.expire_with(|process| process == clang), []);
+        assert_eq!(pending.expire_with(|process| process == clang), [GCC]);
+        assert_eq!(pending.exited(100), None);
+
+        // Seen again in between, so not lost
+        assert_eq!(pending.expire_with(|_| false), []);
+        assert_eq!(pending.expire_with(|_| true), []);
+        assert_eq!(pending.expire_with(|_| false), []);
+        assert_eq!(pending.exited(200), Some(clang));
+    }
+
+    #[test]
+    fn checks_wait_for_their_interval() {
+        let mut pending = PendingExits::new();
+        pending.insert(GCC);
+        assert_eq!(pending.expire(), []);
+        assert_eq!(pending.expire(), []);
+        assert_eq!(pending.exited(100), Some(GCC));
+    }
+}
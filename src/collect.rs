@@ -0,0 +1,286 @@
+// Merging of cached command files into compile_commands.json
+
+use crate::environment;
+use crate::monitor::{join_arguments, split_arguments, CachedCommand};
+use crate::pattern::glob_to_regex;
+use crate::workdir::DirectoryOrigin;
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+/// Which recorded compiles end up in compile_commands.json
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeepMode {
+    /// Every compile not known to have failed
+    Successful,
+    /// Every compile, failed ones included
+    All,
+    /// The most recent compile per file that is not known to have failed
+    Latest,
+}
+
+/// Options shared by `collect` and `run`
+#[derive(Args, Debug)]
+pub struct CollectArgs {
+    /// Which compiles to keep; compiles whose exit was not observed count as successful
+    #[arg(long, value_enum, default_value = "successful")]
+    keep: KeepMode,
+
+    /// Keep probe compiles (CMake try_compile, conftest, version queries) in the output
+    #[arg(long)]
+    include_probes: bool,
+
+    /// Add the INCLUDE/EXTERNAL_INCLUDE directories of each compile's environment
+    /// (Visual Studio developer prompt) as /imsvc, or -isystem for GNU-style drivers
+    #[arg(long)]
+    env_includes: bool,
+
+    /// Only keep compiles of build targets matching this glob, e.g. "ninja **/build"
+    /// or "make **/src/lib" (repeatable)
+    #[arg(long, value_name = "GLOB")]
+    target: Vec<String>,
+}
+
+pub fn collect_commands(cache_dir: &Path, output_path: &Path, options: &CollectArgs) -> Result<()> {
+    println!("Collecting commands from cache...");
+
+    if !cache_dir.exists() {
+        anyhow::bail!("Cache directory does not exist: {}", cache_dir.display());
+    }
+
+    let targets = options
+        .target
+        .iter()
+        .map(|glob| glob_to_regex(glob).with_context(|| format!("Invalid target glob '{}'", glob)))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Command files with their number, which orders them by capture time
+    let mut cached_commands = Vec::new();
+    let mut probes = 0;
+    let mut failed = 0;
+    let mut other_targets = 0;
+    let mut incomplete = 0;
+
+    // Read all JSON files from cache directory
+    for entry in fs::read_dir(cache_dir).context("Failed to read cache directory")? {
+        let entry = entry?;
+        let path = entry.path();
+
+        if path.extension().and_then(|s| s.to_str()) == Some("json") {
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+
+            // A number claimed by a recorder that was killed, or is still writing it
+            if contents.is_empty() {
+                incomplete += 1;
+                continue;
+            }
+
+            let cached: CachedCommand = serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse JSON from {}", path.display()))?;
+
+            if cached.probe.is_some() && !options.include_probes {
+                probes += 1;
+                continue;
+            }
+            if !cached.succeeded() && options.keep != KeepMode::All {
+                failed += 1;
+                continue;
+            }
+            if !targets.is_empty()
+                && !cached
+                    .target
+                    .as_ref()
+                    .is_some_and(|target| targets.iter().any(|glob| glob.is_match(target)))
+            {
+                other_targets += 1;
+                continue;
+            }
+
+            let number = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.strip_prefix("command_"))
+                .and_then(|number| number.parse::<u64>().ok())
+                .unwrap_or(0);
+            cached_commands.push((number, cached));
+        }
+    }
+
+    println!("  Found {} command(s)", cached_commands.len() + probes + failed + other_targets);
+    if incomplete > 0 {
+        println!("  ⚠ Skipped {} empty command file(s), interrupted or still being written", incomplete);
+    }
+    if probes > 0 {
+        println!("  Skipped {} probe compile(s), use --include-probes to keep them", probes);
+    }
+    if failed > 0 {
+        println!("  Skipped {} failed compile(s), use --keep all to keep them", failed);
+    }
+    if other_targets > 0 {
+        println!("  Skipped {} compile(s) of other targets", other_targets);
+    }
+
+    if options.keep == KeepMode::Latest {
+        let mut latest: HashMap<String, (u64, CachedCommand)> = HashMap::new();
+        for (number, cached) in cached_commands {
+            match latest.get(&cached.command.file) {
+                Some((newest, _)) if *newest > number => {}
+                _ => {
+                    latest.insert(cached.command.file.clone(), (number, cached));
+                }
+            }
+        }
+        cached_commands = latest.into_values().collect();
+    }
+
+    // Build targets the kept compiles belong to
+    let mut target_counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for (_, cached) in &cached_commands {
+        if let Some(target) = &cached.target {
+            *target_counts.entry(target).or_default() += 1;
+        }
+    }
+    if !target_counts.is_empty() {
+        println!("  Targets:");
+        for (target, count) in &target_counts {
+            println!("    {:>5}  {}", count, target);
+        }
+    }
+
+    let origin_count = |origin| {
+        cached_commands
+            .iter()
+            .filter(|(_, cached)| cached.directory_origin == Some(origin))
+            .count()
+    };
+    let inferred = origin_count(DirectoryOrigin::Inferred);
+    let unresolved = origin_count(DirectoryOrigin::Unresolved);
+    if inferred > 0 {
+        println!("  {} command(s) with an inferred working directory", inferred);
+    }
+    if unresolved > 0 {
+        println!("  ⚠ {} command(s) with an unresolved working directory, their relative paths may be wrong", unresolved);
+    }
+
+    let mut env_includes = 0;
+    let mut commands: Vec<_> = cached_commands
+        .into_iter()
+        .map(|(_, mut cached)| {
+            if options.env_includes {
+                let environment = cached.environment.unwrap_or_default();
+                let argv = split_arguments(&cached.command.command);
+                if let Some(argv) = environment::add_msvc_system_includes(&argv, &environment) {
+                    cached.command.command = join_arguments(&argv);
+                    env_includes += 1;
+                }
+            }
+            cached.command
+        })
+        .collect();
+    let count = commands.len();
+
+    if options.env_includes {
+        println!("  Added INCLUDE directories to {} command(s)", env_includes);
+    }
+
+    // Sort by file path for consistent ordering
+    commands.sort_by(|a, b| a.file.cmp(&b.file));
+
+    // Write to output file
+    let json = serde_json::to_string_pretty(&commands)
+        .context("Failed to serialize commands")?;
+
+    fs::write(output_path, json)
+        .with_context(|| format!("Failed to write to {}", output_path.display()))?;
+
+    println!("✓ Written to {}", output_path.display());
+    println!("✓ Total commands: {}", count);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::CompileCommand;
+    use crate::test_support::TempTree;
+
+    fn options(keep: KeepMode) -> CollectArgs {
+        CollectArgs {
+            keep,
+            include_probes: false,
+            env_includes: false,
+            target: Vec::new(),
+        }
+    }
+
+    /// A `cache/` holding `entries` as (file, exit code) in capture order, plus a probe
+    fn cache(name: &str, entries: &[(&str, Option<i32>)]) -> TempTree {
+        let tree = TempTree::new(name, &["cache/"]);
+        let cache = tree.path().join("cache");
+        for (number, (file, exit_code)) in entries.iter().enumerate() {
+            let mut entry = serde_json::json!({
+                "directory": "/src",
+                "command": format!("gcc -c {} -DRUN={}", file, number),
+                "file": file,
+            });
+            if let Some(exit_code) = exit_code {
+                entry["exit_code"] = (*exit_code).into();
+            }
+            fs::write(cache.join(format!("command_{:06}.json", number + 1)), entry.to_string()).unwrap();
+        }
+
+        let probe = r#"{"directory": "/src", "command": "gcc -c conftest.c", "file": "conftest.c", "probe": "autoconf"}"#;
+        fs::write(cache.join(format!("command_{:06}.json", entries.len() + 1)), probe).unwrap();
+        tree
+    }
+
+    /// The sorted commands `collect` writes with `options`
+    fn collected(tree: &TempTree, options: &CollectArgs) -> Vec<String> {
+        let output = tree.path().join("compile_commands.json");
+        collect_commands(&tree.path().join("cache"), &output, options).unwrap();
+        let commands: Vec<CompileCommand> = serde_json::from_str(&fs::read_to_string(&output).unwrap()).unwrap();
+        let mut commands: Vec<String> = commands.into_iter().map(|command| command.command).collect();
+        commands.sort();
+        commands
+    }
+
+    #[test]
+    fn failed_compiles_are_left_out_unless_all_are_kept() {
+        let tree = cache("collect_failed", &[("a.c", Some(0)), ("b.c", Some(1)), ("c.c", None), ("d.c", Some(137))]);
+        assert_eq!(
+            collected(&tree, &options(KeepMode::Successful)),
+            ["gcc -c a.c -DRUN=0", "gcc -c c.c -DRUN=2"]
+        );
+        assert_eq!(
+            collected(&tree, &options(KeepMode::All)),
+            ["gcc -c a.c -DRUN=0", "gcc -c b.c -DRUN=1", "gcc -c c.c -DRUN=2", "gcc -c d.c -DRUN=3"]
+        );
+    }
+
+    #[test]
+    fn latest_keeps_the_newest_successful_compile_per_file() {
+        let tree = cache(
+            "collect_latest",
+            &[("a.c", Some(0)), ("b.c", Some(0)), ("a.c", None), ("a.c", Some(1)), ("b.c", Some(2))],
+        );
+        assert_eq!(
+            collected(&tree, &options(KeepMode::Latest)),
+            ["gcc -c a.c -DRUN=2", "gcc -c b.c -DRUN=1"]
+        );
+    }
+
+    #[test]
+    fn probes_and_interrupted_entries_are_skipped() {
+        let tree = cache("collect_probes", &[("a.c", None)]);
+        fs::write(tree.path().join("cache/command_000099.json"), "").unwrap();
+        assert_eq!(collected(&tree, &options(KeepMode::All)), ["gcc -c a.c -DRUN=0"]);
+
+        let mut with_probes = options(KeepMode::All);
+        with_probes.include_probes = true;
+        assert_eq!(collected(&tree, &with_probes), ["gcc -c a.c -DRUN=0", "gcc -c conftest.c"]);
+    }
+}
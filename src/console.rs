@@ -0,0 +1,135 @@
+// Taking over the process's stdout
+//
+// Capture backends and the monitor print their progress with plain `println!`.
+// When stdout is needed for something else (the NDJSON event stream, the TUI
+// dashboard), the original stdout is duplicated for that use and the standard
+// output handle is pointed elsewhere, so all of that printing follows without
+// every print site knowing about it.
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::Write;
+#[cfg(feature = "tui")]
+use std::path::PathBuf;
+
+/// Where the console output goes while stdout is taken
+pub enum Redirect {
+    Stderr,
+    /// Appended to this file
+    #[cfg(feature = "tui")]
+    File(PathBuf),
+}
+
+/// Point the standard output handle elsewhere and return the original stdout
+#[cfg(target_os = "linux")]
+pub fn take_stdout(redirect: Redirect) -> Result<File> {
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    std::io::stdout().flush()?;
+
+    let target: Option<File> = match &redirect {
+        Redirect::Stderr => None,
+        #[cfg(feature = "tui")]
+        Redirect::File(path) => Some(open_log(path)?),
+    };
+    let target_fd = target.as_ref().map_or(libc::STDERR_FILENO, |file| file.as_raw_fd());
+
+    unsafe {
+        let original = libc::dup(libc::STDOUT_FILENO);
+        if original < 0 || libc::dup2(target_fd, libc::STDOUT_FILENO) < 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to redirect stdout");
+        }
+        Ok(File::from_raw_fd(original))
+    }
+}
+
+/// Point the standard output handle back at `original`, as returned by `take_stdout`
+#[cfg(all(target_os = "linux", feature = "tui"))]
+pub fn restore_stdout(original: &File) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    std::io::stdout().flush()?;
+    if unsafe { libc::dup2(original.as_raw_fd(), libc::STDOUT_FILENO) } < 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to restore stdout");
+    }
+    Ok(())
+}
+
+/// Point the standard output handle elsewhere and return the original stdout
+#[cfg(windows)]
+pub fn take_stdout(redirect: Redirect) -> Result<File> {
+    use std::os::windows::io::FromRawHandle;
+    #[cfg(feature = "tui")]
+    use std::os::windows::io::IntoRawHandle;
+    #[cfg(feature = "tui")]
+    use windows::Win32::Foundation::HANDLE;
+    use windows::Win32::System::Console::{GetStdHandle, SetStdHandle, STD_ERROR_HANDLE, STD_OUTPUT_HANDLE};
+
+    std::io::stdout().flush()?;
+
+    unsafe {
+        // Rust looks up the standard handle on every write to stdout. The returned
+        // File owns a duplicate, as with dup() on Linux: dropping it must not close
+        // the console handle, which the process and its children still use.
+        let original = duplicate_handle(GetStdHandle(STD_OUTPUT_HANDLE).context("Failed to get stdout")?)
+            .context("Failed to duplicate stdout")?;
+        let target = match &redirect {
+            Redirect::Stderr => GetStdHandle(STD_ERROR_HANDLE).context("Failed to get stderr")?,
+            // Stays open for the rest of the process lifetime
+            #[cfg(feature = "tui")]
+            Redirect::File(path) => HANDLE(open_log(path)?.into_raw_handle()),
+        };
+        SetStdHandle(STD_OUTPUT_HANDLE, target).context("Failed to redirect stdout")?;
+        Ok(File::from_raw_handle(original.0))
+    }
+}
+
+/// Point the standard output handle back at `original`, as returned by `take_stdout`
+#[cfg(all(windows, feature = "tui"))]
+pub fn restore_stdout(original: &File) -> Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use windows::Win32::Foundation::HANDLE;
+    use windows::Win32::System::Console::{SetStdHandle, STD_OUTPUT_HANDLE};
+
+    std::io::stdout().flush()?;
+    // The standard handle gets its own duplicate, so `original` can still be dropped
+    unsafe {
+        let handle = duplicate_handle(HANDLE(original.as_raw_handle())).context("Failed to duplicate stdout")?;
+        SetStdHandle(STD_OUTPUT_HANDLE, handle).context("Failed to restore stdout")
+    }
+}
+
+/// A new handle to the same console or file, to be closed independently
+#[cfg(windows)]
+unsafe fn duplicate_handle(
+    handle: windows::Win32::Foundation::HANDLE,
+) -> windows::core::Result<windows::Win32::Foundation::HANDLE> {
+    use windows::Win32::Foundation::{DuplicateHandle, DUPLICATE_SAME_ACCESS, FALSE, HANDLE};
+    use windows::Win32::System::Threading::GetCurrentProcess;
+
+    let process = GetCurrentProcess();
+    let mut duplicate = HANDLE::default();
+    DuplicateHandle(process, handle, process, &mut duplicate, 0, FALSE, DUPLICATE_SAME_ACCESS)?;
+    Ok(duplicate)
+}
+
+/// Point the standard output handle elsewhere and return the original stdout
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn take_stdout(_redirect: Redirect) -> Result<File> {
+    anyhow::bail!("Redirecting stdout is not available on this platform yet")
+}
+
+/// Point the standard output handle back at `original`, as returned by `take_stdout`
+#[cfg(all(not(any(target_os = "linux", windows)), feature = "tui"))]
+pub fn restore_stdout(_original: &File) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(all(any(target_os = "linux", windows), feature = "tui"))]
+fn open_log(path: &std::path::Path) -> Result<File> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))
+}
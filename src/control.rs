@@ -0,0 +1,439 @@
+// Control socket of a recording daemon
+//
+// `record --daemon` serves a local control endpoint next to the capture: a Unix
+// socket `control.sock` in the cache directory, or on Windows a named pipe whose
+// name is derived from the cache directory. `compiler_monitor control <action>`
+// connects to the daemon recording into a given cache directory, so scripts and
+// IDE integrations can drive a capture running in the background.
+//
+// The protocol is one request line (`status`, `pause`, `resume`, `stop`, `flush`)
+// answered with a JSON object, after which the connection is closed:
+//
+//   {"ok": true, "message": "Paused", "status": {"state": "paused", ...}}
+//
+// `flush` collects the cache into the daemon's output file without stopping.
+
+use crate::monitor::{CompilerMonitor, SessionStats};
+use crate::shutdown;
+use anyhow::{Context, Result};
+use clap::Subcommand;
+use serde::Serialize;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+#[cfg(unix)]
+use std::time::Duration;
+
+/// Control socket file created inside the cache directory
+#[cfg(unix)]
+const SOCKET_NAME: &str = "control.sock";
+
+/// How long a client may take to send its request line
+#[cfg(unix)]
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Held while `flush` collects, as pipe clients are answered concurrently
+static COLLECTING: Mutex<()> = Mutex::new(());
+
+/// Collects the cache for `flush`
+pub type FlushFn = Box<dyn Fn() -> Result<()> + Send + Sync>;
+
+/// Requests understood by a recording daemon
+#[derive(Subcommand, Debug, Clone, Copy)]
+pub enum ControlAction {
+    /// Print the daemon's state, patterns, cache directory and counters
+    Status,
+    /// Stop capturing new compiles until resumed
+    Pause,
+    /// Capture compiles again after a pause
+    Resume,
+    /// Stop recording, as Ctrl+C in the daemon's console would
+    Stop,
+    /// Collect the cache into the daemon's output file now
+    Flush,
+}
+
+impl ControlAction {
+    fn request(self) -> &'static str {
+        match self {
+            ControlAction::Status => "status",
+            ControlAction::Pause => "pause",
+            ControlAction::Resume => "resume",
+            ControlAction::Stop => "stop",
+            ControlAction::Flush => "flush",
+        }
+    }
+}
+
+/// Answer to a control request
+#[derive(Serialize)]
+struct Response {
+    ok: bool,
+    message: String,
+    status: Status,
+}
+
+/// A daemon's state as reported to `control status`
+#[derive(Serialize)]
+struct Status {
+    /// "recording", "paused" or "stopping"
+    state: &'static str,
+    patterns: Vec<String>,
+    cache_dir: PathBuf,
+    /// Number of the last command file in the cache
+    command_counter: u64,
+    /// Number of the last response file in the cache
+    response_counter: u64,
+    #[serde(flatten)]
+    session: SessionStats,
+}
+
+/// A running control endpoint; the socket file is removed when it is dropped
+pub struct ControlServer {
+    #[cfg(unix)]
+    socket_path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.socket_path);
+    }
+}
+
+/// Start answering control requests for `monitor` on a background thread
+#[cfg(unix)]
+pub fn serve(monitor: Arc<CompilerMonitor>, flush: FlushFn) -> Result<ControlServer> {
+    use std::os::unix::net::UnixListener;
+
+    let socket_path = std::path::absolute(monitor.cache_dir().join(SOCKET_NAME))
+        .context("Failed to resolve control socket path")?;
+
+    // A socket left behind by an earlier session would make bind fail; a live one
+    // belongs to another daemon recording into the same cache
+    if socket_path.exists() {
+        if std::os::unix::net::UnixStream::connect(&socket_path).is_ok() {
+            anyhow::bail!("Another daemon is already recording into {}", monitor.cache_dir().display());
+        }
+        std::fs::remove_file(&socket_path)
+            .with_context(|| format!("Failed to remove stale socket {}", socket_path.display()))?;
+    }
+
+    let listener = UnixListener::bind(&socket_path)
+        .with_context(|| format!("Failed to listen on {}", socket_path.display()))?;
+    println!("✓ Control socket: {}", socket_path.display());
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // A client that connects and sends nothing must not block the others
+            if stream.set_read_timeout(Some(REQUEST_TIMEOUT)).is_ok() {
+                answer(&stream, &stream, &monitor, &flush);
+            }
+        }
+    });
+
+    Ok(ControlServer { socket_path })
+}
+
+/// Start answering control requests for `monitor` on a background thread
+#[cfg(windows)]
+pub fn serve(monitor: Arc<CompilerMonitor>, flush: FlushFn) -> Result<ControlServer> {
+    use std::fs::File;
+    use std::os::windows::io::{AsRawHandle, FromRawHandle};
+    use windows::core::{HRESULT, PCWSTR};
+    use windows::Win32::Foundation::{ERROR_PIPE_CONNECTED, HANDLE};
+    use windows::Win32::Storage::FileSystem::PIPE_ACCESS_DUPLEX;
+    use windows::Win32::System::Pipes::{
+        ConnectNamedPipe, CreateNamedPipeW, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS,
+        PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+    };
+
+    let pipe_name = pipe_name(monitor.cache_dir())?;
+    let wide_name: Vec<u16> = pipe_name.encode_utf16().chain(std::iter::once(0)).collect();
+
+    // One pipe instance per client, the next created once the current one is
+    // connected; owned as a File, which closes it when dropped
+    let create_instance = move || {
+        let pipe = unsafe {
+            CreateNamedPipeW(
+                PCWSTR(wide_name.as_ptr()),
+                PIPE_ACCESS_DUPLEX,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                4096,
+                4096,
+                0,
+                None,
+            )
+        };
+        (!pipe.is_invalid()).then(|| unsafe { File::from_raw_handle(pipe.0) })
+    };
+
+    let first = create_instance()
+        .with_context(|| format!("Failed to create control pipe {}", pipe_name))?;
+    println!("✓ Control pipe: {}", pipe_name);
+
+    // Synchronous pipes have no read timeout, so each client is answered on its own
+    // thread, and one that connects and sends nothing blocks only itself
+    let flush = Arc::new(flush);
+    std::thread::spawn(move || {
+        let mut next = Some(first);
+        while let Some(pipe) = next.take().or_else(&create_instance) {
+            let connected = match unsafe { ConnectNamedPipe(HANDLE(pipe.as_raw_handle()), None) } {
+                Ok(()) => true,
+                // A client that connected before the wait counts as connected
+                Err(e) => e.code() == HRESULT::from_win32(ERROR_PIPE_CONNECTED.0),
+            };
+            if connected {
+                let monitor = Arc::clone(&monitor);
+                let flush = Arc::clone(&flush);
+                std::thread::spawn(move || {
+                    answer(&pipe, &pipe, &monitor, &flush);
+                    // Wait for the client to read the answer before the pipe is closed
+                    let _ = pipe.sync_all();
+                });
+            }
+        }
+    });
+
+    Ok(ControlServer {})
+}
+
+/// Control is not available on this platform
+#[cfg(not(any(unix, windows)))]
+pub fn serve(_monitor: Arc<CompilerMonitor>, _flush: FlushFn) -> Result<ControlServer> {
+    anyhow::bail!("Daemon mode is not available on this platform yet")
+}
+
+/// Read one request and write its answer
+fn answer(reader: impl Read, mut writer: impl Write, monitor: &CompilerMonitor, flush: &FlushFn) {
+    let mut request = String::new();
+    if BufReader::new(reader).read_line(&mut request).is_err() {
+        return;
+    }
+
+    let (ok, message) = match request.trim() {
+        "status" => (true, "OK".to_string()),
+        "pause" => {
+            monitor.set_paused(true);
+            println!("⏸ Paused by control request");
+            (true, "Paused".to_string())
+        }
+        "resume" => {
+            monitor.set_paused(false);
+            println!("▶ Resumed by control request");
+            (true, "Resumed".to_string())
+        }
+        "stop" => {
+            shutdown::request_stop();
+            (true, "Stopping".to_string())
+        }
+        "flush" => {
+            let _collecting = COLLECTING.lock().unwrap_or_else(|e| e.into_inner());
+            match flush() {
+                Ok(()) => (true, "Collected".to_string()),
+                Err(e) => (false, format!("Failed to collect: {:#}", e)),
+            }
+        }
+        other => (false, format!("Unknown request '{}'", other)),
+    };
+
+    let response = Response {
+        ok,
+        message,
+        status: status(monitor),
+    };
+    if let Ok(json) = serde_json::to_string_pretty(&response) {
+        let _ = writer.write_all(json.as_bytes());
+        let _ = writer.write_all(b"\n");
+    }
+}
+
+fn status(monitor: &CompilerMonitor) -> Status {
+    let state = if shutdown::stop_requested() {
+        "stopping"
+    } else if monitor.is_paused() {
+        "paused"
+    } else {
+        "recording"
+    };
+
+    Status {
+        state,
+        patterns: monitor.patterns().to_vec(),
+        cache_dir: std::path::absolute(monitor.cache_dir()).unwrap_or_else(|_| monitor.cache_dir().to_path_buf()),
+        command_counter: monitor.command_counter(),
+        response_counter: monitor.response_counter(),
+        session: monitor.stats(),
+    }
+}
+
+/// Send a request to the daemon recording into `cache_dir` and print its answer
+///
+/// Returns whether the daemon reported success.
+pub fn send(cache_dir: &Path, action: ControlAction) -> Result<bool> {
+    let response = exchange(cache_dir, action.request())?;
+    print!("{}", response);
+
+    let ok = serde_json::from_str::<serde_json::Value>(&response)
+        .ok()
+        .and_then(|value| value.get("ok").and_then(|ok| ok.as_bool()))
+        .unwrap_or(false);
+    Ok(ok)
+}
+
+#[cfg(unix)]
+fn exchange(cache_dir: &Path, request: &str) -> Result<String> {
+    use std::os::unix::net::UnixStream;
+
+    let socket_path = cache_dir.join(SOCKET_NAME);
+    let mut stream = UnixStream::connect(&socket_path)
+        .with_context(|| format!("No recording daemon is listening on {}", socket_path.display()))?;
+
+    writeln!(stream, "{}", request).context("Failed to send control request")?;
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .context("Failed to read control response")?;
+    Ok(response)
+}
+
+#[cfg(windows)]
+fn exchange(cache_dir: &Path, request: &str) -> Result<String> {
+    let pipe_name = pipe_name(cache_dir)?;
+    let mut pipe = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&pipe_name)
+        .with_context(|| format!("No recording daemon is listening on {}", pipe_name))?;
+
+    writeln!(pipe, "{}", request).context("Failed to send control request")?;
+    let mut response = String::new();
+    pipe.read_to_string(&mut response)
+        .context("Failed to read control response")?;
+    Ok(response)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn exchange(_cache_dir: &Path, _request: &str) -> Result<String> {
+    anyhow::bail!("Daemon mode is not available on this platform yet")
+}
+
+/// Named pipe of the daemon recording into `cache_dir`
+///
+/// Pipes live in a flat namespace, so the name carries a hash (FNV-1a) of the
+/// absolute, case-folded cache directory.
+#[cfg(windows)]
+fn pipe_name(cache_dir: &Path) -> Result<String> {
+    let absolute = std::path::absolute(cache_dir).context("Failed to resolve cache directory")?;
+    let key = absolute.to_string_lossy().to_lowercase();
+
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    Ok(format!(r"\\.\pipe\compiler_monitor-{:016x}", hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempTree;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn monitor(cache: &TempTree) -> CompilerMonitor {
+        CompilerMonitor::new(&["gcc".to_string()], cache.path().to_path_buf()).unwrap()
+    }
+
+    /// Answer `request` and parse the JSON written back
+    fn ask(monitor: &CompilerMonitor, flush: &FlushFn, request: &str) -> serde_json::Value {
+        let mut response = Vec::new();
+        answer(request.as_bytes(), &mut response, monitor, flush);
+        serde_json::from_slice(&response).unwrap()
+    }
+
+    #[test]
+    fn status_pause_and_resume() {
+        let cache = TempTree::new("control_pause", &[]);
+        let monitor = monitor(&cache);
+        let flush: FlushFn = Box::new(|| Ok(()));
+
+        let status = ask(&monitor, &flush, "status\n");
+        assert_eq!((status["ok"].as_bool(), status["message"].as_str()), (Some(true), Some("OK")));
+        assert_eq!(status["status"]["patterns"], serde_json::json!(["gcc"]));
+        assert_eq!(status["status"]["command_counter"], 0);
+
+        let paused = ask(&monitor, &flush, "pause\n");
+        assert_eq!(paused["message"], "Paused");
+        assert!(monitor.is_paused());
+
+        let resumed = ask(&monitor, &flush, "resume");
+        assert_eq!(resumed["message"], "Resumed");
+        assert!(!monitor.is_paused());
+    }
+
+    #[test]
+    fn flush_reports_the_collect_result() {
+        let cache = TempTree::new("control_flush", &[]);
+        let monitor = monitor(&cache);
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&calls);
+        let flush: FlushFn = Box::new(move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        let response = ask(&monitor, &flush, "flush\n");
+        assert_eq!((response["ok"].as_bool(), response["message"].as_str()), (Some(true), Some("Collected")));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let failing: FlushFn = Box::new(|| anyhow::bail!("disk full"));
+        let response = ask(&monitor, &failing, "flush\n");
+        assert_eq!(response["ok"], false);
+        assert_eq!(response["message"], "Failed to collect: disk full");
+    }
+
+    #[test]
+    fn unknown_requests_fail() {
+        let cache = TempTree::new("control_unknown", &[]);
+        let monitor = monitor(&cache);
+        let flush: FlushFn = Box::new(|| Ok(()));
+
+        let response = ask(&monitor, &flush, "reboot\n");
+        assert_eq!(response["ok"], false);
+        assert_eq!(response["message"], "Unknown request 'reboot'");
+        assert!(response["status"]["state"].is_string());
+    }
+
+    #[test]
+    fn stop_requests_shutdown() {
+        let cache = TempTree::new("control_stop", &[]);
+        let monitor = monitor(&cache);
+        let flush: FlushFn = Box::new(|| Ok(()));
+
+        // The stop flag is process-wide, so no other test may depend on it staying unset
+        let response = ask(&monitor, &flush, "stop\n");
+        assert_eq!((response["ok"].as_bool(), response["message"].as_str()), (Some(true), Some("Stopping")));
+        assert_eq!(response["status"]["state"], "stopping");
+        assert!(shutdown::stop_requested());
+    }
+
+    /// A client that sends nothing until the read timeout expires
+    struct Silent;
+
+    impl Read for Silent {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    #[test]
+    fn timed_out_requests_get_no_answer() {
+        let cache = TempTree::new("control_silent", &[]);
+        let monitor = monitor(&cache);
+        let flush: FlushFn = Box::new(|| Ok(()));
+
+        let mut response = Vec::new();
+        answer(Silent, &mut response, &monitor, &flush);
+        assert!(response.is_empty());
+    }
+}
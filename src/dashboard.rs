@@ -0,0 +1,520 @@
+// Interactive dashboard for `record --tui`
+//
+// Replaces the scrolling console output of a long recording with a live view:
+// compiles counted by compiler and by directory, the compiles currently running
+// with their elapsed time, recent warnings, and the size of the cache. It is fed by
+// the monitor's events (see events.rs); the console output it replaces is written
+// to `record.log` in the cache directory instead.
+//
+// Keys: `p` pauses and resumes capture, `/` edits a filter applied to every list,
+// `q` (or Ctrl+C) stops recording gracefully.
+//
+// Built with the `tui` feature.
+
+use crate::console::{self, Redirect};
+use crate::events::{Event, EventListener};
+use crate::monitor::CompilerMonitor;
+use crate::shutdown;
+use anyhow::{Context, Result};
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self as terminal_event, Event as TerminalEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::Stylize;
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, Paragraph, Row, Table};
+use ratatui::{Frame, Terminal};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often the screen is redrawn and keys are read
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often the cache directory is measured
+const CACHE_SCAN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Warnings kept for display
+const MAX_WARNINGS: usize = 100;
+
+/// File in the cache directory receiving the console output while the dashboard runs
+const LOG_NAME: &str = "record.log";
+
+/// A recorded compile that has not exited yet
+struct RunningCompile {
+    name: String,
+    directory: String,
+    /// First source file written for it
+    file: Option<String>,
+    started: Instant,
+}
+
+/// What the dashboard shows, built up from the monitor's events
+#[derive(Default)]
+struct Activity {
+    compiles: u64,
+    by_compiler: HashMap<String, u64>,
+    by_directory: HashMap<String, u64>,
+    running: HashMap<u32, RunningCompile>,
+    /// Most recent last
+    warnings: VecDeque<String>,
+}
+
+impl Activity {
+    fn warn(&mut self, warning: String) {
+        if self.warnings.len() == MAX_WARNINGS {
+            self.warnings.pop_front();
+        }
+        self.warnings.push_back(warning);
+    }
+}
+
+/// Receives the monitor's events for the dashboard
+#[derive(Default)]
+struct ActivityListener {
+    activity: Mutex<Activity>,
+}
+
+impl EventListener for ActivityListener {
+    fn on_event(&self, event: &Event) {
+        let mut activity = self.activity.lock().unwrap();
+
+        match event {
+            Event::ProcessMatched { pid, name, directory, .. } => {
+                activity.compiles += 1;
+                *activity.by_compiler.entry(name.to_string()).or_default() += 1;
+                *activity.by_directory.entry(directory.to_string()).or_default() += 1;
+                activity.running.insert(
+                    *pid,
+                    RunningCompile {
+                        name: name.to_string(),
+                        directory: directory.to_string(),
+                        file: None,
+                        started: Instant::now(),
+                    },
+                );
+            }
+            Event::EntryWritten { pid, file, .. } => {
+                if let Some(running) = activity.running.get_mut(pid) {
+                    running.file.get_or_insert_with(|| file.to_string());
+                }
+            }
+            Event::CompileExited { pid, .. } => {
+                activity.running.remove(pid);
+            }
+            Event::ResponseFileMissing { pid, path, error } => {
+                activity.warn(format!("pid {}: Could not read response file {}: {}", pid, path, error));
+            }
+            Event::Warning { pid, message } => match pid {
+                Some(pid) => activity.warn(format!("pid {}: {}", pid, message)),
+                None => activity.warn(message.to_string()),
+            },
+            Event::ResponseFileInlined { .. } | Event::SessionStopped { .. } => {}
+        }
+    }
+}
+
+/// A dashboard to be shown for a monitor, which has to report to `listener()`
+pub struct Dashboard {
+    listener: Arc<ActivityListener>,
+    log_path: PathBuf,
+}
+
+impl Dashboard {
+    pub fn new(cache_dir: &Path) -> Self {
+        Self {
+            listener: Arc::default(),
+            log_path: cache_dir.join(LOG_NAME),
+        }
+    }
+
+    /// The listener to register with `CompilerMonitor::with_event_listener`
+    pub fn listener(&self) -> Arc<dyn EventListener> {
+        self.listener.clone()
+    }
+
+    /// Take over the terminal and draw until `RunningDashboard::finish`
+    pub fn start(self, monitor: Arc<CompilerMonitor>) -> Result<RunningDashboard> {
+        let stdout = console::take_stdout(Redirect::File(self.log_path))
+            .context("Failed to redirect console output for the dashboard")?;
+        let mut screen = stdout.try_clone().context("Failed to open the terminal")?;
+
+        enable_raw_mode().context("Failed to switch the terminal to raw mode")?;
+        execute!(screen, EnterAlternateScreen).context("Failed to switch to the alternate screen")?;
+        let terminal = Terminal::new(CrosstermBackend::new(screen)).context("Failed to set up the terminal")?;
+
+        let done = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let done = Arc::clone(&done);
+            let listener = self.listener;
+            std::thread::spawn(move || run_dashboard(terminal, &monitor, &listener, &done))
+        };
+
+        Ok(RunningDashboard { done, thread, stdout })
+    }
+}
+
+/// A dashboard on screen
+pub struct RunningDashboard {
+    done: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+    /// The original stdout, given back when the dashboard closes
+    stdout: File,
+}
+
+impl RunningDashboard {
+    /// Close the dashboard and give the terminal back to the console output
+    pub fn finish(self) -> Result<()> {
+        self.done.store(true, Ordering::SeqCst);
+        let _ = self.thread.join();
+        console::restore_stdout(&self.stdout)
+    }
+}
+
+/// Keyboard state of the dashboard
+#[derive(Default)]
+struct View {
+    filter: String,
+    editing_filter: bool,
+    cache_files: usize,
+    cache_bytes: u64,
+}
+
+impl View {
+    fn matches(&self, text: &str) -> bool {
+        self.filter.is_empty() || text.to_lowercase().contains(&self.filter.to_lowercase())
+    }
+}
+
+fn run_dashboard(
+    mut terminal: Terminal<CrosstermBackend<File>>,
+    monitor: &CompilerMonitor,
+    listener: &ActivityListener,
+    done: &AtomicBool,
+) {
+    let mut view = View::default();
+    let mut last_scan: Option<Instant> = None;
+
+    while !done.load(Ordering::SeqCst) {
+        if last_scan.is_none_or(|scan| scan.elapsed() >= CACHE_SCAN_INTERVAL) {
+            (view.cache_files, view.cache_bytes) = measure_cache(monitor.cache_dir());
+            last_scan = Some(Instant::now());
+        }
+
+        {
+            let mut activity = listener.activity.lock().unwrap();
+            // Backends that do not report exits leave compiles to be pruned here
+            activity.running.retain(|&pid, _| is_running(pid));
+            let _ = terminal.draw(|frame| draw(frame, monitor, &activity, &view));
+        }
+
+        match terminal_event::poll(REFRESH_INTERVAL) {
+            Ok(true) => {}
+            _ => continue,
+        }
+        if let Ok(TerminalEvent::Key(key)) = terminal_event::read() {
+            if key.kind == KeyEventKind::Press {
+                handle_key(key, monitor, &mut view);
+            }
+        }
+    }
+
+    let _ = disable_raw_mode();
+    let _ = execute!(terminal.backend_mut(), LeaveAlternateScreen);
+    let _ = terminal.show_cursor();
+}
+
+fn handle_key(key: KeyEvent, monitor: &CompilerMonitor, view: &mut View) {
+    // Raw mode turns Ctrl+C into a key instead of a signal
+    if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+        shutdown::request_stop();
+        return;
+    }
+
+    if view.editing_filter {
+        match key.code {
+            KeyCode::Enter => view.editing_filter = false,
+            KeyCode::Esc => {
+                view.filter.clear();
+                view.editing_filter = false;
+            }
+            KeyCode::Backspace => {
+                view.filter.pop();
+            }
+            KeyCode::Char(c) => view.filter.push(c),
+            _ => {}
+        }
+        return;
+    }
+
+    match key.code {
+        KeyCode::Char('q') => shutdown::request_stop(),
+        KeyCode::Char('p') => monitor.set_paused(!monitor.is_paused()),
+        KeyCode::Char('/') => view.editing_filter = true,
+        KeyCode::Esc => view.filter.clear(),
+        _ => {}
+    }
+}
+
+fn draw(frame: &mut Frame, monitor: &CompilerMonitor, activity: &Activity, view: &View) {
+    let [header, counts, running, warnings, footer] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Percentage(40),
+        Constraint::Percentage(35),
+        Constraint::Min(4),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let state = if shutdown::stop_requested() {
+        "stopping".yellow()
+    } else if monitor.is_paused() {
+        "PAUSED".red().bold()
+    } else {
+        "recording".green()
+    };
+    let summary = Line::from(format!(
+        " Compiles: {}   Running: {}   Warnings: {}   Cache: {} files, {}",
+        activity.compiles,
+        activity.running.len(),
+        activity.warnings.len(),
+        view.cache_files,
+        format_size(view.cache_bytes),
+    ));
+    frame.render_widget(
+        Paragraph::new(summary).block(Block::bordered().title(Line::from(vec![" Compiler Monitor ─ ".into(), state, " ".into()]))),
+        header,
+    );
+
+    let [by_compiler, by_directory] =
+        Layout::horizontal([Constraint::Percentage(35), Constraint::Percentage(65)]).areas(counts);
+    frame.render_widget(count_table(" By compiler ", &activity.by_compiler, view), by_compiler);
+    frame.render_widget(count_table(" By directory ", &activity.by_directory, view), by_directory);
+
+    let mut compiles: Vec<(&u32, &RunningCompile)> = activity
+        .running
+        .iter()
+        .filter(|(_, compile)| {
+            view.matches(&compile.name)
+                || view.matches(&compile.directory)
+                || compile.file.as_deref().is_some_and(|file| view.matches(file))
+        })
+        .collect();
+    compiles.sort_by_key(|(_, compile)| compile.started);
+    let rows = compiles.into_iter().map(|(pid, compile)| {
+        Row::new(vec![
+            pid.to_string(),
+            compile.name.clone(),
+            format!("{:.1}s", compile.started.elapsed().as_secs_f64()),
+            compile.file.clone().unwrap_or_else(|| compile.directory.clone()),
+        ])
+    });
+    let widths = [Constraint::Length(8), Constraint::Length(16), Constraint::Length(9), Constraint::Fill(1)];
+    frame.render_widget(
+        Table::new(rows, widths)
+            .header(Row::new(vec!["pid", "compiler", "elapsed", "file"]).bold())
+            .block(Block::bordered().title(" Running ")),
+        running,
+    );
+
+    // Newest first, as many as fit
+    let recent: Vec<&str> = activity
+        .warnings
+        .iter()
+        .rev()
+        .map(String::as_str)
+        .filter(|warning| view.matches(warning))
+        .collect();
+    frame.render_widget(List::new(recent).block(Block::bordered().title(" Warnings ")), warnings);
+
+    let keys = if view.editing_filter {
+        format!(" Filter: {}▏  Enter apply  Esc clear", view.filter)
+    } else if view.filter.is_empty() {
+        " p pause/resume   / filter   q quit".to_string()
+    } else {
+        format!(" p pause/resume   / filter ({})   Esc clear filter   q quit", view.filter)
+    };
+    frame.render_widget(Paragraph::new(keys).reversed(), footer);
+}
+
+/// Counts sorted by count, most first
+fn count_table<'a>(title: &'a str, counts: &HashMap<String, u64>, view: &View) -> Table<'a> {
+    let mut entries: Vec<(&String, &u64)> = counts.iter().filter(|(key, _)| view.matches(key)).collect();
+    entries.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+
+    let rows = entries
+        .into_iter()
+        .map(|(key, count)| Row::new(vec![count.to_string(), key.clone()]));
+    Table::new(rows, [Constraint::Length(7), Constraint::Fill(1)]).block(Block::bordered().title(title))
+}
+
+/// Number of files in the cache and their total size
+fn measure_cache(cache_dir: &Path) -> (usize, u64) {
+    let Ok(entries) = fs::read_dir(cache_dir) else {
+        return (0, 0);
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .fold((0, 0), |(files, bytes), metadata| (files + 1, bytes + metadata.len()))
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Whether a process still exists; backends without exit reports rely on this
+#[cfg(target_os = "linux")]
+fn is_running(pid: u32) -> bool {
+    Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+/// Whether a process still exists; the Windows backend reports exits itself
+#[cfg(not(target_os = "linux"))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempTree;
+
+    fn matched<'a>(pid: u32, name: &'a str, directory: &'a str) -> Event<'a> {
+        Event::ProcessMatched {
+            pid,
+            name,
+            command: "",
+            directory,
+            directory_origin: None,
+            launcher: None,
+            driver: None,
+            target: None,
+        }
+    }
+
+    fn written(pid: u32, file: &str) -> Event<'_> {
+        Event::EntryWritten {
+            pid,
+            file,
+            directory: "/src",
+            cache_file: String::new(),
+            probe: None,
+        }
+    }
+
+    #[test]
+    fn compiles_are_counted_and_tracked_until_they_exit() {
+        let listener = ActivityListener::default();
+        listener.on_event(&matched(1, "gcc", "/src/a"));
+        listener.on_event(&matched(2, "gcc", "/src/b"));
+        listener.on_event(&matched(3, "clang", "/src/a"));
+        listener.on_event(&written(1, "/src/a/x.c"));
+        listener.on_event(&written(1, "/src/a/y.c"));
+        listener.on_event(&Event::CompileExited { pid: 2, exit_code: 0, duration_ms: 5 });
+
+        let activity = listener.activity.lock().unwrap();
+        assert_eq!(activity.compiles, 3);
+        assert_eq!(activity.by_compiler, HashMap::from([("gcc".to_string(), 2), ("clang".to_string(), 1)]));
+        assert_eq!(activity.by_directory, HashMap::from([("/src/a".to_string(), 2), ("/src/b".to_string(), 1)]));
+
+        let mut running: Vec<&u32> = activity.running.keys().collect();
+        running.sort();
+        assert_eq!(running, [&1, &3]);
+        assert_eq!(activity.running[&1].file.as_deref(), Some("/src/a/x.c"));
+        assert_eq!(activity.running[&3].file, None);
+    }
+
+    #[test]
+    fn only_the_latest_warnings_are_kept() {
+        let listener = ActivityListener::default();
+        listener.on_event(&Event::ResponseFileMissing {
+            pid: 9,
+            path: "/tmp/a.rsp".to_string(),
+            error: "gone".to_string(),
+        });
+        for n in 0..MAX_WARNINGS {
+            let message = format!("warning {}", n);
+            listener.on_event(&Event::Warning { pid: (n == 0).then_some(7), message: &message });
+        }
+
+        let activity = listener.activity.lock().unwrap();
+        assert_eq!(activity.warnings.len(), MAX_WARNINGS);
+        assert_eq!(activity.warnings.front().map(String::as_str), Some("pid 7: warning 0"));
+        assert_eq!(activity.warnings.back().map(String::as_str), Some("warning 99"));
+    }
+
+    fn press(code: KeyCode, monitor: &CompilerMonitor, view: &mut View) {
+        handle_key(KeyEvent::new(code, KeyModifiers::NONE), monitor, view);
+    }
+
+    #[test]
+    fn filter_editing() {
+        let cache = TempTree::new("dashboard_filter", &[]);
+        let monitor = CompilerMonitor::new(&["gcc".to_string()], cache.path().to_path_buf()).unwrap();
+        let mut view = View::default();
+        assert!(view.matches("anything"));
+
+        press(KeyCode::Char('/'), &monitor, &mut view);
+        assert!(view.editing_filter);
+        for c in "Srcx".chars() {
+            press(KeyCode::Char(c), &monitor, &mut view);
+        }
+        press(KeyCode::Backspace, &monitor, &mut view);
+        // Keys typed into the filter are not commands
+        press(KeyCode::Char('p'), &monitor, &mut view);
+        press(KeyCode::Backspace, &monitor, &mut view);
+        press(KeyCode::Enter, &monitor, &mut view);
+        assert!(!view.editing_filter);
+        assert!(!monitor.is_paused());
+        assert_eq!(view.filter, "Src");
+        assert!(view.matches("/home/SRC/lib"));
+        assert!(!view.matches("/home/build"));
+
+        press(KeyCode::Esc, &monitor, &mut view);
+        assert_eq!(view.filter, "");
+
+        press(KeyCode::Char('/'), &monitor, &mut view);
+        press(KeyCode::Char('x'), &monitor, &mut view);
+        press(KeyCode::Esc, &monitor, &mut view);
+        assert!(!view.editing_filter);
+        assert_eq!(view.filter, "");
+    }
+
+    #[test]
+    fn p_toggles_pause() {
+        let cache = TempTree::new("dashboard_pause", &[]);
+        let monitor = CompilerMonitor::new(&["gcc".to_string()], cache.path().to_path_buf()).unwrap();
+        let mut view = View::default();
+
+        press(KeyCode::Char('p'), &monitor, &mut view);
+        assert!(monitor.is_paused());
+        press(KeyCode::Char('p'), &monitor, &mut view);
+        assert!(!monitor.is_paused());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024 * 1024 * 1024), "5120.0 GiB");
+    }
+}
@@ -0,0 +1,252 @@
+// Multiplexed compiler drivers
+//
+// Some toolchains do not run a compiler executable under its own name:
+//
+// - `zig cc` / `zig c++` (also `python -m ziglang cc` from the ziglang wheel)
+// - Emscripten's `emcc` / `em++`, Python scripts whose process is `python3`
+// - `xcrun clang`, which looks the tool up in the active Xcode toolchain
+//
+// Pattern matching on the process name cannot find these. Each driver profile
+// recognizes its invocation from argv and rewrites it into the clang-compatible
+// command it amounts to, so patterns are matched against `clang`/`clang++` and the
+// recorded command is one clangd understands. Source extraction then works as for
+// any other compile.
+
+use crate::names::program_stem;
+use std::path::Path;
+
+/// Python interpreter options that take a separate value
+const PYTHON_VALUE_OPTIONS: &[&str] = &["-W", "-X", "-Q"];
+
+/// xcrun options that take a separate value
+const XCRUN_VALUE_OPTIONS: &[&str] = &["-sdk", "--sdk", "-toolchain", "--toolchain"];
+
+/// A driver invocation rewritten into a plain compiler command
+pub struct Rewritten {
+    /// Compiler argv, clang-compatible
+    pub argv: Vec<String>,
+    /// How the compiler was driven, e.g. "zig cc" or "emcc"
+    pub driver: String,
+}
+
+/// Whether a process with this name may be a driver invocation
+pub fn is_driver(process_name: &str) -> bool {
+    let stem = stem(process_name);
+    matches!(stem.as_str(), "zig" | "emcc" | "em++" | "xcrun") || is_python(&stem)
+}
+
+/// Rewrite a driver invocation; `None` if argv is not one
+pub fn rewrite_driver(argv: &[String]) -> Option<Rewritten> {
+    let program = stem(argv.first()?);
+
+    match program.as_str() {
+        "zig" => rewrite_zig(argv, "zig"),
+        "emcc" | "em++" => rewrite_emcc(argv),
+        "xcrun" => rewrite_xcrun(argv),
+        _ if is_python(&program) => rewrite_python(argv),
+        _ => None,
+    }
+}
+
+/// `zig cc ...` -> `clang ...`, `zig c++ ...` -> `clang++ ...`
+fn rewrite_zig(argv: &[String], driver: &str) -> Option<Rewritten> {
+    let compiler = match argv.get(1)?.as_str() {
+        "cc" => "clang",
+        "c++" => "clang++",
+        _ => return None,
+    };
+
+    let mut rewritten = vec![compiler.to_string()];
+    rewritten.extend_from_slice(&argv[2..]);
+    Some(Rewritten {
+        argv: rewritten,
+        driver: format!("{} {}", driver, argv[1]),
+    })
+}
+
+/// `emcc ...` -> `clang --target=wasm32-unknown-emscripten --sysroot=<emscripten>/cache/sysroot ...`
+fn rewrite_emcc(argv: &[String]) -> Option<Rewritten> {
+    let script = &argv[0];
+    let name = stem(script);
+    let compiler = if name == "em++" { "clang++" } else { "clang" };
+
+    let mut rewritten = vec![
+        compiler.to_string(),
+        "--target=wasm32-unknown-emscripten".to_string(),
+    ];
+
+    // The sysroot lives next to emcc.py; only known when the script was run by path
+    if let Some(dir) = Path::new(script).parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        rewritten.push(format!("--sysroot={}", dir.join("cache").join("sysroot").display()));
+    }
+
+    rewritten.extend_from_slice(&argv[1..]);
+    Some(Rewritten { argv: rewritten, driver: name })
+}
+
+/// `xcrun [options] clang ...` -> `clang ...`
+fn rewrite_xcrun(argv: &[String]) -> Option<Rewritten> {
+    let mut index = 1;
+
+    while let Some(arg) = argv.get(index) {
+        if XCRUN_VALUE_OPTIONS.contains(&arg.as_str()) {
+            index += 2;
+        } else if arg.starts_with('-') {
+            index += 1;
+        } else {
+            break;
+        }
+    }
+
+    if index >= argv.len() {
+        return None;
+    }
+
+    Some(Rewritten {
+        argv: argv[index..].to_vec(),
+        driver: "xcrun".to_string(),
+    })
+}
+
+/// `python [options] emcc.py ...` and `python [options] -m ziglang ...`
+fn rewrite_python(argv: &[String]) -> Option<Rewritten> {
+    let mut index = 1;
+
+    while let Some(arg) = argv.get(index) {
+        if arg == "-m" {
+            let module = argv.get(index + 1)?;
+            let rest = &argv[index + 2..];
+            return rewrite_python_module(module, rest);
+        }
+        if arg == "-c" {
+            return None;
+        }
+        if PYTHON_VALUE_OPTIONS.contains(&arg.as_str()) {
+            index += 2;
+        } else if arg.starts_with('-') {
+            index += 1;
+        } else {
+            break;
+        }
+    }
+
+    // A script: only Emscripten's drivers are known
+    let script = argv.get(index)?;
+    match stem(script).as_str() {
+        "emcc" | "em++" => rewrite_emcc(&argv[index..]),
+        _ => None,
+    }
+}
+
+/// `python -m ziglang cc ...`; other modules are not compilers, whatever their
+/// arguments look like
+fn rewrite_python_module(module: &str, rest: &[String]) -> Option<Rewritten> {
+    match module {
+        "ziglang" => {
+            let mut argv = vec![module.to_string()];
+            argv.extend_from_slice(rest);
+            rewrite_zig(&argv, "python -m ziglang")
+        }
+        _ => None,
+    }
+}
+
+fn is_python(stem: &str) -> bool {
+    stem.strip_prefix("python")
+        .is_some_and(|version| version.chars().all(|c| c.is_ascii_digit() || c == '.'))
+}
+
+/// `program_stem`, also without the `.py` of a Python script
+fn stem(path: &str) -> String {
+    let stem = program_stem(path);
+    stem.strip_suffix(".py").map(str::to_string).unwrap_or(stem)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::argv;
+
+    fn rewrite(args: &[&str]) -> Option<(Vec<String>, String)> {
+        rewrite_driver(&argv(args)).map(|rewritten| (rewritten.argv, rewritten.driver))
+    }
+
+    #[test]
+    fn zig_cc_and_cxx() {
+        assert_eq!(
+            rewrite(&["/opt/zig/zig", "cc", "-c", "a.c"]),
+            Some((argv(&["clang", "-c", "a.c"]), "zig cc".to_string()))
+        );
+        assert_eq!(
+            rewrite(&["zig.exe", "c++", "-c", "a.cpp"]),
+            Some((argv(&["clang++", "-c", "a.cpp"]), "zig c++".to_string()))
+        );
+        assert_eq!(rewrite(&["zig", "build"]), None);
+        assert_eq!(rewrite(&["zig"]), None);
+    }
+
+    #[test]
+    fn emcc_gets_the_emscripten_target_and_sysroot() {
+        assert_eq!(
+            rewrite(&["/emsdk/upstream/emscripten/em++", "-c", "a.cpp"]),
+            Some((
+                argv(&[
+                    "clang++",
+                    "--target=wasm32-unknown-emscripten",
+                    "--sysroot=/emsdk/upstream/emscripten/cache/sysroot",
+                    "-c",
+                    "a.cpp",
+                ]),
+                "em++".to_string()
+            ))
+        );
+        // Found on PATH, the sysroot is unknown
+        assert_eq!(
+            rewrite(&["emcc", "-c", "a.c"]),
+            Some((argv(&["clang", "--target=wasm32-unknown-emscripten", "-c", "a.c"]), "emcc".to_string()))
+        );
+    }
+
+    #[test]
+    fn emcc_run_by_python() {
+        assert_eq!(
+            rewrite(&["/usr/bin/python3", "-E", "/emsdk/emcc.py", "-c", "a.c"]),
+            Some((
+                argv(&["clang", "--target=wasm32-unknown-emscripten", "--sysroot=/emsdk/cache/sysroot", "-c", "a.c"]),
+                "emcc".to_string()
+            ))
+        );
+        assert_eq!(rewrite(&["python3", "-W", "ignore", "setup.py", "build"]), None);
+        assert_eq!(rewrite(&["python3", "-c", "import emcc", "emcc.py"]), None);
+    }
+
+    #[test]
+    fn python_modules_other_than_ziglang_are_not_compilers() {
+        assert_eq!(
+            rewrite(&["python3.12", "-m", "ziglang", "c++", "-c", "a.cpp"]),
+            Some((argv(&["clang++", "-c", "a.cpp"]), "python -m ziglang c++".to_string()))
+        );
+        assert_eq!(rewrite(&["python3", "-m", "pip", "cc", "-c", "a.c"]), None);
+        assert_eq!(rewrite(&["python3", "-m"]), None);
+    }
+
+    #[test]
+    fn xcrun_skips_its_options() {
+        assert_eq!(
+            rewrite(&["xcrun", "-sdk", "iphoneos", "--verbose", "clang", "-c", "a.m"]),
+            Some((argv(&["clang", "-c", "a.m"]), "xcrun".to_string()))
+        );
+        assert_eq!(rewrite(&["xcrun", "--show-sdk-path"]), None);
+    }
+
+    #[test]
+    fn driver_names() {
+        for name in ["zig", "emcc", "em++.py", "xcrun", "python", "python3", "python3.12.exe"] {
+            assert!(is_driver(name), "{} should be a driver", name);
+        }
+        for name in ["gcc", "pythonw", "python-config", "zig-cc"] {
+            assert!(!is_driver(name), "{} should not be a driver", name);
+        }
+        assert!(rewrite(&["gcc", "-c", "a.c"]).is_none());
+    }
+}
@@ -0,0 +1,341 @@
+// Compiler options taken from the environment
+//
+// Compilers read part of their configuration from environment variables, which
+// never show up in the recorded command line:
+//
+// - cl.exe (and clang-cl) insert the options in `CL` before the command line
+//   arguments and append those in `_CL_` after them. `#` stands for `=` there,
+//   since `set` cannot put an `=` into a variable.
+// - gcc and clang search `CPATH` as if given with `-I`, and `C_INCLUDE_PATH` or
+//   `CPLUS_INCLUDE_PATH` (by language) as if given with `-isystem`, after the
+//   directories on the command line. An empty list element is the working directory.
+//
+// Backends capture the environment of each process where they can; the variables
+// are turned into explicit arguments so the recorded command means the same thing
+// without the environment. Only these variables are kept in the cache, since the
+// rest of a build's environment may hold tokens and other secrets; `record
+// --full-environment` keeps everything.
+//
+// `INCLUDE` and `EXTERNAL_INCLUDE`, set by the Visual Studio developer prompt, name
+// the CRT and Windows SDK headers. They are only turned into `/imsvc` (`-isystem`
+// for GNU-style drivers) arguments by `collect --env-includes`, as clangd usually
+// finds these headers itself when it runs on the same machine.
+
+use crate::names::{is_cl_style, program_stem};
+use std::collections::BTreeMap;
+
+/// Separator of directory lists in environment variables
+const PATH_LIST_SEPARATOR: char = if cfg!(windows) { ';' } else { ':' };
+
+/// Variables listing MSVC system include directories, in search order
+const MSVC_INCLUDE_VARIABLES: &[&str] = &["INCLUDE", "EXTERNAL_INCLUDE"];
+
+/// Every variable this module reads, the part of the environment worth recording
+const COMPILER_VARIABLES: &[&str] = &[
+    "CL",
+    "_CL_",
+    "CPATH",
+    "C_INCLUDE_PATH",
+    "CPLUS_INCLUDE_PATH",
+    "INCLUDE",
+    "EXTERNAL_INCLUDE",
+];
+
+/// Extensions of C++ sources, deciding between C_INCLUDE_PATH and CPLUS_INCLUDE_PATH
+const CPP_EXTENSIONS: &[&str] = &[".cpp", ".cc", ".cxx", ".c++"];
+
+/// A compiler argv with the environment's options made explicit
+pub struct Expanded {
+    pub argv: Vec<String>,
+    /// Names of the variables that contributed arguments
+    pub variables: Vec<String>,
+}
+
+/// Turn the compiler options in `environment` into arguments; `None` if there are none
+pub fn expand_environment(argv: &[String], environment: &BTreeMap<String, String>) -> Option<Expanded> {
+    let compiler = program_stem(argv.first()?);
+    let mut variables = Vec::new();
+
+    let expanded = if is_cl_style(&compiler) {
+        let prepend = lookup(environment, "CL").map(split_cl_options).unwrap_or_default();
+        let append = lookup(environment, "_CL_").map(split_cl_options).unwrap_or_default();
+        if !prepend.is_empty() {
+            variables.push("CL".to_string());
+        }
+        if !append.is_empty() {
+            variables.push("_CL_".to_string());
+        }
+
+        let mut expanded = vec![argv[0].clone()];
+        expanded.extend(prepend);
+        expanded.extend_from_slice(&argv[1..]);
+        expanded.extend(append);
+        expanded
+    } else {
+        let language_variable = if is_cpp(&compiler, argv) {
+            "CPLUS_INCLUDE_PATH"
+        } else {
+            "C_INCLUDE_PATH"
+        };
+
+        let mut expanded = argv.to_vec();
+        for (variable, option) in [("CPATH", "-I"), (language_variable, "-isystem")] {
+            let Some(directories) = lookup(environment, variable) else {
+                continue;
+            };
+            variables.push(variable.to_string());
+            for directory in directories.split(PATH_LIST_SEPARATOR) {
+                expanded.push(option.to_string());
+                expanded.push(if directory.is_empty() { "." } else { directory }.to_string());
+            }
+        }
+        expanded
+    };
+
+    if variables.is_empty() {
+        return None;
+    }
+    Some(Expanded { argv: expanded, variables })
+}
+
+/// Add the directories in `INCLUDE`/`EXTERNAL_INCLUDE` as system include arguments;
+/// `None` if neither is set
+pub fn add_msvc_system_includes(argv: &[String], environment: &BTreeMap<String, String>) -> Option<Vec<String>> {
+    let cl_style = is_cl_style(argv.first()?);
+
+    let mut directories: Vec<&str> = Vec::new();
+    for variable in MSVC_INCLUDE_VARIABLES {
+        let Some(value) = lookup(environment, variable) else {
+            continue;
+        };
+        for directory in value.split(';').filter(|directory| !directory.is_empty()) {
+            if !directories.iter().any(|known| known.eq_ignore_ascii_case(directory)) {
+                directories.push(directory);
+            }
+        }
+    }
+    if directories.is_empty() {
+        return None;
+    }
+
+    let includes = directories.into_iter().flat_map(|directory| {
+        let option = if cl_style { "/imsvc" } else { "-isystem" };
+        [option.to_string(), directory.to_string()]
+    });
+
+    // Everything after cl's /link goes to the linker
+    let is_link = |arg: &String| arg.eq_ignore_ascii_case("/link") || arg.eq_ignore_ascii_case("-link");
+    let link = match cl_style {
+        true => argv.iter().position(is_link).unwrap_or(argv.len()),
+        false => argv.len(),
+    };
+
+    let mut expanded = argv[..link].to_vec();
+    expanded.extend(includes);
+    expanded.extend_from_slice(&argv[link..]);
+    Some(expanded)
+}
+
+/// The variables of `environment` that affect compiles
+pub fn compiler_variables(environment: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+    environment
+        .iter()
+        .filter(|(key, _)| {
+            COMPILER_VARIABLES.iter().any(|name| {
+                if cfg!(windows) {
+                    key.eq_ignore_ascii_case(name)
+                } else {
+                    key == name
+                }
+            })
+        })
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// A variable's value, ignoring unset and empty variables; names are case-insensitive on Windows
+fn lookup<'a>(environment: &'a BTreeMap<String, String>, name: &str) -> Option<&'a str> {
+    let value = if cfg!(windows) {
+        environment
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    } else {
+        environment.get(name)
+    };
+    value.map(String::as_str).filter(|value| !value.is_empty())
+}
+
+/// Split `CL`/`_CL_` like a command line, with `#` standing for `=`
+fn split_cl_options(value: &str) -> Vec<String> {
+    let mut options = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut chars = value.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'"') => current.push(chars.next().unwrap()),
+            '"' => in_quotes = !in_quotes,
+            '#' => current.push('='),
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    options.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        options.push(current);
+    }
+
+    options
+}
+
+/// Whether a gcc-style invocation compiles C++
+fn is_cpp(compiler: &str, argv: &[String]) -> bool {
+    if compiler.ends_with("++") {
+        return true;
+    }
+
+    let mut args = argv.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let language = match arg.as_str() {
+            "-x" => args.next().map(String::as_str),
+            _ => arg.strip_prefix("-x"),
+        };
+        if let Some(language) = language {
+            if language.starts_with("c++") {
+                return true;
+            }
+            continue;
+        }
+        if !arg.starts_with('-') {
+            // `.C` is C++ too, but only by case
+            let lower = arg.to_lowercase();
+            if arg.ends_with(".C") || CPP_EXTENSIONS.iter().any(|ext| lower.ends_with(ext)) {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::argv;
+
+    fn environment(variables: &[(&str, &str)]) -> BTreeMap<String, String> {
+        variables.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn cl_options_go_before_and_after_the_arguments() {
+        let expanded = expand_environment(
+            &argv(&["cl.exe", "/c", "a.cpp"]),
+            &environment(&[("CL", r#"/DVERSION#2 /I"C:\My Includes""#), ("_CL_", "/link /DEBUG")]),
+        )
+        .unwrap();
+        assert_eq!(
+            expanded.argv,
+            argv(&["cl.exe", "/DVERSION=2", r"/IC:\My Includes", "/c", "a.cpp", "/link", "/DEBUG"])
+        );
+        assert_eq!(expanded.variables, ["CL", "_CL_"]);
+    }
+
+    #[test]
+    fn cl_options_keep_escaped_quotes() {
+        let expanded =
+            expand_environment(&argv(&["clang-cl", "a.c"]), &environment(&[("CL", r#"/DNAME=\"x\""#)])).unwrap();
+        assert_eq!(expanded.argv, argv(&["clang-cl", r#"/DNAME="x""#, "a.c"]));
+    }
+
+    #[test]
+    fn gcc_include_variables_become_include_arguments() {
+        let list = |dirs: &[&str]| dirs.join(&PATH_LIST_SEPARATOR.to_string());
+        let expanded = expand_environment(
+            &argv(&["gcc", "-c", "a.c"]),
+            &environment(&[
+                ("CPATH", &list(&["/opt/inc", ""])),
+                ("C_INCLUDE_PATH", "/opt/sys"),
+                ("CPLUS_INCLUDE_PATH", "/opt/sys++"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            expanded.argv,
+            argv(&["gcc", "-c", "a.c", "-I", "/opt/inc", "-I", ".", "-isystem", "/opt/sys"])
+        );
+        assert_eq!(expanded.variables, ["CPATH", "C_INCLUDE_PATH"]);
+    }
+
+    #[test]
+    fn cxx_compiles_use_cplus_include_path() {
+        let env = environment(&[("C_INCLUDE_PATH", "/opt/sys"), ("CPLUS_INCLUDE_PATH", "/opt/sys++")]);
+        let expanded_argv = |args: &[&str]| expand_environment(&argv(args), &env).unwrap().argv;
+
+        for args in [&["g++", "-c", "a.c"][..], &["gcc", "-c", "a.cc"], &["gcc", "-c", "A.C"], &["gcc", "-xc++", "a.h"]] {
+            assert_eq!(expanded_argv(args).last().map(String::as_str), Some("/opt/sys++"), "{:?}", args);
+        }
+        assert_eq!(expanded_argv(&["gcc", "-c", "a.c"]).last().map(String::as_str), Some("/opt/sys"));
+    }
+
+    #[test]
+    fn nothing_to_expand() {
+        let env = environment(&[("PATH", "/usr/bin"), ("CL", ""), ("CPATH", "/opt/inc")]);
+        assert!(expand_environment(&argv(&["cl.exe", "/c", "a.c"]), &env).is_none());
+        assert!(expand_environment(&argv(&["gcc", "-c", "a.c"]), &environment(&[("CL", "/DX")])).is_none());
+        assert!(expand_environment(&[], &env).is_none());
+    }
+
+    #[test]
+    fn only_compiler_variables_are_kept() {
+        let env = environment(&[
+            ("CPATH", "/opt/inc"),
+            ("INCLUDE", r"C:\VS\include"),
+            ("GITHUB_TOKEN", "secret"),
+            ("PATH", "/usr/bin"),
+        ]);
+        assert_eq!(
+            compiler_variables(&env),
+            environment(&[("CPATH", "/opt/inc"), ("INCLUDE", r"C:\VS\include")])
+        );
+    }
+
+    #[test]
+    fn msvc_includes_become_imsvc_before_link() {
+        let env = environment(&[
+            ("INCLUDE", r"C:\VS\include;C:\SDK\ucrt;"),
+            ("EXTERNAL_INCLUDE", r"c:\vs\INCLUDE;C:\SDK\um"),
+        ]);
+        assert_eq!(
+            add_msvc_system_includes(&argv(&["cl.exe", "/c", "a.cpp", "/link", "/DEBUG"]), &env).unwrap(),
+            argv(&[
+                "cl.exe",
+                "/c",
+                "a.cpp",
+                "/imsvc",
+                r"C:\VS\include",
+                "/imsvc",
+                r"C:\SDK\ucrt",
+                "/imsvc",
+                r"C:\SDK\um",
+                "/link",
+                "/DEBUG",
+            ])
+        );
+    }
+
+    #[test]
+    fn msvc_includes_for_gnu_style_drivers() {
+        let env = environment(&[("INCLUDE", r"C:\VS\include")]);
+        assert_eq!(
+            add_msvc_system_includes(&argv(&["clang++", "-c", "a.cpp"]), &env).unwrap(),
+            argv(&["clang++", "-c", "a.cpp", "-isystem", r"C:\VS\include"])
+        );
+        assert!(add_msvc_system_includes(&argv(&["cl.exe", "a.c"]), &environment(&[("CPATH", "/opt")])).is_none());
+    }
+}
@@ -0,0 +1,351 @@
+// Machine-readable capture events
+//
+// `record --events ndjson` reports what the monitor does as one JSON object per
+// line, for editor extensions and CI scripts that react to captures while the build
+// runs. Every object has an `event` name and `time_ms` (milliseconds since the Unix
+// epoch); the other fields of an event are always present, `null` when unknown.
+//
+//   process_matched         a compiler invocation is being recorded
+//   response_file_inlined   a response file was read and inlined
+//   response_file_missing   a response file could not be read and stays referenced
+//   entry_written           a command file was written to the cache
+//   compile_exited          a recorded compile exited; its entries now have its status
+//   warning                 anything the console marks with ⚠
+//   session_stopped         recording ended; carries the session counters
+//
+// Events go to stdout, in which case the console output moves to stderr, or to a
+// socket a consumer is listening on (`--events-to tcp:HOST:PORT` or `unix:PATH`).
+// In-process consumers like the TUI dashboard receive them as `EventListener`s.
+
+use crate::console::{self, Redirect};
+use crate::monitor::SessionStats;
+use crate::workdir::DirectoryOrigin;
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Formats of the event stream
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventFormat {
+    /// One JSON object per line
+    Ndjson,
+}
+
+/// `record` options for the event stream
+#[derive(Args, Debug)]
+pub struct EventArgs {
+    /// Emit capture events in this format, for editor extensions and scripts
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    events: Option<EventFormat>,
+
+    /// Where events go: "-" for stdout (console output then goes to stderr), or a
+    /// listening socket as "tcp:HOST:PORT" or "unix:PATH"
+    #[arg(long, value_name = "TARGET", default_value = "-", requires = "events")]
+    events_to: String,
+}
+
+/// Something the monitor did, as reported in the event stream
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    ProcessMatched {
+        pid: u32,
+        /// Compiler name, after launchers and drivers are unwrapped
+        name: &'a str,
+        command: &'a str,
+        directory: &'a str,
+        directory_origin: Option<DirectoryOrigin>,
+        launcher: Option<&'a str>,
+        driver: Option<&'a str>,
+        target: Option<&'a str>,
+    },
+    ResponseFileInlined {
+        pid: u32,
+        path: String,
+        /// Copy kept in the cache
+        saved_as: String,
+    },
+    ResponseFileMissing {
+        pid: u32,
+        path: String,
+        error: String,
+    },
+    EntryWritten {
+        pid: u32,
+        file: &'a str,
+        directory: &'a str,
+        cache_file: String,
+        probe: Option<&'a str>,
+    },
+    CompileExited {
+        pid: u32,
+        exit_code: i32,
+        duration_ms: u64,
+    },
+    Warning {
+        pid: Option<u32>,
+        message: &'a str,
+    },
+    SessionStopped {
+        #[serde(flatten)]
+        stats: SessionStats,
+    },
+}
+
+/// Receives the events of a monitor as they happen
+pub trait EventListener: Send + Sync {
+    fn on_event(&self, event: &Event);
+}
+
+/// Every event carries the time it happened
+#[derive(Serialize)]
+struct Envelope<'a> {
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+    time_ms: u64,
+}
+
+/// Destination of the event stream
+pub struct EventSink {
+    /// Dropped after the first failed write, e.g. when the consumer went away
+    writer: Mutex<Option<Box<dyn Write + Send>>>,
+}
+
+impl EventSink {
+    /// Open the stream requested on the command line, if any
+    ///
+    /// For stdout this has to happen before anything else is printed, as the
+    /// console output is moved to stderr here.
+    pub fn from_args(args: &EventArgs) -> Result<Option<Self>> {
+        if args.events.is_none() {
+            return Ok(None);
+        }
+
+        let writer: Box<dyn Write + Send> = match args.events_to.as_str() {
+            "-" => Box::new(console::take_stdout(Redirect::Stderr).context("Failed to set up events on stdout")?),
+            target => {
+                if let Some(address) = target.strip_prefix("tcp:") {
+                    Box::new(
+                        std::net::TcpStream::connect(address)
+                            .with_context(|| format!("Failed to connect to event listener {}", address))?,
+                    )
+                } else if let Some(path) = target.strip_prefix("unix:") {
+                    connect_unix(path)?
+                } else {
+                    anyhow::bail!("Invalid event target '{}', expected -, tcp:HOST:PORT or unix:PATH", target);
+                }
+            }
+        };
+
+        Ok(Some(Self {
+            writer: Mutex::new(Some(writer)),
+        }))
+    }
+
+    /// Whether events were requested on stdout, which then is not a terminal to draw on
+    #[cfg(feature = "tui")]
+    pub fn on_stdout(args: &EventArgs) -> bool {
+        args.events.is_some() && args.events_to == "-"
+    }
+}
+
+impl EventListener for EventSink {
+    /// Write one event as a line
+    fn on_event(&self, event: &Event) {
+        let mut writer = self.writer.lock().unwrap();
+        let Some(stream) = writer.as_mut() else {
+            return;
+        };
+
+        let time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+        let Ok(mut line) = serde_json::to_string(&Envelope { event, time_ms }) else {
+            return;
+        };
+        line.push('\n');
+
+        if let Err(e) = stream.write_all(line.as_bytes()).and_then(|()| stream.flush()) {
+            eprintln!("  ⚠ Warning: Event stream closed, no more events are sent: {}", e);
+            *writer = None;
+        }
+    }
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> Result<Box<dyn Write + Send>> {
+    let stream = std::os::unix::net::UnixStream::connect(path)
+        .with_context(|| format!("Failed to connect to event listener {}", path))?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &str) -> Result<Box<dyn Write + Send>> {
+    anyhow::bail!("Unix socket event targets are not available on this platform, use tcp:HOST:PORT")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    /// A writer whose output stays readable after the sink took it
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The line written for `event`, with `time_ms` checked and removed
+    fn line(event: &Event) -> serde_json::Value {
+        let buffer = Buffer::default();
+        let sink = EventSink {
+            writer: Mutex::new(Some(Box::new(buffer.clone()))),
+        };
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        sink.on_event(event);
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(output.matches('\n').count(), 1, "{}", output);
+        assert!(output.ends_with('\n'));
+
+        let mut value: serde_json::Value = serde_json::from_str(&output).unwrap();
+        let time_ms = value.as_object_mut().unwrap().remove("time_ms").unwrap();
+        assert!(time_ms.as_u64().unwrap() >= before);
+        value
+    }
+
+    #[test]
+    fn process_matched() {
+        let event = Event::ProcessMatched {
+            pid: 42,
+            name: "gcc",
+            command: "gcc -c a.c",
+            directory: "/src",
+            directory_origin: Some(DirectoryOrigin::Inferred),
+            launcher: Some("ccache"),
+            driver: None,
+            target: None,
+        };
+        assert_eq!(
+            line(&event),
+            json!({
+                "event": "process_matched",
+                "pid": 42,
+                "name": "gcc",
+                "command": "gcc -c a.c",
+                "directory": "/src",
+                "directory_origin": "inferred",
+                "launcher": "ccache",
+                "driver": null,
+                "target": null,
+            })
+        );
+    }
+
+    #[test]
+    fn response_files() {
+        let inlined = Event::ResponseFileInlined {
+            pid: 7,
+            path: "/tmp/a.rsp".to_string(),
+            saved_as: "cache/response_000001.rsp".to_string(),
+        };
+        assert_eq!(
+            line(&inlined),
+            json!({
+                "event": "response_file_inlined",
+                "pid": 7,
+                "path": "/tmp/a.rsp",
+                "saved_as": "cache/response_000001.rsp",
+            })
+        );
+
+        let missing = Event::ResponseFileMissing {
+            pid: 7,
+            path: "/tmp/b.rsp".to_string(),
+            error: "No such file or directory".to_string(),
+        };
+        assert_eq!(
+            line(&missing),
+            json!({
+                "event": "response_file_missing",
+                "pid": 7,
+                "path": "/tmp/b.rsp",
+                "error": "No such file or directory",
+            })
+        );
+    }
+
+    #[test]
+    fn entry_written_and_compile_exited() {
+        let written = Event::EntryWritten {
+            pid: 42,
+            file: "/src/a.c",
+            directory: "/src",
+            cache_file: "cache/command_000001.json".to_string(),
+            probe: None,
+        };
+        assert_eq!(
+            line(&written),
+            json!({
+                "event": "entry_written",
+                "pid": 42,
+                "file": "/src/a.c",
+                "directory": "/src",
+                "cache_file": "cache/command_000001.json",
+                "probe": null,
+            })
+        );
+
+        let exited = Event::CompileExited { pid: 42, exit_code: 1, duration_ms: 250 };
+        assert_eq!(
+            line(&exited),
+            json!({"event": "compile_exited", "pid": 42, "exit_code": 1, "duration_ms": 250})
+        );
+    }
+
+    #[test]
+    fn warnings_without_a_process() {
+        let event = Event::Warning { pid: None, message: "Event stream closed" };
+        assert_eq!(
+            line(&event),
+            json!({"event": "warning", "pid": null, "message": "Event stream closed"})
+        );
+    }
+
+    #[test]
+    fn session_stopped_carries_the_counters_inline() {
+        let event = Event::SessionStopped {
+            stats: SessionStats {
+                invocations: 3,
+                files_recorded: 4,
+                response_files_inlined: 1,
+                response_files_missed: 0,
+                probes: 2,
+            },
+        };
+        assert_eq!(
+            line(&event),
+            json!({
+                "event": "session_stopped",
+                "invocations": 3,
+                "files_recorded": 4,
+                "response_files_inlined": 1,
+                "response_files_missed": 0,
+                "probes": 2,
+            })
+        );
+    }
+}
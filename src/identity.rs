@@ -0,0 +1,88 @@
+// Process identity across pid reuse
+//
+// Pids are recycled, on a busy build machine within minutes. A pid alone cannot
+// tell a new compile from one that was already recorded, nor match an exit to the
+// process it belongs to. A process is therefore identified by its pid together
+// with its start time, which backends read from `/proc/<pid>/stat` or the Windows
+// process creation time. The start time is opaque: it is only compared, never
+// interpreted, and 0 where a backend cannot read it.
+//
+// Polling backends remember the processes they have seen with `SeenProcesses`,
+// which forgets a process once it has not shown up for a while instead of
+// clearing everything at some size.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a process that is no longer observed is remembered
+const SEEN_EXPIRY: Duration = Duration::from_secs(60);
+
+/// A process instance: its pid and start time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProcessId {
+    pub pid: u32,
+    /// Backend-specific start time, 0 if unknown
+    pub start_time: u64,
+}
+
+/// Processes a polling backend has already handled
+#[derive(Default)]
+pub struct SeenProcesses {
+    last_seen: HashMap<ProcessId, Instant>,
+}
+
+impl SeenProcesses {
+    /// Note that a process was observed; true the first time
+    pub fn observe(&mut self, process: ProcessId) -> bool {
+        self.last_seen.insert(process, Instant::now()).is_none()
+    }
+
+    /// Forget processes that have not been observed within the expiry time
+    pub fn expire(&mut self) {
+        self.expire_at(Instant::now());
+    }
+
+    fn expire_at(&mut self, now: Instant) {
+        self.last_seen
+            .retain(|_, last_seen| now.duration_since(*last_seen) < SEEN_EXPIRY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GCC: ProcessId = ProcessId { pid: 100, start_time: 5 };
+
+    #[test]
+    fn processes_are_new_only_once() {
+        let mut seen = SeenProcesses::default();
+        assert!(seen.observe(GCC));
+        assert!(!seen.observe(GCC));
+        seen.expire();
+        assert!(!seen.observe(GCC));
+    }
+
+    #[test]
+    fn a_reused_pid_is_a_new_process() {
+        let mut seen = SeenProcesses::default();
+        assert!(seen.observe(GCC));
+        assert!(seen.observe(ProcessId { pid: 100, start_time: 9 }));
+        assert!(seen.observe(ProcessId { pid: 101, start_time: 5 }));
+    }
+
+    #[test]
+    fn processes_not_observed_for_a_while_are_forgotten() {
+        let mut seen = SeenProcesses::default();
+        seen.observe(GCC);
+        let later = Instant::now() + SEEN_EXPIRY / 2;
+        seen.expire_at(later);
+        assert!(!seen.observe(GCC));
+
+        // Observing again restarted the expiry time
+        seen.expire_at(later + SEEN_EXPIRY / 2);
+        assert!(!seen.observe(GCC));
+        seen.expire_at(Instant::now() + SEEN_EXPIRY);
+        assert!(seen.observe(GCC));
+    }
+}
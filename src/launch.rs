@@ -0,0 +1,184 @@
+// Compiler launcher mode
+//
+// `compiler_monitor launch <compiler> <args...>` is meant to be put in front of the
+// real compiler by the build system (CMAKE_<LANG>_COMPILER_LAUNCHER, Meson's
+// compiler wrappers, Ninja rules). Every invocation is recorded straight into the
+// cache through the normal `process_creation_callback` path, then the compiler is
+// run as a child with our stdio. Once it exits, its exit code and duration are
+// added to the record and the exit code is passed back. No process scanning is
+// involved, so capture is exact and deterministic.
+//
+// The compiler's stdout may be meaningful (`-E`, `-M`, `/showIncludes`), so nothing
+// is printed there; recording problems are reported on stderr and never fail the
+// compile.
+
+use crate::identity::ProcessId;
+use crate::monitor::{join_arguments, CompilerMonitor, ProcessInfo};
+use crate::names::file_name;
+use crate::shims::SHIM_ACTIVE_ENV;
+use crate::tree::{self, ParentProcess};
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+
+/// Set to keep the whole environment of launched and shimmed compiles
+const FULL_ENVIRONMENT_ENV: &str = "COMPILER_MONITOR_FULL_ENVIRONMENT";
+
+/// Whether `COMPILER_MONITOR_FULL_ENVIRONMENT` asks for `--full-environment`; any
+/// value but empty, `0`, `false`, `no` or `off`, since a typo must not fail the compile
+pub fn full_environment_requested() -> bool {
+    std::env::var(FULL_ENVIRONMENT_ENV).is_ok_and(|value| is_enabled(&value))
+}
+
+fn is_enabled(value: &str) -> bool {
+    !matches!(value.trim().to_lowercase().as_str(), "" | "0" | "false" | "no" | "off")
+}
+
+/// Record one compiler invocation, run the compiler and record how it exited
+///
+/// Returns the compiler's exit code (128 + signal on Unix if it was killed).
+pub fn launch(cache_dir: &Path, command: &[String], full_environment: bool) -> Result<i32> {
+    let monitor = record_invocation(cache_dir, command, full_environment)
+        .map_err(|e| eprintln!("compiler_monitor: warning: failed to record invocation: {:#}", e))
+        .ok();
+
+    let exit_code = run_compiler(command)?;
+
+    if let Some(monitor) = monitor {
+        if let Err(e) = monitor.process_exit_callback(own_process_id(), exit_code) {
+            eprintln!("compiler_monitor: warning: failed to record exit code: {:#}", e);
+        }
+    }
+
+    Ok(exit_code)
+}
+
+fn record_invocation(cache_dir: &Path, command: &[String], full_environment: bool) -> Result<CompilerMonitor> {
+    let compiler = command.first().context("No compiler given")?;
+    let process_name = file_name(compiler).to_string();
+
+    let working_dir = std::env::current_dir()
+        .context("Failed to get current directory")?
+        .to_string_lossy()
+        .to_string();
+
+    // Everything the build system launches is a compile, so match any name
+    let monitor = CompilerMonitor::for_single_compile(&["*".to_string()], cache_dir.to_path_buf())?
+        .with_quiet(true)
+        .with_exit_tracking(true)
+        .with_full_environment(full_environment);
+    let process = own_process_id();
+    monitor.process_creation_callback(&ProcessInfo {
+        pid: process.pid,
+        start_time: process.start_time,
+        parent_pid: None,
+        name: process_name,
+        executable: compiler.clone(),
+        command_line: join_arguments(command),
+        working_dir,
+        environment: build_environment(std::env::vars()),
+        parents: own_parents(),
+    })?;
+
+    Ok(monitor)
+}
+
+/// The environment the build gave the compiler; the marker a shim sets before
+/// recording is ours, not the build's
+fn build_environment(variables: impl Iterator<Item = (String, String)>) -> BTreeMap<String, String> {
+    variables.filter(|(name, _)| name != SHIM_ACTIVE_ENV).collect()
+}
+
+/// The compile is recorded as our own process, which lives exactly as long
+fn own_process_id() -> ProcessId {
+    ProcessId {
+        pid: std::process::id(),
+        start_time: 0,
+    }
+}
+
+/// The build tool chain that ran us, for attributing the compile to a target
+#[cfg(target_os = "linux")]
+fn own_parents() -> Vec<ParentProcess> {
+    tree::parent_chain(std::process::id(), &crate::capture::ProcfsLookup)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn own_parents() -> Vec<ParentProcess> {
+    Vec::new()
+}
+
+fn run_compiler(command: &[String]) -> Result<i32> {
+    let (compiler, args) = command.split_first().context("No compiler given")?;
+    let status = Command::new(compiler)
+        .args(args)
+        .status()
+        .with_context(|| format!("Failed to execute {}", compiler))?;
+
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return Ok(128 + signal);
+    }
+
+    Ok(status.code().unwrap_or(1))
+}
+
+/// Run the compiler without recording anything
+///
+/// Returns the compiler's exit code. On Unix the compiler replaces this process,
+/// so this only returns if it could not be started.
+#[cfg(unix)]
+pub fn exec_compiler(command: &[String]) -> Result<i32> {
+    use std::os::unix::process::CommandExt;
+
+    let (compiler, args) = command.split_first().context("No compiler given")?;
+    let err = Command::new(compiler).args(args).exec();
+    Err(err).with_context(|| format!("Failed to execute {}", compiler))
+}
+
+#[cfg(not(unix))]
+pub fn exec_compiler(command: &[String]) -> Result<i32> {
+    run_compiler(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::argv;
+
+    #[test]
+    fn full_environment_values() {
+        for value in ["1", "true", "YES", " on ", "yes please", "2"] {
+            assert!(is_enabled(value), "{:?}", value);
+        }
+        for value in ["", " ", "0", "false", "No", "OFF"] {
+            assert!(!is_enabled(value), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn the_shim_marker_is_not_recorded() {
+        let variables = [("CPATH", "/opt/inc"), (SHIM_ACTIVE_ENV, "1")]
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        assert_eq!(
+            build_environment(variables.into_iter()),
+            BTreeMap::from([("CPATH".to_string(), "/opt/inc".to_string())])
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn compiler_exit_codes_are_passed_back() {
+        assert_eq!(run_compiler(&argv(&["sh", "-c", "exit 0"])).unwrap(), 0);
+        assert_eq!(run_compiler(&argv(&["sh", "-c", "exit 3"])).unwrap(), 3);
+        // Killed by a signal: 128 + signal, as a shell reports it
+        assert_eq!(run_compiler(&argv(&["sh", "-c", "kill -9 $$"])).unwrap(), 137);
+    }
+
+    #[test]
+    fn missing_compilers_are_errors() {
+        assert!(run_compiler(&[]).is_err());
+        assert!(run_compiler(&argv(&["compiler_monitor_no_such_compiler"])).is_err());
+    }
+}
@@ -0,0 +1,1374 @@
+// Platform-neutral capture core
+//
+// Everything in here works on the plain process facts a capture backend hands over
+// (`ProcessInfo`): matching the process against the patterns, inlining response files,
+// finding source files and writing one `command_NNNNNN.json` per source file into the
+// cache directory.
+
+use crate::driver;
+use crate::environment;
+use crate::events::{Event, EventListener};
+use crate::identity::ProcessId;
+use crate::names::file_name;
+use crate::pattern::PatternSet;
+use crate::probe::ProbeRules;
+use crate::scope::ScopeFilter;
+use crate::shutdown;
+use crate::subprocess;
+use crate::tree::{self, ParentProcess};
+use crate::workdir::{self, DirectoryOrigin};
+use crate::wrapper;
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long after a launcher or driver was recorded its own runs of the compiler on
+/// the same source are treated as part of it
+const LAUNCHER_CHILD_WINDOW: Duration = Duration::from_secs(600);
+
+/// How long a captured driver is remembered for recognizing its subprocesses, unless
+/// its exit is reported earlier
+const DRIVER_PID_WINDOW: Duration = Duration::from_secs(600);
+
+/// Console progress output, silenced for monitors in quiet mode
+macro_rules! say {
+    ($monitor:expr, $($arg:tt)*) => {
+        if !$monitor.quiet {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// A single compile command entry in JSON Compilation Database format
+/// See: https://clang.llvm.org/docs/JSONCompilationDatabase.html
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompileCommand {
+    pub directory: String,
+    pub command: String,
+    pub file: String,
+}
+
+/// A process handed to the monitor by a capture backend
+#[derive(Debug, Clone, Default)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    /// Start time in the backend's clock, 0 if unknown; see `ProcessId`
+    pub start_time: u64,
+    /// Parent process id, if the backend knows it
+    pub parent_pid: Option<u32>,
+    /// Executable name, as matched by the patterns
+    pub name: String,
+    /// Full executable path when known, otherwise argv[0] or the name
+    pub executable: String,
+    pub command_line: String,
+    /// Working directory, empty if the backend could not read it
+    pub working_dir: String,
+    /// Environment variables, empty if the backend could not read them
+    pub environment: BTreeMap<String, String>,
+    /// Ancestors, nearest first, as far as the backend could read them
+    pub parents: Vec<ParentProcess>,
+}
+
+impl ProcessInfo {
+    pub fn id(&self) -> ProcessId {
+        ProcessId {
+            pid: self.pid,
+            start_time: self.start_time,
+        }
+    }
+}
+
+/// A command file in the cache: the compile command plus what was learned about it
+/// while recording, which `collect` does not pass on to compile_commands.json
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedCommand {
+    #[serde(flatten)]
+    pub command: CompileCommand,
+
+    /// Compiler launcher(s) the command ran through, e.g. "ccache"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub launcher: Option<String>,
+
+    /// Driver the command was rewritten from, e.g. "zig cc" or "emcc"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub driver: Option<String>,
+
+    /// Probe rule the compile matched, e.g. "cmake try_compile"; left out by `collect`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probe: Option<String>,
+
+    /// Compiler exit code (128 + signal if it was killed), if the backend saw it exit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+
+    /// Time from capture to exit in milliseconds, if the backend saw it exit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+
+    /// The compiler variables of its environment (all of it with `--full-environment`);
+    /// their options are already in `command`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<BTreeMap<String, String>>,
+
+    /// Build target the compile belongs to, e.g. "ninja /src/build"; see tree.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+
+    /// Parent processes of the compiler, nearest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parents: Vec<ParentProcess>,
+
+    /// How `directory` was determined when it could not be read; see workdir.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory_origin: Option<DirectoryOrigin>,
+}
+
+impl CachedCommand {
+    /// Whether the compile is not known to have failed
+    pub fn succeeded(&self) -> bool {
+        self.exit_code.is_none_or(|code| code == 0)
+    }
+}
+
+/// Cache entries of a running compile, completed when it exits
+struct InFlight {
+    entries: Vec<(PathBuf, CachedCommand)>,
+    started: Instant,
+}
+
+/// A source recorded through a launcher or driver
+struct LaunchedSource {
+    /// The launcher or driver process
+    process: ProcessId,
+    recorded: Instant,
+}
+
+/// A captured process, remembered to recognize its subprocesses
+struct CapturedDriver {
+    name: String,
+    start_time: u64,
+    captured: Instant,
+}
+
+/// What a recording session captured, for the summary printed when it stops
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SessionStats {
+    /// Matching compiler invocations, driver subprocesses not counted
+    pub invocations: u64,
+    /// Command files written
+    pub files_recorded: u64,
+    pub response_files_inlined: u64,
+    /// Response files that could not be read and stay referenced by path
+    pub response_files_missed: u64,
+    /// Compiles tagged as probes, which `collect` leaves out, and source-less queries
+    pub probes: u64,
+}
+
+/// An invocation with launchers stripped and drivers rewritten
+struct Invocation {
+    argv: Vec<String>,
+    launcher: Option<String>,
+    driver: Option<String>,
+}
+
+/// Main compiler monitoring structure
+///
+/// Monitors process creation and captures compiler invocations that match the specified patterns.
+/// Handles response file inlining and saves individual command files to cache.
+pub struct CompilerMonitor {
+    patterns: PatternSet,
+    cache_dir: PathBuf,
+    command_counter: Arc<Mutex<u64>>,
+    response_counter: Arc<Mutex<u64>>,
+    scope: ScopeFilter,
+    probe_rules: ProbeRules,
+    launched_sources: Mutex<HashMap<String, LaunchedSource>>,
+    captured_drivers: Mutex<HashMap<u32, CapturedDriver>>,
+    in_flight: Mutex<HashMap<ProcessId, InFlight>>,
+    track_exits: bool,
+    full_environment: bool,
+    idle_timeout: Option<Duration>,
+    last_compile: Mutex<Instant>,
+    stats: Mutex<SessionStats>,
+    /// Compiles recorded by `capture_process`, for numbering them on the console
+    captured: Mutex<u64>,
+    paused: AtomicBool,
+    event_listeners: Vec<Arc<dyn EventListener>>,
+    quiet: bool,
+}
+
+impl CompilerMonitor {
+    pub fn new(patterns: &[String], cache_dir: PathBuf) -> Result<Self> {
+        // Create cache directory
+        fs::create_dir_all(&cache_dir).context("Failed to create cache directory")?;
+
+        // Find the highest existing command number to continue from
+        let highest_cmd_num = Self::find_highest_command_number(&cache_dir);
+        let highest_rsp_num = Self::find_highest_response_number(&cache_dir);
+
+        Self::with_counters(patterns, cache_dir, highest_cmd_num, highest_rsp_num)
+    }
+
+    /// A monitor for recording a single compile, as a launcher or shim does
+    ///
+    /// Scanning the cache for the highest number on every compile would make a build
+    /// quadratic in its number of compiles, so the counters start from the numbers
+    /// last written, kept in small hint files next to the entries. A hint that is
+    /// behind because of concurrent launchers only costs `write_numbered` a few
+    /// retries; without one (a new cache) the cache is scanned once.
+    pub fn for_single_compile(patterns: &[String], cache_dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&cache_dir).context("Failed to create cache directory")?;
+
+        let command_start = read_counter_hint(&cache_dir, "command")
+            .unwrap_or_else(|| Self::find_highest_command_number(&cache_dir));
+        let response_start = read_counter_hint(&cache_dir, "response")
+            .unwrap_or_else(|| Self::find_highest_response_number(&cache_dir));
+
+        Self::with_counters(patterns, cache_dir, command_start, response_start)
+    }
+
+    fn with_counters(patterns: &[String], cache_dir: PathBuf, command_start: u64, response_start: u64) -> Result<Self> {
+        let patterns = PatternSet::new(patterns)?;
+
+        Ok(Self {
+            patterns,
+            cache_dir,
+            command_counter: Arc::new(Mutex::new(command_start)),
+            response_counter: Arc::new(Mutex::new(response_start)),
+            scope: ScopeFilter::default(),
+            probe_rules: ProbeRules::default(),
+            launched_sources: Mutex::new(HashMap::new()),
+            captured_drivers: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+            track_exits: false,
+            full_environment: false,
+            idle_timeout: None,
+            last_compile: Mutex::new(Instant::now()),
+            stats: Mutex::new(SessionStats::default()),
+            captured: Mutex::new(0),
+            paused: AtomicBool::new(false),
+            event_listeners: Vec::new(),
+            quiet: false,
+        })
+    }
+
+    /// Suppress all console output, e.g. when running as a compiler launcher
+    /// whose stdout belongs to the compiler
+    pub fn with_quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// Keep the cache entries of running compiles until `process_exit_callback`
+    /// reports their exit; for backends that observe process exits
+    pub fn with_exit_tracking(mut self, track_exits: bool) -> Self {
+        self.track_exits = track_exits;
+        self
+    }
+
+    /// Record each compile's whole environment instead of only the variables that
+    /// affect it (see environment.rs)
+    pub fn with_full_environment(mut self, full_environment: bool) -> Self {
+        self.full_environment = full_environment;
+        self
+    }
+
+    /// Ask backends to stop once no compile has been seen, and none is running, for
+    /// this long
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Report what is captured to an event stream or dashboard, besides the console
+    pub fn with_event_listener(mut self, listener: Arc<dyn EventListener>) -> Self {
+        self.event_listeners.push(listener);
+        self
+    }
+
+    /// Restrict capture to processes inside a scope
+    pub fn with_scope(mut self, scope: ScopeFilter) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    /// Replace the built-in rules tagging probe compiles
+    pub fn with_probe_rules(mut self, probe_rules: ProbeRules) -> Self {
+        self.probe_rules = probe_rules;
+        self
+    }
+
+    /// Scope that backends check before handing a process to `process_creation_callback`
+    pub fn scope(&self) -> &ScopeFilter {
+        &self.scope
+    }
+
+    /// Directory the command and response files are written to
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    /// The patterns as given
+    pub fn patterns(&self) -> &[String] {
+        self.patterns.sources()
+    }
+
+    /// Number of the last command file written to the cache, by anyone
+    pub fn command_counter(&self) -> u64 {
+        *self.command_counter.lock().unwrap()
+    }
+
+    /// Number of the last response file saved to the cache, by anyone
+    pub fn response_counter(&self) -> u64 {
+        *self.response_counter.lock().unwrap()
+    }
+
+    /// Ignore new compiles until resumed; exits of compiles already captured are
+    /// still recorded
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Whether a backend should stop capturing: on Ctrl+C, or after the idle timeout
+    ///
+    /// Backends check this between batches of work and return from `run` once they
+    /// have finished what they were processing.
+    pub fn should_stop(&self) -> bool {
+        shutdown::stop_requested() || self.idle_expired()
+    }
+
+    fn idle_expired(&self) -> bool {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return false;
+        };
+        self.last_compile.lock().unwrap().elapsed() >= idle_timeout && self.in_flight.lock().unwrap().is_empty()
+    }
+
+    /// Counts of what was captured so far
+    pub fn stats(&self) -> SessionStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Print why recording stopped and what the session captured
+    pub fn print_summary(&self) {
+        let stats = self.stats();
+
+        say!(self, "");
+        if shutdown::stop_requested() {
+            say!(self, "Recording stopped (stop requested)");
+        } else if self.idle_expired() {
+            say!(self, "Recording stopped (no compile for {}s)", self.idle_timeout.unwrap_or_default().as_secs());
+        }
+        say!(self, "Session summary:");
+        say!(self, "  Invocations seen:        {}", stats.invocations);
+        say!(self, "  Files recorded:          {}", stats.files_recorded);
+        say!(self, "  Response files inlined:  {}", stats.response_files_inlined);
+        if stats.response_files_missed > 0 {
+            say!(self, "  ⚠ Response files missed: {}", stats.response_files_missed);
+        }
+        say!(self, "  Probes (left out):       {}", stats.probes);
+
+        let running = self.in_flight.lock().unwrap().len();
+        if running > 0 {
+            say!(self, "  {} compile(s) still running, recorded without exit status", running);
+        }
+
+        self.emit(&Event::SessionStopped { stats });
+    }
+
+    fn emit(&self, event: &Event) {
+        for listener in &self.event_listeners {
+            listener.on_event(event);
+        }
+    }
+
+    /// Print a warning and report it in the event stream
+    pub fn warn(&self, pid: Option<u32>, message: &str) {
+        say!(self, "  ⚠ Warning: {}", message);
+        self.emit(&Event::Warning { pid, message });
+    }
+
+    /// Whether a process with this executable name may be captured
+    ///
+    /// Backends use this to skip reading argv and paths of unrelated processes; the
+    /// full match happens in `process_creation_callback`.
+    pub fn matches_process_name(&self, process_name: &str) -> bool {
+        // Launchers and drivers are matched on the compiler they run
+        self.patterns.may_match_name(process_name)
+            || wrapper::is_launcher(process_name)
+            || driver::is_driver(process_name)
+    }
+
+    /// Whether a process should be captured, judged on name, executable path and argv
+    pub fn matches_process(&self, process_name: &str, executable: &str, command_line: &str) -> bool {
+        let argv = split_arguments(command_line);
+        if self.patterns.matches(process_name, executable, &argv) {
+            return true;
+        }
+
+        match unwrap_invocation(executable, &argv) {
+            Some(unwrapped) => {
+                let compiler = &unwrapped.argv[0];
+                self.patterns.matches(file_name(compiler), compiler, &unwrapped.argv)
+            }
+            None => false,
+        }
+    }
+
+    fn find_highest_command_number(cache_dir: &Path) -> u64 {
+        let mut highest = 0u64;
+
+        if let Ok(entries) = fs::read_dir(cache_dir) {
+            let command_regex = Regex::new(r"command_(\d+)\.json$").unwrap();
+
+            for entry in entries.flatten() {
+                if let Some(filename) = entry.file_name().to_str() {
+                    if let Some(caps) = command_regex.captures(filename) {
+                        if let Ok(num) = caps[1].parse::<u64>() {
+                            highest = highest.max(num);
+                        }
+                    }
+                }
+            }
+        }
+
+        highest
+    }
+
+    fn find_highest_response_number(cache_dir: &Path) -> u64 {
+        let mut highest = 0u64;
+
+        if let Ok(entries) = fs::read_dir(cache_dir) {
+            let response_regex = Regex::new(r"response_(\d+)\.rsp$").unwrap();
+
+            for entry in entries.flatten() {
+                if let Some(filename) = entry.file_name().to_str() {
+                    if let Some(caps) = response_regex.captures(filename) {
+                        if let Ok(num) = caps[1].parse::<u64>() {
+                            highest = highest.max(num);
+                        }
+                    }
+                }
+            }
+        }
+
+        highest
+    }
+
+    /// Record a process handed over by a capture backend; whether an entry was written
+    ///
+    /// Failures are reported as warnings, so one compile that cannot be recorded does
+    /// not end the capture.
+    pub fn capture_process(&self, process: &ProcessInfo) -> bool {
+        match self.process_creation_callback(process) {
+            Ok(true) => {
+                let mut captured = self.captured.lock().unwrap();
+                *captured += 1;
+                say!(self, "  [{}] Captured compilation command\n", *captured);
+                true
+            }
+            Ok(false) => false,
+            Err(e) => {
+                self.warn(Some(process.pid), &format!("Failed to record pid {}: {:#}", process.pid, e));
+                false
+            }
+        }
+    }
+
+    /// Match, expand and write a process's compile command; whether an entry was written
+    pub fn process_creation_callback(&self, process: &ProcessInfo) -> Result<bool> {
+        let ProcessInfo { name: process_name, executable, command_line, working_dir, .. } = process;
+
+        if self.is_paused() || !self.matches_process(process_name, executable, command_line) {
+            return Ok(false);
+        }
+
+        // Only the driver invocation the build wrote is a compile command
+        if let Some(driver) = self.captured_driver_of(process) {
+            say!(self, "  Skipped: {} (pid {}) is a subprocess of {}", process_name, process.pid, driver);
+            return Ok(false);
+        }
+
+        // Record the compiler behind a launcher or driver, not the launcher or driver
+        let (process_name, command_line, launcher, driver) =
+            match unwrap_invocation(executable, &split_arguments(command_line)) {
+                Some(unwrapped) => (
+                    file_name(&unwrapped.argv[0]).to_string(),
+                    join_arguments(&unwrapped.argv),
+                    unwrapped.launcher,
+                    unwrapped.driver,
+                ),
+                None => (process_name.clone(), command_line.clone(), None, None),
+            };
+
+        let (working_dir, directory_origin) = if working_dir.is_empty() {
+            let fallback = std::env::current_dir().unwrap_or_default().to_string_lossy().to_string();
+            let recovered = workdir::recover_working_directory(
+                &split_arguments(&command_line),
+                &process.parents,
+                &fallback,
+                |path| peek_response_file(path, process.pid),
+            );
+            (recovered.directory, Some(recovered.origin))
+        } else {
+            (working_dir.clone(), None)
+        };
+
+        // Backends could not check a directory they could not read
+        if directory_origin.is_some() && !self.scope.allows_directory(&working_dir) {
+            say!(self, "  Skipped: {} (pid {}) runs in {}, out of scope", process_name, process.pid, working_dir);
+            return Ok(false);
+        }
+        *self.last_compile.lock().unwrap() = Instant::now();
+        self.stats.lock().unwrap().invocations += 1;
+
+        say!(self, "✓ Detected: {} in {}", process_name, working_dir);
+        match directory_origin {
+            Some(DirectoryOrigin::Inferred) => {
+                say!(self, "  Working directory could not be read, inferred from the command's paths")
+            }
+            Some(DirectoryOrigin::Unresolved) => self.warn(
+                Some(process.pid),
+                "Working directory could not be read or inferred, relative paths may be wrong",
+            ),
+            None => {}
+        }
+        if let Some(launcher) = &launcher {
+            say!(self, "  Launcher: {}", launcher);
+        }
+        if let Some(driver) = &driver {
+            say!(self, "  Driver: {}", driver);
+        }
+        let target = tree::build_target(&process.parents);
+        if let Some(target) = &target {
+            say!(self, "  Target: {}", target);
+        }
+        say!(self, "  Command: {}", command_line);
+        self.emit(&Event::ProcessMatched {
+            pid: process.pid,
+            name: &process_name,
+            command: &command_line,
+            directory: &working_dir,
+            directory_origin,
+            launcher: launcher.as_deref(),
+            driver: driver.as_deref(),
+            target: target.as_deref(),
+        });
+
+        // Parse and inline response files
+        let expanded_command = self.expand_response_files(&command_line, &working_dir, process.pid)?;
+
+        // Options the compiler takes from CL, CPATH and the like; C or C++ is told by
+        // the sources, which may only be named in response files
+        let expanded_command =
+            match environment::expand_environment(&split_arguments(&expanded_command), &process.environment) {
+                Some(expanded) => {
+                    say!(self, "  Environment: {}", expanded.variables.join(", "));
+                    join_arguments(&expanded.argv)
+                }
+                None => expanded_command,
+            };
+
+        // Extract all source files from command line
+        let source_files = self.extract_all_source_files(&expanded_command, &working_dir);
+
+        let argv = split_arguments(&expanded_command);
+
+        if source_files.is_empty() {
+            // Version and search path queries are expected to have none
+            match self.probe_rules.classify("", &working_dir, &argv) {
+                Some(probe) => {
+                    say!(self, "  Probe: {}, nothing to record", probe);
+                    self.stats.lock().unwrap().probes += 1;
+                }
+                None => self.warn(Some(process.pid), "No source files found in command"),
+            }
+            return Ok(false);
+        }
+
+        say!(self, "  Found {} source file(s)", source_files.len());
+        let environment = if self.full_environment {
+            process.environment.clone()
+        } else {
+            environment::compiler_variables(&process.environment)
+        };
+        let mut written = Vec::new();
+        let mut recorded = 0;
+
+        // Create one entry per source file
+        for source_file in source_files {
+            if !self.track_launched_source(&source_file, process.id(), launcher.is_some() || driver.is_some()) {
+                say!(self, "  Skipped: {} is already recorded through its launcher or driver",
+                    PathBuf::from(&source_file).file_name().unwrap_or_default().to_string_lossy());
+                continue;
+            }
+
+            let probe = self
+                .probe_rules
+                .classify(&source_file, &working_dir, &argv)
+                .map(|rule| rule.to_string());
+            if let Some(probe) = &probe {
+                say!(self, "  Probe: {}", probe);
+                self.stats.lock().unwrap().probes += 1;
+            }
+
+            let compile_cmd = CachedCommand {
+                command: CompileCommand {
+                    directory: working_dir.clone(),
+                    command: expanded_command.clone(),
+                    file: source_file.clone(),
+                },
+                launcher: launcher.clone(),
+                driver: driver.clone(),
+                probe,
+                exit_code: None,
+                duration_ms: None,
+                environment: (!environment.is_empty()).then(|| environment.clone()),
+                target: target.clone(),
+                parents: tree::recorded_chain(&process.parents),
+                directory_origin,
+            };
+
+            // Save to individual file in cache
+            let json = serde_json::to_string_pretty(&compile_cmd)
+                .context("Failed to serialize compile command")?;
+            let filepath = write_numbered(&self.cache_dir, &self.command_counter, "command", "json", &json)?;
+            self.stats.lock().unwrap().files_recorded += 1;
+            recorded += 1;
+
+            say!(self, "  Saved: {} -> {}",
+                PathBuf::from(&source_file).file_name().unwrap_or_default().to_string_lossy(),
+                filepath.display());
+            self.emit(&Event::EntryWritten {
+                pid: process.pid,
+                file: &compile_cmd.command.file,
+                directory: &compile_cmd.command.directory,
+                cache_file: filepath.to_string_lossy().to_string(),
+                probe: compile_cmd.probe.as_deref(),
+            });
+
+            if self.track_exits {
+                written.push((filepath, compile_cmd));
+            }
+        }
+
+        // Only a process that was recorded is a driver whose subprocesses are dropped
+        if recorded > 0 {
+            self.remember_driver(process);
+        }
+
+        if !written.is_empty() {
+            self.in_flight.lock().unwrap().insert(
+                process.id(),
+                InFlight {
+                    entries: written,
+                    started: Instant::now(),
+                },
+            );
+        }
+
+        Ok(recorded > 0)
+    }
+
+    /// Complete the cache entries of a captured compile with its exit code and duration
+    ///
+    /// Exits of processes that were not captured are ignored.
+    pub fn process_exit_callback(&self, process: ProcessId, exit_code: i32) -> Result<()> {
+        self.forget_driver(process);
+
+        let Some(in_flight) = self.in_flight.lock().unwrap().remove(&process) else {
+            return Ok(());
+        };
+        let pid = process.pid;
+        let duration_ms = in_flight.started.elapsed().as_millis() as u64;
+
+        for (filepath, mut compile_cmd) in in_flight.entries {
+            compile_cmd.exit_code = Some(exit_code);
+            compile_cmd.duration_ms = Some(duration_ms);
+
+            let json = serde_json::to_string_pretty(&compile_cmd)
+                .context("Failed to serialize compile command")?;
+            write_atomically(&filepath, &json)?;
+        }
+
+        self.emit(&Event::CompileExited { pid, exit_code, duration_ms });
+        if exit_code != 0 {
+            say!(self, "  ⚠ Compile in pid {} failed with exit code {} after {} ms", pid, exit_code, duration_ms);
+        }
+
+        Ok(())
+    }
+
+    /// Forget a captured compile whose exit the backend missed, so that it no longer
+    /// counts as running; its entries keep no exit code
+    pub fn process_exit_lost(&self, process: ProcessId) {
+        self.forget_driver(process);
+
+        if self.in_flight.lock().unwrap().remove(&process).is_some() {
+            self.warn(Some(process.pid), &format!("Exit of pid {} was not observed, its entries have no exit code", process.pid));
+        }
+    }
+
+    /// The name of the captured driver this process is an internal step of
+    fn captured_driver_of(&self, process: &ProcessInfo) -> Option<String> {
+        let parent_pid = process.parent_pid?;
+        let drivers = self.captured_drivers.lock().unwrap();
+        let driver = drivers.get(&parent_pid)?;
+
+        // The parent must still be the driver, not a later process given its pid
+        if driver.captured.elapsed() >= DRIVER_PID_WINDOW
+            || driver.start_time == 0
+            || read_start_time(parent_pid) != Some(driver.start_time)
+        {
+            return None;
+        }
+
+        let argv = split_arguments(&process.command_line);
+        subprocess::is_driver_subprocess(&driver.name, &process.name, &argv)
+            .then(|| format!("{} (pid {})", driver.name, parent_pid))
+    }
+
+    fn remember_driver(&self, process: &ProcessInfo) {
+        // A spawn reported before its child exists has no pid children could name
+        if process.pid == 0 {
+            return;
+        }
+
+        // Without a start time a reused pid cannot be told apart
+        let start_time = match process.start_time {
+            0 => read_start_time(process.pid).unwrap_or_default(),
+            start_time => start_time,
+        };
+        if start_time == 0 {
+            return;
+        }
+
+        let mut drivers = self.captured_drivers.lock().unwrap();
+        let now = Instant::now();
+        drivers.retain(|_, driver| now.duration_since(driver.captured) < DRIVER_PID_WINDOW);
+        drivers.insert(
+            process.pid,
+            CapturedDriver {
+                name: process.name.clone(),
+                start_time,
+                captured: now,
+            },
+        );
+    }
+
+    /// Stop treating children of an exited driver's pid as its subprocesses, as the
+    /// pid may be reused
+    fn forget_driver(&self, process: ProcessId) {
+        let mut drivers = self.captured_drivers.lock().unwrap();
+        if drivers
+            .get(&process.pid)
+            .is_some_and(|driver| driver.start_time == process.start_time)
+        {
+            drivers.remove(&process.pid);
+        }
+    }
+
+    /// Remember sources compiled through a launcher or driver; false for a compile of
+    /// such a source that is most likely the launcher or driver running the compiler,
+    /// or the same driver process again after re-executing itself (emcc's launcher
+    /// script exec'ing `python emcc.py`)
+    fn track_launched_source(&self, source_file: &str, process: ProcessId, via_launcher: bool) -> bool {
+        let mut launched = self.launched_sources.lock().unwrap();
+        let now = Instant::now();
+        launched.retain(|_, source| now.duration_since(source.recorded) < LAUNCHER_CHILD_WINDOW);
+
+        if via_launcher {
+            let previous = launched.insert(
+                source_file.to_string(),
+                LaunchedSource {
+                    process,
+                    recorded: now,
+                },
+            );
+            return previous.is_none_or(|source| source.process != process);
+        }
+
+        !launched.contains_key(source_file)
+    }
+
+    pub fn expand_response_files(&self, command_line: &str, working_dir: &str, pid: u32) -> Result<String> {
+        let mut result = command_line.to_string();
+        let response_file_regex = Regex::new(r"@([^\s]+)").unwrap();
+
+        // Find all response files
+        let matches: Vec<_> = response_file_regex
+            .captures_iter(command_line)
+            .map(|cap| cap[1].to_string())
+            .collect();
+
+        for response_file_path in matches {
+            // Resolve the response file path
+            let mut full_path = PathBuf::from(&response_file_path);
+            if !full_path.is_absolute() {
+                full_path = PathBuf::from(working_dir).join(&response_file_path);
+            }
+
+            let contents = match self.read_response_file(&full_path, pid) {
+                Ok(bytes) => decode_response_file(bytes),
+                Err(e) => {
+                    say!(
+                        self,
+                        "  ⚠ Warning: Could not read response file {}: {}",
+                        full_path.display(),
+                        e
+                    );
+                    self.stats.lock().unwrap().response_files_missed += 1;
+                    self.emit(&Event::ResponseFileMissing {
+                        pid,
+                        path: full_path.to_string_lossy().to_string(),
+                        error: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+
+            // Save response file to cache
+            let saved_as = self.save_response_file(&full_path, &contents)?;
+
+            // Inline the contents
+            result = result.replace(&format!("@{}", response_file_path), &join_lines(&contents));
+            say!(self, "  ✓ Inlined response file: {}", full_path.display());
+            self.stats.lock().unwrap().response_files_inlined += 1;
+            self.emit(&Event::ResponseFileInlined {
+                pid,
+                path: full_path.to_string_lossy().to_string(),
+                saved_as: saved_as.to_string_lossy().to_string(),
+            });
+        }
+
+        Ok(result)
+    }
+
+    /// Read a response file by path, or failing that through the process using it
+    ///
+    /// Build tools delete response files once the compile is done, which may be
+    /// before a polling backend gets to them; while the compiler runs, it may still
+    /// have the file open.
+    fn read_response_file(&self, path: &Path, pid: u32) -> std::io::Result<Vec<u8>> {
+        let (bytes, recovered) = read_file_of_process(path, pid)?;
+        if recovered {
+            say!(self, "  ✓ Recovered response file {} from pid {}", path.display(), pid);
+        }
+        Ok(bytes)
+    }
+
+    fn save_response_file(&self, _path: &Path, contents: &str) -> Result<PathBuf> {
+        let cache_path = write_numbered(&self.cache_dir, &self.response_counter, "response", "rsp", contents)
+            .context("Failed to save response file")?;
+
+        say!(self, "  [RSP] Saved: {}", cache_path.display());
+
+        Ok(cache_path)
+    }
+
+    pub fn extract_all_source_files(&self, command: &str, working_dir: &str) -> Vec<String> {
+        // Look for common source file extensions
+        let source_extensions = [".c", ".cpp", ".cc", ".cxx", ".c++", ".C"];
+
+        // Use proper argument parsing to handle quoted paths
+        let args = split_arguments(command);
+        let mut source_files = Vec::new();
+
+        for arg in args {
+            // Strip quotes and check for source file extensions
+            let clean_arg = arg.trim_matches('"');
+            let lower = clean_arg.to_lowercase();
+
+            if source_extensions.iter().any(|ext| lower.ends_with(ext)) {
+                // Make it absolute if relative
+                let path = PathBuf::from(clean_arg);
+                let absolute_path = if path.is_absolute() {
+                    clean_arg.to_string()
+                } else {
+                    PathBuf::from(working_dir)
+                        .join(clean_arg)
+                        .to_string_lossy()
+                        .to_string()
+                };
+                source_files.push(absolute_path);
+            }
+        }
+
+        source_files
+    }
+}
+
+/// Write `contents` to the next free `<prefix>_NNNNNN.<extension>` in the cache
+///
+/// Several processes may share a cache directory (e.g. one launcher per compile), so
+/// a number taken by someone else since our counter was initialised is skipped
+/// rather than overwritten. The number is claimed with an empty file, which the
+/// contents then replace in one step.
+fn write_numbered(
+    cache_dir: &Path,
+    counter: &Mutex<u64>,
+    prefix: &str,
+    extension: &str,
+    contents: &str,
+) -> Result<PathBuf> {
+    let mut counter = counter.lock().unwrap();
+
+    loop {
+        *counter += 1;
+        let filepath = cache_dir.join(format!("{}_{:06}.{}", prefix, *counter, extension));
+
+        match fs::OpenOptions::new().write(true).create_new(true).open(&filepath) {
+            Ok(_) => {
+                write_atomically(&filepath, contents)?;
+                write_counter_hint(cache_dir, prefix, *counter);
+                return Ok(filepath);
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to write to {}", filepath.display()))
+            }
+        }
+    }
+}
+
+/// Hint file with the last number written for `prefix`, see `CompilerMonitor::for_single_compile`
+fn counter_hint_path(cache_dir: &Path, prefix: &str) -> PathBuf {
+    cache_dir.join(format!(".{}_counter", prefix))
+}
+
+fn read_counter_hint(cache_dir: &Path, prefix: &str) -> Option<u64> {
+    fs::read_to_string(counter_hint_path(cache_dir, prefix)).ok()?.trim().parse().ok()
+}
+
+/// Best effort: a missing or stale hint only means a scan or a few retries later
+///
+/// Each writer renames its own temporary file into place, so concurrent writers
+/// leave one of their numbers rather than a mix of both.
+fn write_counter_hint(cache_dir: &Path, prefix: &str, number: u64) {
+    let hint_path = counter_hint_path(cache_dir, prefix);
+    let temp_path = cache_dir.join(format!(".{}_counter.{}.tmp", prefix, std::process::id()));
+
+    if fs::write(&temp_path, number.to_string()).is_err() || fs::rename(&temp_path, &hint_path).is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+}
+
+/// Replace a file's contents so that readers, and a process killed halfway, never
+/// leave it half written
+fn write_atomically(filepath: &Path, contents: &str) -> Result<()> {
+    let mut temp_path = filepath.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    let result = fs::File::create(&temp_path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .and_then(|()| fs::rename(&temp_path, filepath));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result.with_context(|| format!("Failed to write to {}", filepath.display()))
+}
+
+/// Read a file by path, or failing that through the process using it; true if it
+/// could only be read through the process
+fn read_file_of_process(path: &Path, pid: u32) -> std::io::Result<(Vec<u8>, bool)> {
+    let err = match fs::read(path) {
+        Ok(bytes) => return Ok((bytes, false)),
+        Err(e) => e,
+    };
+
+    #[cfg(target_os = "linux")]
+    if let Some(bytes) = crate::capture::read_process_file(pid, path) {
+        return Ok((bytes, true));
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = pid;
+
+    Err(err)
+}
+
+/// The arguments in a response file, read quietly: nothing is saved or counted
+fn peek_response_file(path: &Path, pid: u32) -> Option<Vec<String>> {
+    let (bytes, _) = read_file_of_process(path, pid).ok()?;
+    Some(split_arguments(&join_lines(&decode_response_file(bytes))))
+}
+
+/// Text of a response file: UTF-8, or UTF-16 LE as MSVC tools often write, with or
+/// without a byte order mark; Windows-1252 and other bytes are read as lossy UTF-8
+fn decode_response_file(bytes: Vec<u8>) -> String {
+    let utf16 = |bytes: &[u8]| {
+        let units: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    };
+
+    match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => {
+            let bytes = e.into_bytes();
+            if bytes.starts_with(&[0xFF, 0xFE]) {
+                utf16(&bytes[2..])
+            } else if bytes.len() % 2 == 0 {
+                utf16(&bytes)
+            } else {
+                String::from_utf8_lossy(&bytes).to_string()
+            }
+        }
+    }
+}
+
+/// Response file lines joined into one command line fragment
+fn join_lines(contents: &str) -> String {
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Current start time of a process, in the clock the capture backends use
+fn read_start_time(pid: u32) -> Option<u64> {
+    #[cfg(any(target_os = "linux", windows))]
+    return crate::capture::read_process_start_time(pid);
+
+    #[cfg(not(any(target_os = "linux", windows)))]
+    {
+        let _ = pid;
+        None
+    }
+}
+
+/// Strip launchers and rewrite drivers, in any nesting (`ccache zig cc ...`)
+fn unwrap_invocation(executable: &str, argv: &[String]) -> Option<Invocation> {
+    let mut argv = argv.to_vec();
+    let mut launchers = Vec::new();
+    let mut drivers = Vec::new();
+
+    // The executable only tells anything about the outermost process
+    let mut executable = executable;
+
+    loop {
+        if let Some(unwrapped) = wrapper::unwrap_launcher(executable, &argv) {
+            let changed = unwrapped.argv != argv;
+            launchers.push(unwrapped.launcher);
+            argv = unwrapped.argv;
+            executable = "";
+            if changed {
+                continue;
+            }
+        }
+
+        match driver::rewrite_driver(&argv) {
+            Some(rewritten) => {
+                drivers.push(rewritten.driver);
+                argv = rewritten.argv;
+                executable = "";
+            }
+            None => break,
+        }
+    }
+
+    if launchers.is_empty() && drivers.is_empty() {
+        return None;
+    }
+
+    let join = |names: Vec<String>| (!names.is_empty()).then(|| names.join(" "));
+    Some(Invocation {
+        argv,
+        launcher: join(launchers),
+        driver: join(drivers),
+    })
+}
+
+/// Split a command line into argv, the inverse of `join_arguments`
+pub fn split_arguments(command: &str) -> Vec<String> {
+    // Simple argument parsing - split on spaces but respect quotes
+    // Backslash escapes are only honoured off Windows, where `join_arguments` emits them
+    let mut args = Vec::new();
+    let mut current_arg = String::new();
+    let mut in_quotes = false;
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if !cfg!(windows) => {
+                if let Some(next) = chars.next() {
+                    current_arg.push(next);
+                }
+            }
+            '"' => in_quotes = !in_quotes,
+            ' ' if !in_quotes => {
+                if !current_arg.is_empty() {
+                    args.push(current_arg.clone());
+                    current_arg.clear();
+                }
+            }
+            _ => current_arg.push(c),
+        }
+    }
+
+    if !current_arg.is_empty() {
+        args.push(current_arg);
+    }
+
+    args
+}
+
+/// Join an argv into a single command line string
+///
+/// Arguments containing anything beyond plain path characters are wrapped in double
+/// quotes. Off Windows, `"`, `\`, `$` and `` ` `` inside them are backslash-escaped so the
+/// result is also a valid POSIX shell command, as clangd expects on those platforms.
+pub fn join_arguments(argv: &[String]) -> String {
+    argv.iter()
+        .map(|arg| quote_argument(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn quote_argument(arg: &str) -> String {
+    let is_plain = |c: char| c.is_ascii_alphanumeric() || "_-+=/.,:@%^".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_plain) {
+        return arg.to_string();
+    }
+
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+    for c in arg.chars() {
+        if !cfg!(windows) && matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{argv, TempTree};
+
+    #[test]
+    fn plain_arguments_are_joined_as_they_are() {
+        let command = argv(&["gcc", "-DVERSION=2", "-I../include", "-o", "out/a.o", "-c", "a.c"]);
+        assert_eq!(join_arguments(&command), "gcc -DVERSION=2 -I../include -o out/a.o -c a.c");
+        assert_eq!(split_arguments(&join_arguments(&command)), command);
+    }
+
+    #[test]
+    fn spaces_survive_the_round_trip() {
+        let command = argv(&["clang", "-I/opt/My Includes", "-c", "my file.c", "-DEMPTY="]);
+        assert_eq!(join_arguments(&command), r#"clang "-I/opt/My Includes" -c "my file.c" -DEMPTY="#);
+        assert_eq!(split_arguments(&join_arguments(&command)), command);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn shell_characters_are_escaped_and_survive_the_round_trip() {
+        let command = argv(&["gcc", r#"-DNAME="x y""#, r"-DPATH=C:\dir", "-DCOST=$5", "-DCMD=`id`", "a.c"]);
+        assert_eq!(
+            join_arguments(&command),
+            r#"gcc "-DNAME=\"x y\"" "-DPATH=C:\\dir" "-DCOST=\$5" "-DCMD=\`id\`" a.c"#
+        );
+        assert_eq!(split_arguments(&join_arguments(&command)), command);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn backslashes_are_path_separators_on_windows() {
+        let command = argv(&[r"C:\VS\cl.exe", r"/IC:\Program Files\include", "/c", r"src\a.cpp"]);
+        assert_eq!(join_arguments(&command), r#""C:\VS\cl.exe" "/IC:\Program Files\include" /c "src\a.cpp""#);
+        assert_eq!(split_arguments(&join_arguments(&command)), command);
+    }
+
+    #[test]
+    fn splitting_skips_repeated_spaces() {
+        assert_eq!(split_arguments("  cc   -c  a.c "), argv(&["cc", "-c", "a.c"]));
+        assert!(split_arguments("").is_empty());
+    }
+
+    /// A compile of `a.c` in `directory` by the given process
+    fn compile(pid: u32, start_time: u64, directory: &str) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            start_time,
+            name: "gcc".to_string(),
+            executable: "/usr/bin/gcc".to_string(),
+            command_line: "gcc -c a.c".to_string(),
+            working_dir: directory.to_string(),
+            ..ProcessInfo::default()
+        }
+    }
+
+    fn cached(cache: &TempTree, number: u64) -> CachedCommand {
+        let path = cache.path().join(format!("cache/command_{:06}.json", number));
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn exits_complete_the_entries_of_their_compile() {
+        let tree = TempTree::new("monitor_exit", &["a.c", "cache/"]);
+        let directory = tree.path().to_string_lossy().to_string();
+        let monitor = CompilerMonitor::new(&["gcc".to_string()], tree.path().join("cache"))
+            .unwrap()
+            .with_quiet(true)
+            .with_exit_tracking(true);
+
+        assert!(monitor.process_creation_callback(&compile(4242, 7, &directory)).unwrap());
+        assert_eq!(cached(&tree, 1).exit_code, None);
+
+        // Same pid, but another process: the compile has not exited
+        monitor.process_exit_callback(ProcessId { pid: 4242, start_time: 8 }, 0).unwrap();
+        assert_eq!(cached(&tree, 1).exit_code, None);
+
+        monitor.process_exit_callback(ProcessId { pid: 4242, start_time: 7 }, 1).unwrap();
+        let entry = cached(&tree, 1);
+        assert_eq!(entry.exit_code, Some(1));
+        assert!(entry.duration_ms.is_some());
+        assert!(!entry.succeeded());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn subprocesses_of_a_recorded_driver_are_skipped_while_it_runs() {
+        let tree = TempTree::new("monitor_driver", &["a.c", "cache/"]);
+        let directory = tree.path().to_string_lossy().to_string();
+        let monitor = CompilerMonitor::new(&["gcc".to_string(), "cc1".to_string()], tree.path().join("cache"))
+            .unwrap()
+            .with_quiet(true);
+
+        // The test process stands in for the driver, so its start time can be checked
+        let driver_pid = std::process::id();
+        let driver_start = read_start_time(driver_pid).unwrap();
+        let cc1 = |pid| ProcessInfo {
+            pid,
+            parent_pid: Some(driver_pid),
+            name: "cc1".to_string(),
+            executable: "/usr/libexec/gcc/cc1".to_string(),
+            command_line: "cc1 -quiet a.c -o a.s".to_string(),
+            working_dir: directory.clone(),
+            ..ProcessInfo::default()
+        };
+
+        assert!(monitor.process_creation_callback(&compile(driver_pid, driver_start, &directory)).unwrap());
+        assert!(!monitor.process_creation_callback(&cc1(4243)).unwrap());
+        assert_eq!(monitor.stats().files_recorded, 1);
+
+        // Once the driver exited its pid may belong to anything
+        monitor.process_exit_callback(ProcessId { pid: driver_pid, start_time: driver_start }, 0).unwrap();
+        assert!(monitor.process_creation_callback(&cc1(4244)).unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn children_of_a_reused_driver_pid_are_recorded() {
+        let tree = TempTree::new("monitor_reused", &["a.c", "cache/"]);
+        let directory = tree.path().to_string_lossy().to_string();
+        let monitor = CompilerMonitor::new(&["gcc".to_string(), "cc1".to_string()], tree.path().join("cache"))
+            .unwrap()
+            .with_quiet(true);
+
+        // Recorded with another start time than the process now holding the pid
+        let pid = std::process::id();
+        let start_time = read_start_time(pid).unwrap() + 1;
+        assert!(monitor.process_creation_callback(&compile(pid, start_time, &directory)).unwrap());
+
+        let child = ProcessInfo {
+            pid: 4245,
+            parent_pid: Some(pid),
+            name: "cc1".to_string(),
+            executable: "/usr/libexec/gcc/cc1".to_string(),
+            command_line: "cc1 -quiet a.c -o a.s".to_string(),
+            working_dir: directory.clone(),
+            ..ProcessInfo::default()
+        };
+        assert!(monitor.process_creation_callback(&child).unwrap());
+    }
+
+    #[test]
+    fn the_idle_timeout_waits_for_running_compiles() {
+        let tree = TempTree::new("monitor_idle", &["a.c", "cache/"]);
+        let directory = tree.path().to_string_lossy().to_string();
+        let monitor = |idle_timeout| {
+            CompilerMonitor::new(&["gcc".to_string()], tree.path().join("cache"))
+                .unwrap()
+                .with_quiet(true)
+                .with_exit_tracking(true)
+                .with_idle_timeout(idle_timeout)
+        };
+
+        assert!(!monitor(None).idle_expired());
+        assert!(!monitor(Some(Duration::from_secs(3600))).idle_expired());
+
+        let idle = monitor(Some(Duration::ZERO));
+        assert!(idle.idle_expired());
+        idle.process_creation_callback(&compile(4246, 7, &directory)).unwrap();
+        assert!(!idle.idle_expired());
+        idle.process_exit_callback(ProcessId { pid: 4246, start_time: 7 }, 0).unwrap();
+        assert!(idle.idle_expired());
+
+        // Nor for one whose exit was lost
+        idle.process_creation_callback(&compile(4246, 8, &directory)).unwrap();
+        assert!(!idle.idle_expired());
+        idle.process_exit_lost(ProcessId { pid: 4246, start_time: 8 });
+        assert!(idle.idle_expired());
+        assert_eq!(cached(&tree, 2).exit_code, None);
+    }
+
+    #[test]
+    fn environment_options_follow_the_sources_in_response_files() {
+        let tree = TempTree::new("monitor_rsp_language", &["a.cpp", "cache/"]);
+        fs::write(tree.path().join("sources.rsp"), "-c\na.cpp\n").unwrap();
+        let directory = tree.path().to_string_lossy().to_string();
+        let monitor = CompilerMonitor::new(&["gcc".to_string()], tree.path().join("cache"))
+            .unwrap()
+            .with_quiet(true);
+
+        let process = ProcessInfo {
+            command_line: "gcc @sources.rsp".to_string(),
+            environment: BTreeMap::from([
+                ("C_INCLUDE_PATH".to_string(), "/opt/sys".to_string()),
+                ("CPLUS_INCLUDE_PATH".to_string(), "/opt/sys++".to_string()),
+            ]),
+            ..compile(4247, 7, &directory)
+        };
+        assert!(monitor.process_creation_callback(&process).unwrap());
+        assert_eq!(cached(&tree, 1).command.command, "gcc -c a.cpp -isystem /opt/sys++");
+    }
+
+    #[test]
+    fn source_less_queries_count_as_probes() {
+        let tree = TempTree::new("monitor_query", &["cache/"]);
+        let directory = tree.path().to_string_lossy().to_string();
+        let monitor = CompilerMonitor::new(&["gcc".to_string()], tree.path().join("cache"))
+            .unwrap()
+            .with_quiet(true);
+        let run = |command_line: &str| {
+            let process = ProcessInfo {
+                command_line: command_line.to_string(),
+                ..compile(4248, 7, &directory)
+            };
+            monitor.process_creation_callback(&process).unwrap()
+        };
+
+        assert!(!run("gcc -dumpversion"));
+        assert!(!run("gcc -c"));
+        let stats = monitor.stats();
+        assert_eq!(stats.probes, 1);
+        assert_eq!(stats.files_recorded, 0);
+    }
+}
@@ -0,0 +1,23 @@
+// Program names
+//
+// Launchers, drivers, build tools and compiler subprocesses are all recognized by
+// the name of the program they run, taken from argv[0], an executable path or a
+// process name. Any of these may be a Windows path even when recording on Linux
+// (cross builds, Wine), so both separators are understood everywhere, and names
+// are compared without case and `.exe`.
+
+/// File name of a path, the whole string if it has no `/` or `\`
+pub fn file_name(path: &str) -> &str {
+    path.rsplit(['/', '\\']).next().unwrap_or(path)
+}
+
+/// Lowercase file name without directory and `.exe` extension
+pub fn program_stem(path: &str) -> String {
+    let file_name = file_name(path).to_lowercase();
+    file_name.strip_suffix(".exe").map(str::to_string).unwrap_or(file_name)
+}
+
+/// Whether a program takes cl.exe-style options (`/I`, `/Fo`, ...)
+pub fn is_cl_style(path: &str) -> bool {
+    matches!(program_stem(path).as_str(), "cl" | "clang-cl")
+}
@@ -0,0 +1,338 @@
+// Process patterns
+//
+// `--pattern` selects which processes are compiler invocations. It can be given
+// several times; a process is captured if any pattern matches. Each pattern is an
+// executable matcher optionally followed by argv matchers, separated by `::`:
+//
+//   cl.exe                           glob over the executable name
+//   C:\VS2022\**\cl.exe              glob over the full executable path (has a separator)
+//   re:^clang(\+\+|-cl)?(\.exe)?$    regex, searched in both the name and the full path
+//   python3*::-m::*mycc*             name glob plus arguments that must all be present
+//
+// Globs are anchored. `*` and `?` stay within one path component, `**` crosses
+// separators and `[...]` is a character class; `/` and `\` are interchangeable.
+// Executable globs ignore case, argv globs do not (`-D` and `-d` are different
+// options). Regexes are used as written, so anchoring and case are up to the
+// user. An argv matcher (glob or `re:` regex) is satisfied when any argument
+// after argv[0] matches it.
+
+use anyhow::{Context, Result};
+use regex::Regex;
+
+/// Separates the executable matcher from argv matchers
+const ARGV_SEPARATOR: &str = "::";
+
+/// Prefix of the regex form
+const REGEX_PREFIX: &str = "re:";
+
+/// Patterns used when none are given
+///
+/// Cross-compiler prefixes (`aarch64-linux-gnu-gcc`) and version suffixes (`gcc-13`,
+/// `clang-17`) are allowed, but not the binutils wrappers sharing the name
+/// (`gcc-ar`, `gcc-nm`, `gcc-ranlib`) or clang's tools (`clang-format`, `clang-tidy`).
+#[cfg(windows)]
+pub const DEFAULT_PATTERNS: &[&str] = &["cl.exe"];
+#[cfg(not(windows))]
+pub const DEFAULT_PATTERNS: &[&str] = &[
+    "cc",
+    "c++",
+    "*gcc",
+    "*gcc-[0-9]*",
+    "*g++",
+    "*g++-[0-9]*",
+    "clang",
+    "clang++",
+    "clang-[0-9]*",
+    "clang++-[0-9]*",
+];
+
+/// The patterns a monitor captures
+#[derive(Debug)]
+pub struct PatternSet {
+    patterns: Vec<ProcessPattern>,
+    sources: Vec<String>,
+}
+
+#[derive(Debug)]
+struct ProcessPattern {
+    executable: ExecutableMatcher,
+    argv: Vec<Regex>,
+}
+
+#[derive(Debug)]
+enum ExecutableMatcher {
+    /// Glob over the executable's file name
+    Name(Regex),
+    /// Glob over the full executable path
+    Path(Regex),
+    /// Regex searched in the name and the full path
+    Regex(Regex),
+}
+
+impl PatternSet {
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        let parsed = patterns
+            .iter()
+            .map(|pattern| {
+                ProcessPattern::parse(pattern)
+                    .with_context(|| format!("Invalid pattern '{}'", pattern))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if parsed.is_empty() {
+            anyhow::bail!("No process pattern given");
+        }
+
+        Ok(Self {
+            patterns: parsed,
+            sources: patterns.to_vec(),
+        })
+    }
+
+    /// The patterns as given on the command line
+    pub fn sources(&self) -> &[String] {
+        &self.sources
+    }
+
+    /// Cheap pre-check before argv and the executable path are read: false only
+    /// if no pattern can match a process with this name
+    pub fn may_match_name(&self, process_name: &str) -> bool {
+        self.patterns.iter().any(|pattern| match &pattern.executable {
+            ExecutableMatcher::Name(glob) => glob.is_match(process_name),
+            ExecutableMatcher::Path(_) | ExecutableMatcher::Regex(_) => true,
+        })
+    }
+
+    /// Whether a process is captured
+    ///
+    /// `executable` is the full path when known, otherwise whatever the backend has
+    /// (argv[0] or the name); `argv` includes argv[0].
+    pub fn matches(&self, process_name: &str, executable: &str, argv: &[String]) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| pattern.matches(process_name, executable, argv))
+    }
+}
+
+impl ProcessPattern {
+    fn parse(pattern: &str) -> Result<Self> {
+        let mut parts = pattern.split(ARGV_SEPARATOR);
+        let executable = parts.next().unwrap_or_default();
+        if executable.is_empty() {
+            anyhow::bail!("Missing executable matcher");
+        }
+
+        let executable = if let Some(regex) = executable.strip_prefix(REGEX_PREFIX) {
+            ExecutableMatcher::Regex(Regex::new(regex).context("Invalid regex")?)
+        } else if executable.contains(['/', '\\']) {
+            ExecutableMatcher::Path(glob_to_regex(executable)?)
+        } else {
+            ExecutableMatcher::Name(glob_to_regex(executable)?)
+        };
+
+        let argv = parts
+            .map(|matcher| match matcher.strip_prefix(REGEX_PREFIX) {
+                Some(regex) => Regex::new(regex).context("Invalid argument regex"),
+                None => glob_to_case_sensitive_regex(matcher),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { executable, argv })
+    }
+
+    fn matches(&self, process_name: &str, executable: &str, argv: &[String]) -> bool {
+        let executable_matches = match &self.executable {
+            ExecutableMatcher::Name(glob) => glob.is_match(process_name),
+            ExecutableMatcher::Path(glob) => glob.is_match(executable),
+            ExecutableMatcher::Regex(regex) => {
+                regex.is_match(process_name) || regex.is_match(executable)
+            }
+        };
+
+        executable_matches
+            && self
+                .argv
+                .iter()
+                .all(|matcher| argv.iter().skip(1).any(|arg| matcher.is_match(arg)))
+    }
+}
+
+/// Translate a glob into an anchored, case-insensitive regex
+pub fn glob_to_regex(glob: &str) -> Result<Regex> {
+    Regex::new(&format!("(?i){}", glob_pattern(glob)?)).context("Failed to compile glob")
+}
+
+/// Translate a glob into an anchored regex that respects case, for arguments
+/// (`-D` and `-d` are different options)
+pub fn glob_to_case_sensitive_regex(glob: &str) -> Result<Regex> {
+    Regex::new(&glob_pattern(glob)?).context("Failed to compile glob")
+}
+
+/// Regex source for a glob, anchored at both ends
+fn glob_pattern(glob: &str) -> Result<String> {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str(r"[^/\\]*"),
+            '?' => regex.push_str(r"[^/\\]"),
+            '/' | '\\' => regex.push_str(r"[/\\]"),
+            '[' => {
+                let mut class = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == ']' && !class.is_empty() && class != "!" {
+                        closed = true;
+                        break;
+                    }
+                    class.push(c);
+                }
+                if !closed {
+                    anyhow::bail!("Unclosed '[' in glob");
+                }
+                let class = match class.strip_prefix('!') {
+                    Some(negated) => format!("^{}", negated),
+                    None => class,
+                };
+                regex.push('[');
+                regex.push_str(&class.replace('\\', r"\\").replace('[', r"\["));
+                regex.push(']');
+            }
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    regex.push('$');
+    Ok(regex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::argv;
+
+    fn set(patterns: &[&str]) -> PatternSet {
+        PatternSet::new(&patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn globs_are_anchored_and_ignore_case() {
+        let glob = glob_to_regex("cl.exe").unwrap();
+        assert!(glob.is_match("cl.exe"));
+        assert!(glob.is_match("CL.EXE"));
+        assert!(!glob.is_match("clang-cl.exe"));
+        assert!(!glob.is_match("cl.exe.bak"));
+        assert!(!glob.is_match("clXexe"));
+    }
+
+    #[test]
+    fn stars_stay_within_one_component() {
+        let glob = glob_to_regex(r"C:\VS\*\cl.exe").unwrap();
+        assert!(glob.is_match(r"C:\VS\bin\cl.exe"));
+        assert!(glob.is_match("C:/VS/bin/cl.exe"));
+        assert!(!glob.is_match(r"C:\VS\bin\Hostx64\cl.exe"));
+
+        let glob = glob_to_regex(r"C:\VS\**\cl.exe").unwrap();
+        assert!(glob.is_match(r"C:\VS\bin\Hostx64\x64\cl.exe"));
+    }
+
+    #[test]
+    fn question_marks_and_classes() {
+        let glob = glob_to_regex("gcc-?").unwrap();
+        assert!(glob.is_match("gcc-9"));
+        assert!(!glob.is_match("gcc-13"));
+
+        let glob = glob_to_regex("clang-[0-9]*").unwrap();
+        assert!(glob.is_match("clang-17"));
+        assert!(!glob.is_match("clang-format"));
+
+        let glob = glob_to_regex("g[!c]c").unwrap();
+        assert!(glob.is_match("gxc"));
+        assert!(!glob.is_match("gcc"));
+
+        let glob = glob_to_regex("[]]").unwrap();
+        assert!(glob.is_match("]"));
+
+        assert!(glob_to_regex("gcc[0-9").is_err());
+    }
+
+    #[test]
+    fn other_characters_are_literal() {
+        let glob = glob_to_regex("c++").unwrap();
+        assert!(glob.is_match("c++"));
+        assert!(!glob.is_match("cc"));
+        assert!(!glob.is_match("c+++"));
+    }
+
+    #[test]
+    fn case_sensitive_globs_keep_case() {
+        let glob = glob_to_case_sensitive_regex("-D*").unwrap();
+        assert!(glob.is_match("-DNDEBUG"));
+        assert!(!glob.is_match("-dM"));
+    }
+
+    #[test]
+    fn names_paths_and_regexes() {
+        let patterns = set(&["cl.exe", "/opt/**/bin/gcc", r"re:^clang(\+\+)?$"]);
+        assert!(patterns.matches("cl.exe", r"C:\VS\cl.exe", &argv(&["cl"])));
+        assert!(patterns.matches("gcc", "/opt/cross/arm/bin/gcc", &argv(&["gcc"])));
+        assert!(!patterns.matches("gcc", "/usr/bin/gcc", &argv(&["gcc"])));
+        assert!(patterns.matches("clang++", "/usr/bin/clang++", &argv(&["clang++"])));
+        assert!(!patterns.matches("clang-tidy", "/usr/bin/clang-tidy", &argv(&["clang-tidy"])));
+    }
+
+    #[test]
+    fn argv_matchers_must_all_be_present_after_argv0() {
+        let patterns = set(&["python3*::-m::*mycc*"]);
+        let matches = |args: &[&str]| patterns.matches("python3.12", "/usr/bin/python3.12", &argv(args));
+        assert!(matches(&["python3", "-m", "mycc.main", "a.c"]));
+        assert!(!matches(&["python3", "-m", "pip"]));
+        assert!(!matches(&["mycc", "-m"]));
+    }
+
+    #[test]
+    fn argv_globs_respect_case() {
+        let patterns = set(&["GCC::-D*"]);
+        assert!(patterns.matches("gcc", "/usr/bin/gcc", &argv(&["gcc", "-DNDEBUG", "a.c"])));
+        assert!(!patterns.matches("gcc", "/usr/bin/gcc", &argv(&["gcc", "-dM", "-E", "a.c"])));
+    }
+
+    #[test]
+    fn name_pre_check() {
+        let patterns = set(&["gcc"]);
+        assert!(patterns.may_match_name("gcc"));
+        assert!(!patterns.may_match_name("make"));
+
+        // Path and regex patterns need the full path to decide
+        assert!(set(&["gcc", "/usr/**/cc1"]).may_match_name("make"));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!(PatternSet::new(&[]).is_err());
+        assert!(PatternSet::new(&["::-c".to_string()]).is_err());
+        assert!(PatternSet::new(&["re:(".to_string()]).is_err());
+        assert!(PatternSet::new(&["gcc::re:[".to_string()]).is_err());
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn default_patterns_take_compilers_but_not_their_tools() {
+        let patterns = set(DEFAULT_PATTERNS);
+        let matches = |name: &str| patterns.matches(name, name, &argv(&[name]));
+        for name in ["cc", "c++", "gcc", "g++", "gcc-13", "g++-13", "aarch64-linux-gnu-gcc", "x86_64-w64-mingw32-g++"] {
+            assert!(matches(name), "{} should match", name);
+        }
+        for name in ["clang", "clang++", "clang-17", "clang++-17"] {
+            assert!(matches(name), "{} should match", name);
+        }
+        for name in ["gcc-ar", "gcc-nm", "gcc-ranlib", "clang-format", "clang-tidy", "ld", "make"] {
+            assert!(!matches(name), "{} should not match", name);
+        }
+    }
+}
@@ -0,0 +1,242 @@
+// Build-system probe compiles
+//
+// Configure steps compile throwaway programs to learn about the toolchain: CMake's
+// compiler identification and try_compile, autoconf's conftest.c, Meson's sanity
+// checks, and macro dumps such as `gcc -E -dM`. They are real compiler
+// invocations, but their entries only clutter compile_commands.json.
+//
+// Queries such as `-dumpversion`, `--version` or `/?` name no source file, so never
+// become entries. The rules are still checked for them, with an empty source file,
+// so that they count as probes instead of being reported as commands without
+// sources.
+//
+// Probes are recognized at record time and tagged in the cache with the name of
+// the rule that matched; `collect` leaves them out unless asked to include them.
+// A rule is one or more conditions separated by `::`, all of which must hold:
+//
+//   file:<glob>    a source file path
+//   dir:<glob>     the working directory
+//   arg:<glob>     any argument
+//
+// Globs follow the `--pattern` syntax (`**` spans directories); `file:` and `dir:`
+// ignore case, `arg:` does not.
+
+use crate::pattern::{glob_to_case_sensitive_regex, glob_to_regex};
+use anyhow::{Context, Result};
+use clap::Args;
+use regex::Regex;
+
+/// Rules applied unless `--no-builtin-probe-rules` is given: (name, rule)
+const BUILTIN_RULES: &[(&str, &str)] = &[
+    ("cmake try_compile", "file:**/CMakeFiles/CMakeScratch/**"),
+    ("cmake try_compile", "file:**/CMakeFiles/CMakeTmp/**"),
+    ("cmake compiler id", "file:**/CMakeFiles/*/CompilerId*/**"),
+    ("cmake compiler abi", "file:**/CMake*CompilerABI.*"),
+    ("autoconf conftest", "file:**/conftest.*"),
+    ("meson sanity check", "file:**/meson-private/**"),
+    ("macro dump", "arg:-E::arg:-dM"),
+    ("version query", "arg:--version"),
+    ("version query", "arg:-dumpversion"),
+    ("version query", "arg:-dumpfullversion"),
+    ("target query", "arg:-dumpmachine"),
+    ("search path query", "arg:-print-*"),
+    ("search path query", "arg:--print-*"),
+    ("help query", "arg:--help"),
+    ("help query", "arg:/[?]"),
+    ("help query", "arg:-[?]"),
+];
+
+/// `record` options controlling probe detection
+#[derive(Args, Debug, Default)]
+pub struct ProbeArgs {
+    /// Additional rule tagging compiles as probes: conditions "file:GLOB", "dir:GLOB"
+    /// or "arg:GLOB", joined with "::" (repeatable)
+    #[arg(long, value_name = "RULE")]
+    probe_rule: Vec<String>,
+
+    /// Only use the rules given with --probe-rule
+    #[arg(long)]
+    no_builtin_probe_rules: bool,
+}
+
+/// Rules deciding which compiles are probes
+#[derive(Debug)]
+pub struct ProbeRules {
+    rules: Vec<ProbeRule>,
+}
+
+#[derive(Debug)]
+struct ProbeRule {
+    name: String,
+    conditions: Vec<Condition>,
+}
+
+#[derive(Debug)]
+enum Condition {
+    File(Regex),
+    Dir(Regex),
+    Arg(Regex),
+}
+
+impl Default for ProbeRules {
+    fn default() -> Self {
+        let rules = BUILTIN_RULES
+            .iter()
+            .map(|(name, rule)| ProbeRule::parse(name, rule).expect("built-in probe rule"))
+            .collect();
+        Self { rules }
+    }
+}
+
+impl ProbeRules {
+    pub fn from_args(args: ProbeArgs) -> Result<Self> {
+        let mut rules = if args.no_builtin_probe_rules {
+            Vec::new()
+        } else {
+            Self::default().rules
+        };
+
+        for rule in &args.probe_rule {
+            rules.push(
+                ProbeRule::parse(rule, rule)
+                    .with_context(|| format!("Invalid probe rule '{}'", rule))?,
+            );
+        }
+
+        Ok(Self { rules })
+    }
+
+    /// Name of the first rule tagging this compile as a probe
+    pub fn classify(&self, source_file: &str, working_dir: &str, argv: &[String]) -> Option<&str> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(source_file, working_dir, argv))
+            .map(|rule| rule.name.as_str())
+    }
+}
+
+impl ProbeRule {
+    fn parse(name: &str, rule: &str) -> Result<Self> {
+        let conditions = rule
+            .split("::")
+            .map(|condition| {
+                let (kind, glob) = condition
+                    .split_once(':')
+                    .context("Expected file:, dir: or arg: condition")?;
+                match kind {
+                    "file" => Ok(Condition::File(glob_to_regex(glob)?)),
+                    "dir" => Ok(Condition::Dir(glob_to_regex(glob)?)),
+                    "arg" => Ok(Condition::Arg(glob_to_case_sensitive_regex(glob)?)),
+                    _ => anyhow::bail!("Unknown condition '{}:'", kind),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            name: name.to_string(),
+            conditions,
+        })
+    }
+
+    fn matches(&self, source_file: &str, working_dir: &str, argv: &[String]) -> bool {
+        self.conditions.iter().all(|condition| match condition {
+            Condition::File(glob) => glob.is_match(source_file),
+            Condition::Dir(glob) => glob.is_match(working_dir),
+            Condition::Arg(glob) => argv.iter().skip(1).any(|arg| glob.is_match(arg)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::argv;
+
+    fn rules(probe_rule: &[&str], no_builtin_probe_rules: bool) -> ProbeRules {
+        ProbeRules::from_args(ProbeArgs {
+            probe_rule: probe_rule.iter().map(|rule| rule.to_string()).collect(),
+            no_builtin_probe_rules,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn builtin_rules_tag_configure_probes() {
+        let rules = ProbeRules::default();
+        let classify = |source: &str| rules.classify(source, "/src/build", &argv(&["cc", "-c", source]));
+
+        assert_eq!(
+            classify("/src/build/CMakeFiles/CMakeScratch/TryCompile-abc/src.c"),
+            Some("cmake try_compile")
+        );
+        assert_eq!(
+            classify(r"C:\src\build\CMakeFiles\3.28.1\CompilerIdC\CMakeCCompilerId.c"),
+            Some("cmake compiler id")
+        );
+        assert_eq!(
+            classify("/usr/share/cmake-3.28/Modules/CMakeCXXCompilerABI.cpp"),
+            Some("cmake compiler abi")
+        );
+        assert_eq!(classify("/src/conftest.c"), Some("autoconf conftest"));
+        assert_eq!(classify("/src/build/meson-private/sanitycheckc.c"), Some("meson sanity check"));
+        assert_eq!(classify("/src/lib/conf.c"), None);
+        assert_eq!(classify("/src/CMakeFiles.c"), None);
+    }
+
+    #[test]
+    fn macro_dumps_need_both_arguments_in_their_case() {
+        let rules = ProbeRules::default();
+        let classify = |args: &[&str]| rules.classify("/tmp/empty.c", "/tmp", &argv(args));
+
+        assert_eq!(classify(&["gcc", "-E", "-dM", "/tmp/empty.c"]), Some("macro dump"));
+        assert_eq!(classify(&["gcc", "-E", "/tmp/empty.c"]), None);
+        // -dm is not a macro dump, and -e is an entry point
+        assert_eq!(classify(&["gcc", "-e", "-dm", "/tmp/empty.c"]), None);
+    }
+
+    #[test]
+    fn source_less_queries() {
+        let rules = ProbeRules::default();
+        let classify = |args: &[&str]| rules.classify("", "/tmp", &argv(args));
+
+        assert_eq!(classify(&["gcc", "-dumpversion"]), Some("version query"));
+        assert_eq!(classify(&["clang", "--version"]), Some("version query"));
+        assert_eq!(classify(&["gcc", "-print-search-dirs"]), Some("search path query"));
+        assert_eq!(classify(&["cl.exe", "/?"]), Some("help query"));
+        assert_eq!(classify(&["gcc", "-E", "-dM", "-"]), Some("macro dump"));
+        // `?` is not a wildcard here
+        assert_eq!(classify(&["cl.exe", "/c"]), None);
+        assert_eq!(classify(&["gcc"]), None);
+    }
+
+    #[test]
+    fn custom_rules_combine_conditions() {
+        let rules = rules(&["dir:**/probes::arg:-DPROBE*"], true);
+        let classify = |dir: &str, args: &[&str]| rules.classify("a.c", dir, &argv(args));
+
+        assert_eq!(classify("/src/probes", &["cc", "-DPROBE=1", "a.c"]), Some("dir:**/probes::arg:-DPROBE*"));
+        assert_eq!(classify("/src/probes", &["cc", "-dprobe", "a.c"]), None);
+        assert_eq!(classify("/src", &["cc", "-DPROBE=1", "a.c"]), None);
+        // Builtin rules are off
+        assert_eq!(rules.classify("/src/conftest.c", "/src", &argv(&["cc"])), None);
+    }
+
+    #[test]
+    fn argv0_is_not_an_argument() {
+        let rules = rules(&["arg:*clang*"], true);
+        assert_eq!(rules.classify("a.c", "/src", &argv(&["clang", "-c", "a.c"])), None);
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let parse = |rule: &str| {
+            ProbeRules::from_args(ProbeArgs {
+                probe_rule: vec![rule.to_string()],
+                no_builtin_probe_rules: false,
+            })
+        };
+        assert!(parse("conftest.c").is_err());
+        assert!(parse("path:conftest.c").is_err());
+        assert!(parse("file:[").is_err());
+    }
+}
@@ -0,0 +1,414 @@
+// Capture scope filters
+//
+// A system-wide backend sees every matching process on the machine, including
+// those of parallel builds and other users' sessions. The scope filter narrows
+// capture down to one build: processes descended from a given pid or command,
+// owned by a given user, running under a directory prefix or in a cgroup. Each
+// kind of filter that is given must match; several values of one kind are
+// alternatives.
+//
+// Backends evaluate the scope before handing a process to
+// `process_creation_callback`, so out-of-scope compiles never touch the cache. A
+// working directory that could not be read is checked by the monitor instead, once
+// it has been recovered from the command's paths.
+// Facts about the process (parent, owner, cgroup) are queried through
+// `ProcessLookup`, implemented per OS next to the backends.
+
+use crate::names::program_stem;
+use anyhow::{Context, Result};
+use clap::Args;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Ancestor walks stop here, in case parent pids were reused into a cycle
+const MAX_ANCESTOR_DEPTH: usize = 256;
+
+/// `record` options restricting which processes are captured
+#[derive(Args, Debug, Default)]
+pub struct ScopeArgs {
+    /// Only capture descendants of this process id (repeatable)
+    #[arg(long, value_name = "PID")]
+    under_pid: Vec<u32>,
+
+    /// Only capture descendants of a process with this name, e.g. "ninja"; a value with
+    /// spaces matches ancestor command lines containing it, e.g. "ninja -C out/a" (repeatable)
+    #[arg(long, value_name = "COMMAND")]
+    under_command: Vec<String>,
+
+    /// Only capture processes owned by this user (name or numeric uid)
+    #[arg(long)]
+    user: Option<String>,
+
+    /// Only capture processes whose working directory is under this directory (repeatable)
+    #[arg(long, value_name = "DIR")]
+    cwd_prefix: Vec<PathBuf>,
+
+    /// Only capture processes in this cgroup or below it, e.g. "/user.slice/build.scope" (repeatable)
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "PATH")]
+    cgroup: Vec<String>,
+}
+
+/// OS queries needed to evaluate a scope filter
+pub trait ProcessLookup {
+    /// Parent process id, if the process still exists
+    fn parent(&self, pid: u32) -> Option<u32>;
+
+    /// Executable name
+    fn name(&self, pid: u32) -> Option<String>;
+
+    /// Full command line
+    fn command_line(&self, pid: u32) -> Option<String>;
+
+    /// Current working directory
+    fn working_dir(&self, pid: u32) -> Option<String>;
+
+    /// Owner, in the form `ScopeFilter` resolves `--user` to (uid on Linux,
+    /// `DOMAIN\user` on Windows)
+    fn user(&self, pid: u32) -> Option<String>;
+
+    /// Control groups the process belongs to
+    fn cgroups(&self, _pid: u32) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Whether the process leads its session (a terminal's shell, an ssh login),
+    /// above which nothing belongs to a build
+    fn is_session_leader(&self, _pid: u32) -> bool {
+        false
+    }
+}
+
+/// Resolved scope restrictions; the default captures everything
+#[derive(Debug, Default)]
+pub struct ScopeFilter {
+    under_pids: Vec<u32>,
+    under_commands: Vec<String>,
+    user: Option<String>,
+    cwd_prefixes: Vec<PathBuf>,
+    cgroups: Vec<String>,
+}
+
+impl ScopeFilter {
+    pub fn from_args(args: ScopeArgs) -> Result<Self> {
+        let user = args.user.as_deref().map(resolve_user).transpose()?;
+
+        let cwd_prefixes = args
+            .cwd_prefix
+            .iter()
+            .map(|dir| {
+                dir.canonicalize()
+                    .map(strip_verbatim_prefix)
+                    .or_else(|_| std::path::absolute(dir))
+                    .with_context(|| format!("Failed to resolve {}", dir.display()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        #[cfg(target_os = "linux")]
+        let cgroups = args.cgroup.iter().map(|cgroup| normalize_cgroup(cgroup)).collect();
+        #[cfg(not(target_os = "linux"))]
+        let cgroups = Vec::new();
+
+        Ok(Self {
+            under_pids: args.under_pid,
+            under_commands: args.under_command,
+            user,
+            cwd_prefixes,
+            cgroups,
+        })
+    }
+
+    /// Whether no restriction is configured
+    pub fn is_empty(&self) -> bool {
+        self.under_pids.is_empty()
+            && self.under_commands.is_empty()
+            && self.user.is_none()
+            && self.cwd_prefixes.is_empty()
+            && self.cgroups.is_empty()
+    }
+
+    /// Whether a process is inside the configured scope
+    ///
+    /// Cheap checks come first, the ancestor walk last.
+    pub fn allows(&self, pid: u32, working_dir: &str, lookup: &dyn ProcessLookup) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        // An unknown directory is left to the monitor, see `allows_directory`
+        if !working_dir.is_empty() && !self.allows_directory(working_dir) {
+            return false;
+        }
+
+        if let Some(user) = &self.user {
+            match lookup.user(pid) {
+                Some(owner) if user_matches(&owner, user) => {}
+                _ => return false,
+            }
+        }
+
+        if !self.cgroups.is_empty() {
+            let cgroups = lookup.cgroups(pid);
+            let in_cgroup = self.cgroups.iter().any(|wanted| {
+                cgroups
+                    .iter()
+                    .any(|cgroup| Path::new(cgroup).starts_with(Path::new(wanted)))
+            });
+            if !in_cgroup {
+                return false;
+            }
+        }
+
+        if !self.under_pids.is_empty() || !self.under_commands.is_empty() {
+            return self.has_matching_ancestor(pid, lookup);
+        }
+
+        true
+    }
+
+    /// Whether a working directory is under one of the `--cwd-prefix` directories,
+    /// always true without any
+    pub fn allows_directory(&self, working_dir: &str) -> bool {
+        self.cwd_prefixes.is_empty() || self.cwd_prefixes.iter().any(|prefix| path_has_prefix(working_dir, prefix))
+    }
+
+    fn has_matching_ancestor(&self, pid: u32, lookup: &dyn ProcessLookup) -> bool {
+        let mut visited = HashSet::from([pid]);
+        let mut current = pid;
+
+        for _ in 0..MAX_ANCESTOR_DEPTH {
+            let Some(parent) = lookup.parent(current) else {
+                return false;
+            };
+            if parent == 0 || !visited.insert(parent) {
+                return false;
+            }
+
+            if self.under_pids.contains(&parent) {
+                return true;
+            }
+            if !self.under_commands.is_empty() && self.is_matching_command(parent, lookup) {
+                return true;
+            }
+
+            current = parent;
+        }
+
+        false
+    }
+
+    fn is_matching_command(&self, pid: u32, lookup: &dyn ProcessLookup) -> bool {
+        let name = lookup.name(pid).unwrap_or_default();
+        let stem = program_stem(&name);
+        let mut command_line = None;
+
+        self.under_commands.iter().any(|wanted| {
+            if wanted.contains(char::is_whitespace) {
+                command_line
+                    .get_or_insert_with(|| lookup.command_line(pid).unwrap_or_default())
+                    .contains(wanted.as_str())
+            } else if cfg!(windows) {
+                name.eq_ignore_ascii_case(wanted) || stem.eq_ignore_ascii_case(wanted)
+            } else {
+                name == *wanted
+            }
+        })
+    }
+}
+
+impl fmt::Display for ScopeFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        parts.extend(self.under_pids.iter().map(|pid| format!("under pid {}", pid)));
+        parts.extend(self.under_commands.iter().map(|command| format!("under \"{}\"", command)));
+        parts.extend(self.user.iter().map(|user| format!("user {}", user)));
+        parts.extend(self.cwd_prefixes.iter().map(|dir| format!("cwd in {}", dir.display())));
+        parts.extend(self.cgroups.iter().map(|cgroup| format!("cgroup {}", cgroup)));
+
+        if parts.is_empty() {
+            write!(f, "all processes")
+        } else {
+            write!(f, "{}", parts.join(", "))
+        }
+    }
+}
+
+/// `\\?\C:\dir` to `C:\dir` and `\\?\UNC\server\share` to `\\server\share`
+///
+/// `canonicalize` returns these verbatim paths on Windows, while working directories
+/// are read in the usual form.
+fn strip_verbatim_prefix(path: PathBuf) -> PathBuf {
+    let text = path.to_string_lossy();
+    if let Some(share) = text.strip_prefix(r"\\?\UNC\") {
+        return PathBuf::from(format!(r"\\{}", share));
+    }
+    match text.strip_prefix(r"\\?\") {
+        Some(plain) => PathBuf::from(plain),
+        None => path.clone(),
+    }
+}
+
+fn path_has_prefix(working_dir: &str, prefix: &Path) -> bool {
+    if cfg!(windows) {
+        let dir = PathBuf::from(working_dir.to_lowercase());
+        dir.starts_with(prefix.to_string_lossy().to_lowercase())
+    } else {
+        Path::new(working_dir).starts_with(prefix)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn resolve_user(user: &str) -> Result<String> {
+    if user.parse::<u32>().is_ok() {
+        return Ok(user.to_string());
+    }
+
+    let name = std::ffi::CString::new(user).context("Invalid user name")?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        anyhow::bail!("Unknown user '{}'", user);
+    }
+    Ok(unsafe { (*passwd).pw_uid }.to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resolve_user(user: &str) -> Result<String> {
+    Ok(user.to_string())
+}
+
+/// Compare a process owner with the wanted user; on Windows the domain is optional
+fn user_matches(owner: &str, wanted: &str) -> bool {
+    if cfg!(windows) {
+        let account = owner.rsplit('\\').next().unwrap_or(owner);
+        owner.eq_ignore_ascii_case(wanted) || account.eq_ignore_ascii_case(wanted)
+    } else {
+        owner == wanted
+    }
+}
+
+/// Accept cgroup paths relative to the hierarchy root or under /sys/fs/cgroup
+#[cfg(target_os = "linux")]
+fn normalize_cgroup(cgroup: &str) -> String {
+    let relative = cgroup
+        .strip_prefix("/sys/fs/cgroup")
+        .unwrap_or(cgroup)
+        .trim_matches('/');
+    format!("/{}", relative)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockLookup, MockProcess};
+
+    /// bash (100) > ninja -C out/a (200) > sh (300) > cc (400); cc in /src/out/a,
+    /// owned by uid 1000, in the build.scope cgroup
+    fn lookup() -> MockLookup {
+        MockLookup::default()
+            .with(100, MockProcess::new(1, "bash"))
+            .with(200, MockProcess::new(100, "ninja -C out/a"))
+            .with(300, MockProcess::new(200, "sh -c cc"))
+            .with(
+                400,
+                MockProcess::new(300, "cc -c a.c")
+                    .with_user("1000")
+                    .with_cgroup("/user.slice/user-1000.slice/build.scope"),
+            )
+    }
+
+    fn filter() -> ScopeFilter {
+        ScopeFilter::default()
+    }
+
+    #[test]
+    fn empty_scope_allows_everything() {
+        assert!(filter().is_empty());
+        assert!(filter().allows(999, "", &MockLookup::default()));
+        assert_eq!(filter().to_string(), "all processes");
+    }
+
+    #[test]
+    fn ancestors_by_pid_and_command() {
+        let lookup = lookup();
+        let under = |scope: ScopeFilter| scope.allows(400, "/src/out/a", &lookup);
+
+        assert!(under(ScopeFilter { under_pids: vec![200], ..filter() }));
+        assert!(under(ScopeFilter { under_pids: vec![7, 100], ..filter() }));
+        // The process itself is not its own ancestor
+        assert!(!under(ScopeFilter { under_pids: vec![400], ..filter() }));
+
+        assert!(under(ScopeFilter { under_commands: vec!["ninja".to_string()], ..filter() }));
+        assert!(under(ScopeFilter { under_commands: vec!["ninja -C out/a".to_string()], ..filter() }));
+        assert!(!under(ScopeFilter { under_commands: vec!["ninja -C out/b".to_string()], ..filter() }));
+        assert!(!under(ScopeFilter { under_commands: vec!["make".to_string()], ..filter() }));
+    }
+
+    #[test]
+    fn ancestor_walk_survives_cycles() {
+        let lookup = MockLookup::default()
+            .with(10, MockProcess::new(20, "sh"))
+            .with(20, MockProcess::new(10, "sh"));
+        assert!(!ScopeFilter { under_pids: vec![30], ..filter() }.allows(10, "/", &lookup));
+    }
+
+    #[test]
+    fn user() {
+        let lookup = lookup();
+        assert!(ScopeFilter { user: Some("1000".to_string()), ..filter() }.allows(400, "/", &lookup));
+        assert!(!ScopeFilter { user: Some("0".to_string()), ..filter() }.allows(400, "/", &lookup));
+        // Unknown owner
+        assert!(!ScopeFilter { user: Some("1000".to_string()), ..filter() }.allows(999, "/", &lookup));
+    }
+
+    #[test]
+    fn cwd_prefix() {
+        let scope = ScopeFilter { cwd_prefixes: vec![PathBuf::from("/src/out")], ..filter() };
+        assert!(scope.allows(400, "/src/out/a", &lookup()));
+        assert!(scope.allows(400, "/src/out", &lookup()));
+        assert!(!scope.allows(400, "/src/outside", &lookup()));
+        assert!(!scope.allows(400, "/tmp", &lookup()));
+
+        // Unreadable directories are checked by the monitor once recovered
+        assert!(scope.allows(400, "", &lookup()));
+        assert!(!scope.allows_directory("/tmp"));
+        assert!(filter().allows_directory("/tmp"));
+    }
+
+    #[test]
+    fn cgroup() {
+        let scope = |cgroup: &str| ScopeFilter { cgroups: vec![cgroup.to_string()], ..filter() };
+        assert!(scope("/user.slice").allows(400, "/", &lookup()));
+        assert!(scope("/user.slice/user-1000.slice/build.scope").allows(400, "/", &lookup()));
+        assert!(!scope("/user.slice/user-1000.slice/build").allows(400, "/", &lookup()));
+        assert!(!scope("/system.slice").allows(400, "/", &lookup()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn cgroup_paths_are_normalized() {
+        assert_eq!(normalize_cgroup("/sys/fs/cgroup/user.slice/"), "/user.slice");
+        assert_eq!(normalize_cgroup("user.slice"), "/user.slice");
+    }
+
+    #[test]
+    fn all_given_kinds_must_match() {
+        let scope = ScopeFilter {
+            under_pids: vec![200],
+            user: Some("1000".to_string()),
+            cwd_prefixes: vec![PathBuf::from("/src")],
+            ..filter()
+        };
+        assert!(scope.allows(400, "/src/out/a", &lookup()));
+        assert!(!scope.allows(400, "/elsewhere", &lookup()));
+        assert!(!scope.allows(300, "/src", &lookup()));
+        assert_eq!(scope.to_string(), "under pid 200, user 1000, cwd in /src");
+    }
+
+    #[test]
+    fn verbatim_prefixes_are_stripped() {
+        assert_eq!(strip_verbatim_prefix(PathBuf::from(r"\\?\C:\src")), PathBuf::from(r"C:\src"));
+        assert_eq!(strip_verbatim_prefix(PathBuf::from(r"\\?\UNC\server\share")), PathBuf::from(r"\\server\share"));
+        assert_eq!(strip_verbatim_prefix(PathBuf::from("/src")), PathBuf::from("/src"));
+    }
+}
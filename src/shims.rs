@@ -0,0 +1,281 @@
+// PATH shim farm
+//
+// `compiler_monitor shims install` fills a directory with links named after common
+// compilers (cc, gcc, g++, clang, cl, ...), all pointing at this binary, plus a small
+// config file recording the cache directory. With that directory first on PATH, a
+// build that hardcodes `cc` or `cl` runs us instead. We notice from argv[0] that we
+// were started as a shim, find the next real compiler of that name further down
+// PATH (skipping every shim directory) and hand over to it in launcher mode: the
+// invocation is recorded, then the real compiler runs with the same stdio and
+// exit code.
+
+use crate::launch;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Config file marking a directory as a shim directory
+const SHIM_CONFIG: &str = ".compiler_monitor_shims.json";
+
+/// Set for compilers started through a shim, so nested lookups of the same name
+/// (e.g. ccache in masquerade mode finding our shim again) are not recorded twice
+pub const SHIM_ACTIVE_ENV: &str = "COMPILER_MONITOR_SHIM_ACTIVE";
+
+/// Compiler names installed when none are given
+#[cfg(windows)]
+pub const DEFAULT_SHIM_NAMES: &[&str] = &["cl", "clang-cl", "clang", "clang++", "gcc", "g++"];
+#[cfg(not(windows))]
+pub const DEFAULT_SHIM_NAMES: &[&str] = &["cc", "c++", "gcc", "g++", "clang", "clang++"];
+
+/// How shim entries refer to the compiler_monitor binary
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShimMode {
+    /// Symbolic links (default on Unix)
+    Symlink,
+    /// Hard links (default on Windows, where symlinks need extra privileges)
+    Hardlink,
+    /// Plain copies of the binary
+    Copy,
+}
+
+impl ShimMode {
+    pub fn platform_default() -> Self {
+        if cfg!(windows) {
+            ShimMode::Hardlink
+        } else {
+            ShimMode::Symlink
+        }
+    }
+}
+
+/// Contents of the shim directory's config file
+#[derive(Debug, Serialize, Deserialize)]
+struct ShimConfig {
+    cache_dir: PathBuf,
+}
+
+/// Create the shim directory
+pub fn install(dir: &Path, cache_dir: &Path, names: &[String], mode: ShimMode) -> Result<()> {
+    let exe = std::env::current_exe().context("Failed to locate compiler_monitor executable")?;
+
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create shim directory {}", dir.display()))?;
+    let dir = dir
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {}", dir.display()))?;
+
+    // Shims run in arbitrary directories, so the cache must be absolute
+    let cache_dir = std::path::absolute(cache_dir).context("Failed to resolve cache directory")?;
+
+    for name in names {
+        let shim = dir.join(executable_name(name));
+
+        if shim.symlink_metadata().is_ok() {
+            fs::remove_file(&shim)
+                .with_context(|| format!("Failed to replace {}", shim.display()))?;
+        }
+
+        create_shim(&exe, &shim, mode)
+            .with_context(|| format!("Failed to create shim {}", shim.display()))?;
+        println!("  ✓ {}", shim.display());
+    }
+
+    let config = ShimConfig { cache_dir: cache_dir.clone() };
+    let json = serde_json::to_string_pretty(&config).context("Failed to serialize shim config")?;
+    fs::write(dir.join(SHIM_CONFIG), json).context("Failed to write shim config")?;
+
+    println!();
+    println!("✓ Installed {} shim(s) in {}", names.len(), dir.display());
+    println!("✓ Invocations will be recorded to {}", cache_dir.display());
+    println!();
+    println!("Put the shim directory first on PATH for the duration of the build:");
+    if cfg!(windows) {
+        println!("  set PATH={};%PATH%", dir.display());
+    } else {
+        println!("  export PATH=\"{}:$PATH\"", dir.display());
+    }
+
+    Ok(())
+}
+
+fn create_shim(exe: &Path, shim: &Path, mode: ShimMode) -> std::io::Result<()> {
+    match mode {
+        #[cfg(unix)]
+        ShimMode::Symlink => std::os::unix::fs::symlink(exe, shim),
+        #[cfg(windows)]
+        ShimMode::Symlink => std::os::windows::fs::symlink_file(exe, shim),
+        ShimMode::Hardlink => fs::hard_link(exe, shim),
+        ShimMode::Copy => fs::copy(exe, shim).map(|_| ()),
+    }
+}
+
+fn executable_name(name: &str) -> String {
+    if cfg!(windows) && !name.to_lowercase().ends_with(".exe") {
+        format!("{}.exe", name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// If we were started through a shim, the compiler name and shim directory
+pub fn invoked_as_shim() -> Option<(String, PathBuf)> {
+    let argv0 = PathBuf::from(std::env::args_os().next()?);
+    shim_invocation(&argv0, &path_entries())
+}
+
+/// The compiler name and shim directory for an `argv0`, searching `path` for bare names
+fn shim_invocation(argv0: &Path, path: &[PathBuf]) -> Option<(String, PathBuf)> {
+    let name = argv0.file_name()?.to_string_lossy().to_string();
+
+    if name.to_lowercase().starts_with("compiler_monitor") {
+        return None;
+    }
+
+    // With a path in argv[0] the shim is right there; otherwise the shell found it on
+    // PATH, where on Windows argv[0] usually lacks the `.exe` the file has
+    let dir = if argv0.components().count() > 1 {
+        argv0.parent()?.to_path_buf()
+    } else {
+        path.iter()
+            .find(|dir| dir.join(executable_name(&name)).is_file())?
+            .clone()
+    };
+
+    if dir.join(SHIM_CONFIG).is_file() {
+        let compiler = Path::new(&name).file_stem()?.to_string_lossy().to_string();
+        Some((compiler, dir))
+    } else {
+        None
+    }
+}
+
+/// Record the invocation and run the real compiler; returns its exit code
+pub fn run_shim(compiler: &str, shim_dir: &Path) -> Result<i32> {
+    let real_compiler = find_real_compiler(compiler, &path_entries())
+        .with_context(|| format!("compiler_monitor: no real '{}' found on PATH after the shims", compiler))?;
+
+    let mut command = vec![real_compiler.to_string_lossy().to_string()];
+    command.extend(std::env::args().skip(1));
+
+    if std::env::var_os(SHIM_ACTIVE_ENV).is_some() {
+        return launch::exec_compiler(&command);
+    }
+    std::env::set_var(SHIM_ACTIVE_ENV, "1");
+
+    let cache_dir = match std::env::var_os("COMPILER_MONITOR_CACHE") {
+        Some(dir) => PathBuf::from(dir),
+        None => match read_config(shim_dir) {
+            Ok(config) => config.cache_dir,
+            Err(e) => {
+                // Like a failed recording, a broken shim directory must not fail the compile
+                eprintln!("compiler_monitor: warning: failed to record invocation: {:#}", e);
+                return launch::exec_compiler(&command);
+            }
+        },
+    };
+
+    launch::launch(&cache_dir, &command, launch::full_environment_requested())
+}
+
+fn read_config(shim_dir: &Path) -> Result<ShimConfig> {
+    let path = shim_dir.join(SHIM_CONFIG);
+    let contents = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&contents)
+        .with_context(|| format!("Failed to parse JSON from {}", path.display()))
+}
+
+/// The first `compiler` in the `path` directories that is not one of our shims
+fn find_real_compiler(compiler: &str, path: &[PathBuf]) -> Option<PathBuf> {
+    let own_exe = std::env::current_exe().ok().and_then(|exe| exe.canonicalize().ok());
+    let file_name = executable_name(compiler);
+
+    path.iter().find_map(|dir| {
+        // Hardlinked or copied shims do not resolve to our binary, but their directory is marked
+        if dir.join(SHIM_CONFIG).is_file() {
+            return None;
+        }
+
+        let candidate = dir.join(&file_name);
+        if !candidate.is_file() {
+            return None;
+        }
+
+        let resolved = candidate.canonicalize().ok()?;
+        if Some(&resolved) == own_exe.as_ref() {
+            return None;
+        }
+
+        Some(candidate)
+    })
+}
+
+fn path_entries() -> Vec<PathBuf> {
+    std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).collect())
+        .unwrap_or_else(|| std::env::split_paths(&OsString::new()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempTree;
+
+    const MARKED: &str = "shims/.compiler_monitor_shims.json";
+
+    #[test]
+    fn executable_names() {
+        if cfg!(windows) {
+            assert_eq!(executable_name("gcc"), "gcc.exe");
+            assert_eq!(executable_name("CL.EXE"), "CL.EXE");
+        } else {
+            assert_eq!(executable_name("gcc"), "gcc");
+            assert_eq!(executable_name("cl.exe"), "cl.exe");
+        }
+    }
+
+    #[test]
+    fn shims_are_recognized_by_their_directory() {
+        let gcc = executable_name("gcc");
+        let tree = TempTree::new("shim_invocation", &[MARKED, &format!("shims/{}", gcc), &format!("bin/{}", gcc)]);
+        let shims = tree.path().join("shims");
+        let bin = tree.path().join("bin");
+
+        assert_eq!(shim_invocation(&shims.join(&gcc), &[]), Some(("gcc".to_string(), shims.clone())));
+        // Found on PATH, where the first directory with the name wins
+        assert_eq!(shim_invocation(Path::new("gcc"), &[shims.clone(), bin.clone()]), Some(("gcc".to_string(), shims.clone())));
+        assert_eq!(shim_invocation(Path::new("gcc"), &[bin.clone(), shims.clone()]), None);
+
+        assert_eq!(shim_invocation(&bin.join(&gcc), &[]), None);
+        assert_eq!(shim_invocation(&shims.join("compiler_monitor"), &[]), None);
+    }
+
+    #[test]
+    fn real_compilers_are_found_after_the_shims() {
+        let gcc = executable_name("gcc");
+        let tree = TempTree::new(
+            "real_compiler",
+            &[MARKED, &format!("shims/{}", gcc), "empty/", &format!("bin/{}", gcc), &format!("later/{}", gcc)],
+        );
+        let dir = |name: &str| tree.path().join(name);
+
+        let path = [dir("shims"), dir("empty"), dir("bin"), dir("later")];
+        assert_eq!(find_real_compiler("gcc", &path), Some(dir("bin").join(&gcc)));
+        assert_eq!(find_real_compiler("gcc", &[dir("shims")]), None);
+        assert_eq!(find_real_compiler("clang", &path), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_to_our_own_binary_are_skipped_outside_shim_directories() {
+        let tree = TempTree::new("own_binary", &["self/", "bin/gcc"]);
+        let own = tree.path().join("self/gcc");
+        std::os::unix::fs::symlink(std::env::current_exe().unwrap(), &own).unwrap();
+
+        let path = [tree.path().join("self"), tree.path().join("bin")];
+        assert_eq!(find_real_compiler("gcc", &path), Some(tree.path().join("bin/gcc")));
+    }
+}
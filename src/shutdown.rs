@@ -0,0 +1,80 @@
+// Ctrl+C handling for `record`
+//
+// The first Ctrl+C (SIGINT/SIGTERM on Linux, a console control event on Windows) only
+// sets a flag. Backends check it between batches of work, finish what they are
+// processing and return, so no command file is left half written and the session
+// summary can be printed. A second Ctrl+C exits immediately, as it would without
+// the handler.
+
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by the first Ctrl+C
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Exit code of a process ended by SIGINT
+#[cfg(target_os = "linux")]
+const INTERRUPTED_EXIT_CODE: i32 = 128 + libc::SIGINT;
+
+/// Whether the user asked recording to stop
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Ask recording to stop as if Ctrl+C was pressed, e.g. from the control socket
+pub fn request_stop() {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Turn Ctrl+C into a stop request for the rest of the process lifetime
+#[cfg(target_os = "linux")]
+pub fn install_handler() -> Result<()> {
+    extern "C" fn on_signal(_signal: libc::c_int) {
+        if STOP_REQUESTED.swap(true, Ordering::SeqCst) {
+            unsafe { libc::_exit(INTERRUPTED_EXIT_CODE) };
+        }
+    }
+
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        unsafe {
+            // No SA_RESTART: blocking calls return EINTR and get to check the flag
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, std::ptr::null_mut()) < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Turn Ctrl+C into a stop request for the rest of the process lifetime
+#[cfg(windows)]
+pub fn install_handler() -> Result<()> {
+    use windows::Win32::Foundation::{BOOL, FALSE, TRUE};
+    use windows::Win32::System::Console::{SetConsoleCtrlHandler, CTRL_BREAK_EVENT, CTRL_C_EVENT};
+
+    unsafe extern "system" fn on_control(control: u32) -> BOOL {
+        if control != CTRL_C_EVENT && control != CTRL_BREAK_EVENT {
+            return FALSE;
+        }
+        // Handled the first time; the second falls through to the default handler,
+        // which ends the process
+        if STOP_REQUESTED.swap(true, Ordering::SeqCst) {
+            FALSE
+        } else {
+            TRUE
+        }
+    }
+
+    unsafe { SetConsoleCtrlHandler(Some(on_control), TRUE)? };
+    Ok(())
+}
+
+/// Turn Ctrl+C into a stop request for the rest of the process lifetime
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn install_handler() -> Result<()> {
+    Ok(())
+}
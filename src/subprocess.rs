@@ -0,0 +1,55 @@
+// Compiler driver subprocesses
+//
+// A compiler driver does its work through child processes: `g++ -c a.cpp` runs
+// `cc1plus` and `as`, links go through `collect2` and `ld`, `clang` re-executes
+// itself with `-cc1`, and `cl /MP` starts one child `cl.exe` per batch of sources
+// with synthetic argv. Event-based and tracing backends see all of them, and with
+// a broad pattern they would be recorded next to the driver invocation the user
+// actually wrote.
+//
+// `CompilerMonitor` remembers every driver it recorded an entry for, by pid and
+// start time, for a while; a process whose parent is still that driver (the pid
+// not reused since) and that looks like one of its internal steps is dropped.
+
+use crate::names::program_stem;
+
+/// Executables drivers run internally (frontends, assembler, linker steps)
+const INTERNAL_TOOLS: &[&str] = &[
+    "cc1", "cc1plus", "cc1obj", "cc1objplus", "f951", "lto1", "lto-wrapper", "as", "collect2",
+    "ld", "ld.bfd", "ld.gold", "ld.lld", "ld.mold", "link",
+];
+
+/// Arguments marking clang's internal re-execution of itself
+const CLANG_INTERNAL_MODES: &[&str] = &["-cc1", "-cc1as"];
+
+/// Whether a child of a captured driver is one of its internal steps
+pub fn is_driver_subprocess(driver_name: &str, child_name: &str, child_argv: &[String]) -> bool {
+    let child = program_stem(child_name);
+
+    INTERNAL_TOOLS.contains(&child.as_str())
+        || child == program_stem(driver_name)
+        || child_argv
+            .get(1)
+            .is_some_and(|arg| CLANG_INTERNAL_MODES.contains(&arg.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::argv;
+
+    #[test]
+    fn internal_tools_and_reexecutions_are_subprocesses() {
+        assert!(is_driver_subprocess("g++", "cc1plus", &argv(&["cc1plus", "-quiet", "a.cpp"])));
+        assert!(is_driver_subprocess("gcc", "as", &argv(&["as", "--64", "-o", "a.o"])));
+        assert!(is_driver_subprocess("clang", "clang-17", &argv(&["clang-17", "-cc1", "-triple"])));
+        // cl /MP runs cl.exe again for each batch of sources
+        assert!(is_driver_subprocess("cl.exe", "CL.EXE", &argv(&["cl.exe", "@batch.rsp"])));
+    }
+
+    #[test]
+    fn other_children_are_not() {
+        assert!(!is_driver_subprocess("gcc", "clang", &argv(&["clang", "-c", "a.c"])));
+        assert!(!is_driver_subprocess("make", "gcc", &argv(&["gcc", "-c", "a.c"])));
+    }
+}
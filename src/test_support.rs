@@ -0,0 +1,135 @@
+// Helpers shared by the unit tests
+
+use crate::scope::ProcessLookup;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// An argv from string literals
+pub fn argv(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/// A directory tree under the system temp directory, removed on drop
+pub struct TempTree(PathBuf);
+
+impl TempTree {
+    /// Create empty `files` (directories when ending in `/`) under a directory
+    /// unique to `name` and this test process
+    pub fn new(name: &str, files: &[&str]) -> Self {
+        let root = std::env::temp_dir().join(format!("compiler_monitor_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        for file in files {
+            let path = root.join(file);
+            if file.ends_with('/') {
+                fs::create_dir_all(&path).unwrap();
+            } else {
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(&path, "").unwrap();
+            }
+        }
+        Self(root)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn dir(&self, relative: &str) -> String {
+        self.0.join(relative).to_string_lossy().to_string()
+    }
+}
+
+impl Drop for TempTree {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A process of a `MockLookup`
+#[derive(Default, Clone)]
+pub struct MockProcess {
+    pub parent: u32,
+    pub name: String,
+    pub command_line: String,
+    pub working_dir: String,
+    pub user: String,
+    pub cgroups: Vec<String>,
+    pub session_leader: bool,
+}
+
+impl MockProcess {
+    pub fn new(parent: u32, command_line: &str) -> Self {
+        let name = command_line.split(' ').next().unwrap_or_default();
+        Self {
+            parent,
+            name: crate::names::file_name(name).to_string(),
+            command_line: command_line.to_string(),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_working_dir(mut self, working_dir: &str) -> Self {
+        self.working_dir = working_dir.to_string();
+        self
+    }
+
+    pub fn with_user(mut self, user: &str) -> Self {
+        self.user = user.to_string();
+        self
+    }
+
+    pub fn with_cgroup(mut self, cgroup: &str) -> Self {
+        self.cgroups.push(cgroup.to_string());
+        self
+    }
+
+    pub fn session_leader(mut self) -> Self {
+        self.session_leader = true;
+        self
+    }
+}
+
+/// A process table for code that queries processes through `ProcessLookup`
+#[derive(Default)]
+pub struct MockLookup {
+    processes: HashMap<u32, MockProcess>,
+}
+
+impl MockLookup {
+    pub fn with(mut self, pid: u32, process: MockProcess) -> Self {
+        self.processes.insert(pid, process);
+        self
+    }
+}
+
+impl ProcessLookup for MockLookup {
+    fn parent(&self, pid: u32) -> Option<u32> {
+        self.processes.get(&pid).map(|process| process.parent)
+    }
+
+    fn name(&self, pid: u32) -> Option<String> {
+        self.processes.get(&pid).map(|process| process.name.clone())
+    }
+
+    fn command_line(&self, pid: u32) -> Option<String> {
+        self.processes.get(&pid).map(|process| process.command_line.clone())
+    }
+
+    fn working_dir(&self, pid: u32) -> Option<String> {
+        self.processes.get(&pid).map(|process| process.working_dir.clone())
+    }
+
+    fn user(&self, pid: u32) -> Option<String> {
+        self.processes.get(&pid).map(|process| process.user.clone())
+    }
+
+    fn cgroups(&self, pid: u32) -> Vec<String> {
+        self.processes.get(&pid).map(|process| process.cgroups.clone()).unwrap_or_default()
+    }
+
+    fn is_session_leader(&self, pid: u32) -> bool {
+        self.processes.get(&pid).is_some_and(|process| process.session_leader)
+    }
+}
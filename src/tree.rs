@@ -0,0 +1,313 @@
+// Process tree of a compile and the build target it belongs to
+//
+// Backends record the chain of parent processes of each compile while it runs,
+// nearest first, up to the build root: the outermost build tool, or without one
+// the session leader (the terminal's shell) or the process that started `run`.
+// Parents usually outlive their compiles, but the chain has to be read at capture
+// time all the same: by `collect` they are long gone.
+//
+// Only build tools keep their command line and working directory in the cache.
+// Other ancestors (shells, terminals, IDEs, CI agents) are recorded by pid and
+// name, as their command lines are unrelated to the build and may hold secrets.
+// Their working directories are still read, for `workdir` to try at capture time.
+//
+// The nearest build tool in the chain names the build target the compile belongs
+// to, which `collect` can list and filter on:
+//
+//   make <dir>       the (recursive) make invocation, by its working directory;
+//                    also nmake, jom and mingw32-make
+//   ninja <dir>      the ninja invocation, by its working directory
+//   msbuild <name>   the MSBuild project, from FileTracker's log directory or the
+//                    project file msbuild was given; "msbuild node <pid>" for a
+//                    worker node whose project is not known
+
+use crate::monitor::split_arguments;
+use crate::names::program_stem;
+use crate::scope::ProcessLookup;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Longest parent chain recorded
+const MAX_PARENT_DEPTH: usize = 64;
+
+/// Programs that run builds, whose command lines are recorded
+const BUILD_TOOLS: &[&str] = &[
+    "make",
+    "gmake",
+    "mingw32-make",
+    "nmake",
+    "jom",
+    "ninja",
+    "samu",
+    "tracker",
+    "msbuild",
+    "cmake",
+];
+
+/// Project file extensions msbuild is given
+const MSBUILD_PROJECT_EXTENSIONS: &[&str] = &["sln", "slnx", "vcxproj", "csproj", "proj", "vcproj"];
+
+/// An ancestor of a captured process
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParentProcess {
+    pub pid: u32,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub command_line: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub working_dir: String,
+}
+
+/// Ancestors of a process, nearest first, up to the build root
+///
+/// The walk stops at a session leader, init and our own process; what is above the
+/// outermost build tool is then dropped. Command lines are only read for build tools.
+pub fn parent_chain(pid: u32, lookup: &dyn ProcessLookup) -> Vec<ParentProcess> {
+    let own_pid = std::process::id();
+    let mut visited = HashSet::from([pid]);
+    let mut parents = Vec::new();
+    let mut current = pid;
+
+    while parents.len() < MAX_PARENT_DEPTH {
+        let Some(parent) = lookup.parent(current) else {
+            break;
+        };
+        if parent <= 1 || parent == own_pid || !visited.insert(parent) {
+            break;
+        }
+
+        let name = lookup.name(parent).unwrap_or_default();
+        let command_line = match is_build_tool(&name) {
+            true => lookup.command_line(parent).unwrap_or_default(),
+            false => String::new(),
+        };
+        parents.push(ParentProcess {
+            pid: parent,
+            name,
+            command_line,
+            working_dir: lookup.working_dir(parent).unwrap_or_default(),
+        });
+
+        if lookup.is_session_leader(parent) {
+            break;
+        }
+        current = parent;
+    }
+
+    if let Some(outermost) = parents.iter().rposition(|parent| is_build_tool(&parent.name)) {
+        parents.truncate(outermost + 1);
+    }
+    parents
+}
+
+/// The chain as stored in the cache: the working directories of ancestors other
+/// than build tools are left out
+pub fn recorded_chain(parents: &[ParentProcess]) -> Vec<ParentProcess> {
+    parents
+        .iter()
+        .map(|parent| ParentProcess {
+            working_dir: match is_build_tool(&parent.name) {
+                true => parent.working_dir.clone(),
+                false => String::new(),
+            },
+            ..parent.clone()
+        })
+        .collect()
+}
+
+fn is_build_tool(name: &str) -> bool {
+    BUILD_TOOLS.contains(&program_stem(name).as_str())
+}
+
+/// The build target a compile belongs to, from the nearest build tool among its parents
+pub fn build_target(parents: &[ParentProcess]) -> Option<String> {
+    parents.iter().find_map(|parent| {
+        let tool = program_stem(&parent.name);
+        let argv = split_arguments(&parent.command_line);
+
+        match tool.as_str() {
+            "make" | "gmake" | "mingw32-make" | "nmake" | "jom" => {
+                Some(format!("{} {}", tool, tool_directory(parent, &argv)))
+            }
+            "ninja" | "samu" => Some(format!("ninja {}", tool_directory(parent, &argv))),
+            "tracker" => tracker_project(&argv).map(|project| format!("msbuild {}", project)),
+            "msbuild" => Some(match msbuild_project(&argv) {
+                Some(project) => format!("msbuild {}", project),
+                None => format!("msbuild node {}", parent.pid),
+            }),
+            _ => None,
+        }
+    })
+}
+
+/// Directory a make or ninja runs in
+///
+/// Both change into their `-C` directory before running anything, so the working
+/// directory already reflects it; the argument is only a fallback.
+fn tool_directory(parent: &ParentProcess, argv: &[String]) -> String {
+    if !parent.working_dir.is_empty() {
+        return parent.working_dir.clone();
+    }
+
+    let mut directory = String::new();
+    let mut args = argv.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let change = match arg.as_str() {
+            "-C" | "--directory" => args.next().cloned(),
+            _ => arg
+                .strip_prefix("--directory=")
+                .or_else(|| arg.strip_prefix("-C"))
+                .map(str::to_string),
+        };
+        if let Some(change) = change {
+            directory = Path::new(&directory).join(change).to_string_lossy().to_string();
+        }
+    }
+
+    directory
+}
+
+/// Project name from FileTracker's `/i <IntDir>\<project>.tlog` argument
+fn tracker_project(argv: &[String]) -> Option<String> {
+    let position = argv.iter().position(|arg| arg.eq_ignore_ascii_case("/i"))?;
+    let tlog_dir = argv.get(position + 1)?.trim_end_matches(['\\', '/']);
+    let name = tlog_dir.rsplit(['\\', '/']).next()?;
+    Some(name.strip_suffix(".tlog").unwrap_or(name).to_string())
+}
+
+/// Project or solution file given to msbuild
+fn msbuild_project(argv: &[String]) -> Option<String> {
+    argv.iter().skip(1).find_map(|arg| {
+        let extension = Path::new(arg).extension()?.to_string_lossy().to_lowercase();
+        (!arg.starts_with(['/', '-']) && MSBUILD_PROJECT_EXTENSIONS.contains(&extension.as_str()))
+            .then(|| arg.clone())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{argv, MockLookup, MockProcess};
+
+    /// Above any pid_max, so never our own process
+    const BASE: u32 = 5_000_000;
+
+    /// sshd > bash (session leader) > make > make -C lib > sh > gcc
+    fn build_under_make() -> MockLookup {
+        MockLookup::default()
+            .with(BASE + 1, MockProcess::new(1, "sshd: user@pts/0"))
+            .with(BASE + 2, MockProcess::new(BASE + 1, "bash").session_leader().with_working_dir("/home/user"))
+            .with(BASE + 3, MockProcess::new(BASE + 2, "make -j8 TOKEN=secret").with_working_dir("/src"))
+            .with(BASE + 4, MockProcess::new(BASE + 3, "make -C lib").with_working_dir("/src/lib"))
+            .with(BASE + 5, MockProcess::new(BASE + 4, "sh -c gcc -c a.c").with_working_dir("/src/lib"))
+            .with(BASE + 6, MockProcess::new(BASE + 5, "gcc -c a.c").with_working_dir("/src/lib"))
+    }
+
+    fn parent(name: &str, command_line: &str, working_dir: &str) -> ParentProcess {
+        ParentProcess {
+            pid: 0,
+            name: name.to_string(),
+            command_line: command_line.to_string(),
+            working_dir: working_dir.to_string(),
+        }
+    }
+
+    #[test]
+    fn chain_ends_at_the_outermost_build_tool() {
+        let chain = parent_chain(BASE + 6, &build_under_make());
+        let summary: Vec<_> = chain
+            .iter()
+            .map(|parent| (parent.pid - BASE, parent.name.as_str(), parent.command_line.as_str()))
+            .collect();
+        assert_eq!(summary, [(5, "sh", ""), (4, "make", "make -C lib"), (3, "make", "make -j8 TOKEN=secret")]);
+        assert_eq!(chain[0].working_dir, "/src/lib");
+    }
+
+    #[test]
+    fn chain_without_build_tool_ends_at_the_session_leader() {
+        let lookup = build_under_make()
+            .with(BASE + 7, MockProcess::new(BASE + 2, "sh -c cc -c a.c"))
+            .with(BASE + 8, MockProcess::new(BASE + 7, "cc -c a.c"));
+        let chain = parent_chain(BASE + 8, &lookup);
+        let summary: Vec<_> = chain
+            .iter()
+            .map(|parent| (parent.pid - BASE, parent.name.as_str(), parent.command_line.as_str()))
+            .collect();
+        assert_eq!(summary, [(7, "sh", ""), (2, "bash", "")]);
+    }
+
+    #[test]
+    fn chain_stops_at_init_unknown_processes_and_cycles() {
+        let lookup = MockLookup::default()
+            .with(BASE + 1, MockProcess::new(1, "systemd --user"))
+            .with(BASE + 2, MockProcess::new(BASE + 1, "cc"));
+        assert_eq!(parent_chain(BASE + 2, &lookup).len(), 1);
+        assert!(parent_chain(BASE + 9, &lookup).is_empty());
+
+        let lookup = MockLookup::default()
+            .with(BASE + 1, MockProcess::new(BASE + 2, "sh"))
+            .with(BASE + 2, MockProcess::new(BASE + 1, "sh"))
+            .with(BASE + 3, MockProcess::new(BASE + 2, "cc"));
+        assert_eq!(parent_chain(BASE + 3, &lookup).len(), 2);
+    }
+
+    #[test]
+    fn recorded_chain_keeps_directories_of_build_tools_only() {
+        let recorded = recorded_chain(&parent_chain(BASE + 6, &build_under_make()));
+        let directories: Vec<_> = recorded.iter().map(|parent| parent.working_dir.as_str()).collect();
+        assert_eq!(directories, ["", "/src/lib", "/src"]);
+    }
+
+    #[test]
+    fn build_target_is_named_by_the_nearest_build_tool() {
+        let chain = parent_chain(BASE + 6, &build_under_make());
+        assert_eq!(build_target(&chain).as_deref(), Some("make /src/lib"));
+
+        let chain = [parent("sh", "", "/out"), parent("ninja", "ninja -C out", "/src/out")];
+        assert_eq!(build_target(&chain).as_deref(), Some("ninja /src/out"));
+        assert_eq!(build_target(&[parent("samu.exe", "samu", "C:\\out")]).as_deref(), Some("ninja C:\\out"));
+
+        assert_eq!(build_target(&[parent("sh", "", "/"), parent("bash", "", "/")]), None);
+        assert_eq!(build_target(&[]), None);
+    }
+
+    #[test]
+    fn build_target_of_msbuild() {
+        // Recorded command lines are parsed like our own, where `\` escapes off Windows
+        let tracker = parent(
+            "Tracker.exe",
+            "Tracker.exe /d FileTracker.dll /i C:/src/obj/Debug/app.tlog /r C:/src/a.cpp /c cl.exe",
+            "",
+        );
+        let msbuild = parent("MSBuild.exe", "MSBuild.exe C:/src/app.sln /m /p:Configuration=Debug", "");
+        assert_eq!(build_target(&[tracker, msbuild.clone()]).as_deref(), Some("msbuild app"));
+        assert_eq!(build_target(&[msbuild]).as_deref(), Some("msbuild C:/src/app.sln"));
+
+        let mut node = parent("MSBuild.exe", "MSBuild.exe /nodemode:1 /nodeReuse:true", "");
+        node.pid = 4242;
+        assert_eq!(build_target(&[node]).as_deref(), Some("msbuild node 4242"));
+    }
+
+    #[test]
+    fn tool_directory_prefers_the_working_directory() {
+        let make = parent("make", "make -C lib", "/src/lib");
+        assert_eq!(tool_directory(&make, &argv(&["make", "-C", "lib"])), "/src/lib");
+
+        let unknown = parent("make", "", "");
+        assert_eq!(tool_directory(&unknown, &argv(&["make", "-C", "/src", "-Clib", "--directory=sub"])), "/src/lib/sub");
+        assert_eq!(tool_directory(&unknown, &argv(&["ninja", "--directory", "out"])), "out");
+        assert_eq!(tool_directory(&unknown, &argv(&["make", "all"])), "");
+    }
+
+    #[test]
+    fn tracker_project_from_the_tlog_directory() {
+        assert_eq!(
+            tracker_project(&argv(&["Tracker.exe", "/I", r"C:\src\obj\x64\Debug\zlib.tlog\", "/c", "cl.exe"])).as_deref(),
+            Some("zlib")
+        );
+        assert_eq!(tracker_project(&argv(&["Tracker.exe", "/i", "obj/plain"])).as_deref(), Some("plain"));
+        assert_eq!(tracker_project(&argv(&["Tracker.exe", "/i"])), None);
+        assert_eq!(tracker_project(&argv(&["Tracker.exe", "/c", "cl.exe"])), None);
+    }
+}
@@ -0,0 +1,342 @@
+// Working directory recovery
+//
+// A backend cannot always read a process's working directory: it may lack access
+// to the process, or the process may be gone by the time it is looked at. The
+// directory is what relative source paths, include directories and outputs are
+// resolved against, so guessing wrong breaks the whole entry.
+//
+// Instead, the directory is inferred from where the command's relative paths
+// exist. Candidates are, in order of preference:
+//
+// - the working directories of the parent processes, nearest first: the process
+//   that ran the compiler, and further up the build tools that started it
+// - the build root, the directory of the outermost build tool (see tree.rs), which
+//   ends the parent chain; generated build files often name paths relative to it
+// - the directories of response files given by absolute path
+//
+// Response files are read for their arguments, a relative one from under each
+// candidate, where finding it counts in the candidate's favour. The candidate under
+// which the most relative sources, include directories and output directories exist
+// wins. If none of them fits, the entry is marked as unresolved; either way the
+// cache records that the directory was not observed.
+
+use crate::names::is_cl_style;
+use crate::tree::ParentProcess;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Extensions of source files checked for existence
+const SOURCE_EXTENSIONS: &[&str] = &["c", "cpp", "cc", "cxx", "c++", "m", "mm"];
+
+/// Include directory options of all drivers
+const INCLUDE_OPTIONS: &[&str] = &["-I", "-isystem", "-iquote", "-idirafter"];
+
+/// Include directory options of cl-style drivers only; for others these are
+/// absolute paths such as `/Include/foo.c`
+const CL_INCLUDE_OPTIONS: &[&str] = &["/I", "/external:I", "/imsvc"];
+
+/// How an entry's directory was determined, when it was not read from the process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DirectoryOrigin {
+    /// Chosen because the command's relative paths exist under it
+    Inferred,
+    /// No candidate fits; the directory is a fallback and likely wrong
+    Unresolved,
+}
+
+/// A working directory found by `recover_working_directory`
+pub struct Recovered {
+    pub directory: String,
+    pub origin: DirectoryOrigin,
+}
+
+/// Find the directory a compile most likely ran in
+///
+/// `read_response_file` returns the arguments in a response file, or `None` if it
+/// cannot be read. `fallback` is used, marked unresolved, when there is no candidate
+/// at all.
+pub fn recover_working_directory(
+    argv: &[String],
+    parents: &[ParentProcess],
+    fallback: &str,
+    read_response_file: impl Fn(&Path) -> Option<Vec<String>>,
+) -> Recovered {
+    let candidates = candidates(argv, parents);
+
+    let best = candidates
+        .iter()
+        .map(|candidate| {
+            let (argv, response_files) = inline_response_files(argv, Path::new(candidate), &read_response_file);
+            let probes = relative_paths(&argv);
+            let found = probes
+                .iter()
+                .filter(|path| Path::new(candidate).join(path).exists())
+                .count();
+            (found + response_files, probes.is_empty(), *candidate)
+        })
+        // max_by_key keeps the last of equal scores; prefer the earlier candidate
+        .rev()
+        .max_by_key(|(found, _, _)| *found);
+
+    match best {
+        // Without relative paths any directory resolves the command the same way
+        Some((found, no_probes, candidate)) if found > 0 || no_probes => Recovered {
+            directory: candidate.to_string(),
+            origin: DirectoryOrigin::Inferred,
+        },
+        _ => Recovered {
+            directory: candidates.first().copied().unwrap_or(fallback).to_string(),
+            origin: DirectoryOrigin::Unresolved,
+        },
+    }
+}
+
+/// Directories the compile may have run in, most likely first
+fn candidates<'a>(argv: &'a [String], parents: &'a [ParentProcess]) -> Vec<&'a str> {
+    // The parent chain ends at the build root, so its directory is the last of these
+    let mut candidates: Vec<&str> = parents
+        .iter()
+        .map(|parent| parent.working_dir.as_str())
+        .filter(|dir| !dir.is_empty())
+        .collect();
+    for arg in argv {
+        if let Some(dir) = arg
+            .strip_prefix('@')
+            .map(Path::new)
+            .filter(|path| path.is_absolute())
+            .and_then(Path::parent)
+            .and_then(Path::to_str)
+        {
+            candidates.push(dir);
+        }
+    }
+
+    // Build tools often run in the same directory as a nearer parent
+    let mut seen = HashSet::new();
+    candidates.retain(|candidate| seen.insert(*candidate));
+    candidates
+}
+
+/// `argv` with the response files readable from `directory` replaced by their
+/// arguments, and how many of them were given by relative path
+fn inline_response_files(
+    argv: &[String],
+    directory: &Path,
+    read_response_file: &impl Fn(&Path) -> Option<Vec<String>>,
+) -> (Vec<String>, usize) {
+    let mut inlined = Vec::with_capacity(argv.len());
+    let mut relative = 0;
+
+    for arg in argv {
+        let Some(file) = arg.strip_prefix('@').filter(|file| !file.is_empty()) else {
+            inlined.push(arg.clone());
+            continue;
+        };
+        match read_response_file(&directory.join(file)) {
+            Some(arguments) => {
+                if Path::new(file).is_relative() {
+                    relative += 1;
+                }
+                inlined.extend(arguments);
+            }
+            None => inlined.push(arg.clone()),
+        }
+    }
+
+    (inlined, relative)
+}
+
+/// Relative paths in a command that should exist in its working directory: sources,
+/// include directories and the directories of outputs
+fn relative_paths(argv: &[String]) -> Vec<String> {
+    let mut paths = Vec::new();
+    let cl_style = argv.first().is_some_and(|compiler| is_cl_style(compiler));
+    let include_options = match cl_style {
+        true => [INCLUDE_OPTIONS, CL_INCLUDE_OPTIONS].concat(),
+        false => INCLUDE_OPTIONS.to_vec(),
+    };
+    let mut args = argv.iter().skip(1);
+
+    while let Some(arg) = args.next() {
+        if let Some(option) = include_options.iter().find(|option| arg.starts_with(*option)) {
+            let value = match &arg[option.len()..] {
+                "" => args.next().map(String::as_str),
+                value => Some(value),
+            };
+            paths.extend(value.map(str::to_string));
+            continue;
+        }
+
+        let output = match arg.as_str() {
+            "-o" => args.next().map(String::as_str),
+            _ if cl_style => arg.strip_prefix("/Fo").or_else(|| arg.strip_prefix("-Fo")),
+            _ => None,
+        };
+        if let Some(output) = output {
+            // The output itself may not exist yet, its directory does
+            if let Some(dir) = Path::new(output).parent().and_then(Path::to_str) {
+                paths.push(dir.to_string());
+            }
+            continue;
+        }
+
+        let is_source = Path::new(arg)
+            .extension()
+            .is_some_and(|extension| SOURCE_EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str()));
+        if is_source && !arg.starts_with(['-', '/']) {
+            paths.push(arg.clone());
+        }
+    }
+
+    paths.retain(|path| !path.is_empty() && Path::new(path).is_relative());
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{argv, TempTree};
+    use std::fs;
+
+    /// Response files read from disk, one argument per line
+    fn read_lines(path: &Path) -> Option<Vec<String>> {
+        Some(fs::read_to_string(path).ok()?.lines().map(str::to_string).collect())
+    }
+
+    fn recover(argv: &[String], parents: &[ParentProcess], fallback: &str) -> Recovered {
+        recover_working_directory(argv, parents, fallback, read_lines)
+    }
+
+    fn parents(dirs: &[&str]) -> Vec<ParentProcess> {
+        dirs.iter()
+            .map(|dir| ParentProcess {
+                pid: 1,
+                name: "make".to_string(),
+                command_line: String::new(),
+                working_dir: dir.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn picks_the_parent_directory_where_the_paths_exist() {
+        let tree = TempTree::new("workdir_parents", &["src/a.c", "src/include/", "build/obj/"]);
+        let recovered = recover(
+            &argv(&["gcc", "-Iinclude", "-o", "../build/obj/a.o", "-c", "a.c"]),
+            &parents(&[&tree.dir(""), &tree.dir("src"), &tree.dir("build")]),
+            "/fallback",
+        );
+        assert_eq!(recovered.directory, tree.dir("src"));
+        assert_eq!(recovered.origin, DirectoryOrigin::Inferred);
+    }
+
+    #[test]
+    fn prefers_the_nearest_parent_on_a_tie() {
+        let tree = TempTree::new("workdir_tie", &["a/a.c", "b/a.c"]);
+        let recovered =
+            recover(&argv(&["gcc", "-c", "a.c"]), &parents(&[&tree.dir("a"), &tree.dir("b")]), "/");
+        assert_eq!(recovered.directory, tree.dir("a"));
+    }
+
+    #[test]
+    fn considers_the_directories_of_response_files() {
+        let tree = TempTree::new("workdir_response", &["project/a.cpp", "parent/"]);
+        let response = format!("@{}", tree.path().join("project").join("args.rsp").display());
+        let recovered =
+            recover(&argv(&["cl.exe", &response, "a.cpp"]), &parents(&[&tree.dir("parent")]), "/");
+        assert_eq!(recovered.directory, tree.dir("project"));
+        assert_eq!(recovered.origin, DirectoryOrigin::Inferred);
+    }
+
+    #[test]
+    fn the_build_root_is_a_candidate() {
+        let tree = TempTree::new("workdir_root", &["root/src/a.c", "root/out/", "shell/"]);
+        let recovered = recover(
+            &argv(&["gcc", "-o", "out/a.o", "-c", "src/a.c"]),
+            &parents(&[&tree.dir("shell"), &tree.dir("shell"), &tree.dir("root")]),
+            "/fallback",
+        );
+        assert_eq!(recovered.directory, tree.dir("root"));
+        assert_eq!(recovered.origin, DirectoryOrigin::Inferred);
+    }
+
+    #[test]
+    fn candidates_are_unique_and_keep_their_order() {
+        let response = if cfg!(windows) { r"@C:\rsp\a.rsp" } else { "@/rsp/a.rsp" };
+        let argv = argv(&["gcc", response, "@relative.rsp"]);
+        let parents = parents(&["/b", "/a", "/b", "/c", "/a"]);
+        let rsp_dir = if cfg!(windows) { r"C:\rsp" } else { "/rsp" };
+        assert_eq!(candidates(&argv, &parents), ["/b", "/a", "/c", rsp_dir]);
+    }
+
+    #[test]
+    fn relative_response_files_are_read_from_each_candidate() {
+        let tree = TempTree::new("workdir_relative_rsp", &["project/src/a.c", "project/inc/", "other/"]);
+        fs::write(tree.path().join("project/args.rsp"), "-Iinc\n-c\nsrc/a.c\n").unwrap();
+
+        let recovered =
+            recover(&argv(&["gcc", "@args.rsp"]), &parents(&[&tree.dir("other"), &tree.dir("project")]), "/");
+        assert_eq!(recovered.directory, tree.dir("project"));
+        assert_eq!(recovered.origin, DirectoryOrigin::Inferred);
+    }
+
+    #[test]
+    fn sources_in_response_files_count() {
+        let tree = TempTree::new("workdir_rsp_sources", &["rsp/", "near/", "far/src/a.c"]);
+        let response = tree.path().join("rsp/args.rsp");
+        fs::write(&response, "-c\nsrc/a.c\n").unwrap();
+
+        let recovered = recover(
+            &argv(&["gcc", &format!("@{}", response.display())]),
+            &parents(&[&tree.dir("near"), &tree.dir("far")]),
+            "/",
+        );
+        assert_eq!(recovered.directory, tree.dir("far"));
+    }
+
+    #[test]
+    fn unresolved_when_no_candidate_fits() {
+        let tree = TempTree::new("workdir_unresolved", &["a/", "b/"]);
+        let recovered = recover(
+            &argv(&["gcc", "-c", "missing.c"]),
+            &parents(&[&tree.dir("a"), &tree.dir("b")]),
+            "/fallback",
+        );
+        assert_eq!(recovered.directory, tree.dir("a"));
+        assert_eq!(recovered.origin, DirectoryOrigin::Unresolved);
+
+        let recovered = recover(&argv(&["gcc", "-c", "a.c"]), &[], "/fallback");
+        assert_eq!(recovered.directory, "/fallback");
+        assert_eq!(recovered.origin, DirectoryOrigin::Unresolved);
+    }
+
+    #[test]
+    fn any_directory_fits_without_relative_paths() {
+        let recovered = recover(&argv(&["gcc", "-v"]), &parents(&["/tmp"]), "/");
+        assert_eq!(recovered.directory, "/tmp");
+        assert_eq!(recovered.origin, DirectoryOrigin::Inferred);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn relative_paths_of_gcc_style_commands() {
+        assert_eq!(
+            relative_paths(&argv(&[
+                "gcc", "-I", "inc", "-isystem../sys", "-o", "obj/a.o", "-c", "a.c", "/abs/b.c", "-Werror", "lib.a",
+            ])),
+            ["inc", "../sys", "obj", "a.c"]
+        );
+        // For gcc these are absolute paths, not cl options
+        assert_eq!(relative_paths(&argv(&["gcc", "-c", "/Include/foo.c", "/Fo/x.o"])), Vec::<String>::new());
+    }
+
+    #[test]
+    fn relative_paths_of_cl_style_commands() {
+        assert_eq!(
+            relative_paths(&argv(&["cl.exe", "/Iinc", "/external:I", "ext", "/Foobj/a.obj", "/c", "a.cpp"])),
+            ["inc", "ext", "obj", "a.cpp"]
+        );
+    }
+}
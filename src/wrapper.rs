@@ -0,0 +1,145 @@
+// Compiler launchers
+//
+// Builds often run the compiler through a caching or distributing launcher:
+// `ccache g++ -c a.cpp`, `sccache cl.exe /c a.cpp`, `distcc gcc ...`. The launcher
+// is what gets exec'd first, and recording its command verbatim gives clangd a
+// command starting with `ccache`. So the launcher prefix is stripped, leaving the
+// real compiler as argv[0], and the launcher's name is kept as metadata.
+//
+// Launchers are recognized two ways:
+//
+// - Prefix: argv[0] is the launcher and the compiler follows (`ccache g++ ...`).
+//   Several launchers can be stacked (`ccache distcc g++ ...`). distcc and icecc
+//   also accept a bare option list and then run `cc`.
+// - Masquerade: a symlink named after the compiler points at the launcher
+//   (`/usr/lib/ccache/g++`), so the name is the compiler's but the executable is
+//   the launcher's. argv is already right.
+//
+// The launcher then runs the real compiler itself, possibly several times (a
+// preprocessing pass, the compile), and those children would be recorded again.
+// `CompilerMonitor` suppresses them; see `process_creation_callback`.
+
+use crate::names::program_stem;
+
+/// Executable names (without extension) of known compiler launchers
+const LAUNCHERS: &[&str] = &["ccache", "sccache", "distcc", "icecc", "icerun", "clcache", "buildcache"];
+
+/// Launchers that default to `cc` when no compiler is named
+const IMPLICIT_CC_LAUNCHERS: &[&str] = &["distcc", "icecc"];
+
+/// A compiler invocation with its launcher removed
+pub struct Unwrapped {
+    /// Compiler argv, with the real compiler as argv[0]
+    pub argv: Vec<String>,
+    /// Launcher name(s), outermost first, separated by spaces
+    pub launcher: String,
+}
+
+/// Whether an executable name or path is a known compiler launcher
+pub fn is_launcher(name_or_path: &str) -> bool {
+    launcher_name(name_or_path).is_some()
+}
+
+/// Strip launchers from an invocation; `None` if it does not go through one
+pub fn unwrap_launcher(executable: &str, argv: &[String]) -> Option<Unwrapped> {
+    let mut launchers = Vec::new();
+    let mut rest = argv;
+
+    while let Some(launcher) = rest.first().and_then(|arg0| launcher_name(arg0)) {
+        rest = &rest[1..];
+
+        // `ccache -- g++ ...`
+        if rest.first().map(String::as_str) == Some("--") {
+            rest = &rest[1..];
+        }
+
+        let implicit_cc = IMPLICIT_CC_LAUNCHERS.contains(&launcher.as_str())
+            && rest.first().is_some_and(|arg| arg.starts_with('-'));
+        launchers.push(launcher);
+
+        if implicit_cc {
+            let mut argv = vec!["cc".to_string()];
+            argv.extend_from_slice(rest);
+            return Some(Unwrapped {
+                argv,
+                launcher: launchers.join(" "),
+            });
+        }
+    }
+
+    if !launchers.is_empty() {
+        if rest.is_empty() {
+            return None;
+        }
+        return Some(Unwrapped {
+            argv: rest.to_vec(),
+            launcher: launchers.join(" "),
+        });
+    }
+
+    // Masquerading: started under the compiler's name, but the binary is the launcher
+    let launcher = launcher_name(executable)?;
+    Some(Unwrapped {
+        argv: argv.to_vec(),
+        launcher,
+    })
+}
+
+fn launcher_name(name_or_path: &str) -> Option<String> {
+    let stem = program_stem(name_or_path);
+    LAUNCHERS.contains(&stem.as_str()).then_some(stem)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::argv;
+
+    fn unwrap(executable: &str, args: &[&str]) -> Option<(Vec<String>, String)> {
+        unwrap_launcher(executable, &argv(args)).map(|unwrapped| (unwrapped.argv, unwrapped.launcher))
+    }
+
+    #[test]
+    fn strips_a_launcher_prefix() {
+        assert_eq!(
+            unwrap("/usr/bin/ccache", &["ccache", "g++", "-c", "a.cpp"]),
+            Some((argv(&["g++", "-c", "a.cpp"]), "ccache".to_string()))
+        );
+        assert_eq!(
+            unwrap(r"C:\tools\sccache.exe", &[r"C:\tools\SCCACHE.EXE", "cl.exe", "/c", "a.cpp"]),
+            Some((argv(&["cl.exe", "/c", "a.cpp"]), "sccache".to_string()))
+        );
+    }
+
+    #[test]
+    fn strips_stacked_launchers_and_double_dashes() {
+        assert_eq!(
+            unwrap("/usr/bin/ccache", &["ccache", "--", "distcc", "gcc", "-c", "a.c"]),
+            Some((argv(&["gcc", "-c", "a.c"]), "ccache distcc".to_string()))
+        );
+    }
+
+    #[test]
+    fn distcc_options_alone_run_cc() {
+        assert_eq!(
+            unwrap("/usr/bin/distcc", &["distcc", "-c", "a.c"]),
+            Some((argv(&["cc", "-c", "a.c"]), "distcc".to_string()))
+        );
+    }
+
+    #[test]
+    fn recognizes_masquerading_symlinks() {
+        assert_eq!(
+            unwrap("/usr/bin/ccache", &["/usr/lib/ccache/g++", "-c", "a.cpp"]),
+            Some((argv(&["/usr/lib/ccache/g++", "-c", "a.cpp"]), "ccache".to_string()))
+        );
+    }
+
+    #[test]
+    fn leaves_other_invocations_alone() {
+        assert_eq!(unwrap("/usr/bin/g++", &["g++", "-c", "a.cpp"]), None);
+        assert_eq!(unwrap("/usr/bin/ccache", &["ccache"]), None);
+        assert!(!is_launcher("/usr/bin/ccache-swig"));
+        assert!(is_launcher("buildcache.exe"));
+    }
+}